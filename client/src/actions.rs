@@ -0,0 +1,1072 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "enigo")]
+use enigo::{Enigo, Key, KeyboardControllable};
+
+use crate::audio_controller::{
+    AudioControllerTrait, AudioError, AudioInputDeviceTrait, AudioOutputDeviceTrait,
+};
+
+#[cfg(feature = "enigo")]
+const KEYCODE: Key = Key::F13;
+
+#[derive(Debug)]
+pub struct ActionError {
+    pub msg: String,
+}
+
+impl From<AudioError> for ActionError {
+    fn from(e: AudioError) -> Self {
+        ActionError { msg: e.msg }
+    }
+}
+
+/// Trait that describes something to do when the footswitch changes state.
+pub trait Action {
+    /// Called when the footswitch is pressed.
+    fn press(&mut self) -> Result<(), ActionError>;
+
+    /// Called when the footswitch is released, after debouncing.
+    fn release(&mut self) -> Result<(), ActionError>;
+
+    /// Called when a button on any source is pressed or released, before
+    /// debouncing, and whether or not it holds the footswitch.
+    fn button(&mut self, _button: u8, _pressed: bool) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    /// Called when an analog pedal moves, with its position from 0.0 to 1.0.
+    ///
+    /// This isn't debounced.
+    fn axis(&mut self, _value: f32) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    /// Called when a knob turns, with the number of steps it moved (positive
+    /// is clockwise).
+    ///
+    /// This isn't debounced.
+    fn delta(&mut self, _steps: i32) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    /// Called when force mute is turned on or off. While it's on, the
+    /// footswitch is released, and pressing it does nothing.
+    fn force_mute(&mut self, _muted: bool) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    /// Called when the client is about to stop because an action failed.
+    fn error(&mut self, _msg: &str) {}
+
+    /// Called periodically to let the action do any outstanding work.
+    ///
+    /// Returns the maximum time to wait before polling again, or `None` if
+    /// the action is idle.
+    fn poll(&mut self) -> Result<Option<Duration>, ActionError> {
+        Ok(None)
+    }
+}
+
+/// Something with a volume control.
+pub trait Volume {
+    fn get_volume(&self) -> Result<f32, AudioError>;
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError>;
+
+    /// How often to step volume ramps.
+    fn ramp_step(&self) -> Duration;
+
+    /// The ID to save the device's original volume under, if it's an output
+    /// device.
+    fn output_id(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Volume for dyn AudioInputDeviceTrait {
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        AudioInputDeviceTrait::get_volume(self)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        AudioInputDeviceTrait::set_volume(self, volume)
+    }
+
+    fn ramp_step(&self) -> Duration {
+        AudioInputDeviceTrait::ramp_step(self)
+    }
+}
+
+impl Volume for dyn AudioOutputDeviceTrait {
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        AudioOutputDeviceTrait::get_volume(self)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        AudioOutputDeviceTrait::set_volume(self, volume)
+    }
+
+    fn ramp_step(&self) -> Duration {
+        AudioOutputDeviceTrait::ramp_step(self)
+    }
+
+    fn output_id(&self) -> Option<String> {
+        self.id().ok()
+    }
+}
+
+/// A linear volume ramp, applied in steps of the device's `ramp_step()`.
+struct Ramp {
+    from: f32,
+    to: f32,
+    started_at: Instant,
+    duration: Duration,
+}
+
+impl Ramp {
+    fn new(from: f32, to: f32, duration: Duration) -> Self {
+        Ramp {
+            from,
+            to,
+            started_at: Instant::now(),
+            duration,
+        }
+    }
+
+    /// Returns the volume level for the current step, and whether the ramp
+    /// has finished.
+    fn step(&self) -> (f32, bool) {
+        let elapsed = self.started_at.elapsed();
+        if elapsed >= self.duration {
+            return (self.to, true);
+        }
+        let progress = elapsed.as_secs_f32() / self.duration.as_secs_f32();
+        (self.from + (self.to - self.from) * progress, false)
+    }
+
+    fn remaining(&self) -> Duration {
+        self.duration.saturating_sub(self.started_at.elapsed())
+    }
+}
+
+/// Drives a `Ramp` on an audio device.
+struct Fader<D: ?Sized + Volume> {
+    device: Box<D>,
+    ramp_time: Duration,
+    ramp: Option<Ramp>,
+    /// How often to step the ramp.
+    ramp_step: Duration,
+    /// When the ramp was last stepped.
+    stepped_at: Instant,
+    /// The volume the device is at (or will be at when the ramp finishes).
+    level: f32,
+}
+
+impl<D: ?Sized + Volume> Fader<D> {
+    /// Creates a new `Fader` for a device currently at `level`.
+    fn new(device: Box<D>, ramp_time: Duration, level: f32) -> Self {
+        Fader {
+            ramp_step: device.ramp_step(),
+            device,
+            ramp_time,
+            ramp: None,
+            stepped_at: Instant::now(),
+            level,
+        }
+    }
+
+    /// Creates a new `Fader`, reading the device's current volume.
+    fn with_current_volume(device: Box<D>, ramp_time: Duration) -> Result<Self, ActionError> {
+        let level = device.get_volume()?;
+        Ok(Fader::new(device, ramp_time, level))
+    }
+
+    /// Starts ramping the device towards `target`, starting from wherever an
+    /// in-progress ramp has got to.
+    fn fade_to(&mut self, target: f32) -> Result<(), ActionError> {
+        let from = match &self.ramp {
+            Some(r) => r.step().0,
+            None => self.level,
+        };
+        self.level = target;
+        self.ramp = Some(Ramp::new(from, target, self.ramp_time));
+        self.apply_step().map(|_| ())
+    }
+
+    /// Applies the next step of the ramp, if it's due. Returns `true` if a
+    /// ramp has just finished.
+    fn step(&mut self) -> Result<bool, ActionError> {
+        match &self.ramp {
+            Some(r) if r.remaining().is_zero() || self.stepped_at.elapsed() >= self.ramp_step => {
+                self.apply_step()
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn apply_step(&mut self) -> Result<bool, ActionError> {
+        let (level, done) = match &self.ramp {
+            Some(r) => r.step(),
+            None => return Ok(false),
+        };
+        self.stepped_at = Instant::now();
+        self.device.set_volume(level)?;
+        if done {
+            self.ramp = None;
+        }
+        Ok(done)
+    }
+
+    fn is_ramping(&self) -> bool {
+        self.ramp.is_some()
+    }
+
+    /// How long until the ramp's next step is due, if it's ramping.
+    fn next_step(&self) -> Option<Duration> {
+        let ramp = self.ramp.as_ref()?;
+        Some(
+            self.ramp_step
+                .saturating_sub(self.stepped_at.elapsed())
+                .min(ramp.remaining()),
+        )
+    }
+}
+
+/// Volume differences smaller than this are put down to the mixer rounding the
+/// volume we set (eg: to a whole percentage), rather than someone changing it.
+const VOLUME_TOLERANCE: f32 = 0.005;
+
+/// Mutes the microphone while the footswitch is released.
+///
+/// With a non-zero ramp time, the microphone volume is faded out before
+/// muting, and faded back in after unmuting, to avoid clicks.
+pub struct MuteAction {
+    fader: Fader<dyn AudioInputDeviceTrait>,
+    /// The volume to restore when unmuting.
+    volume: f32,
+    /// Whether the device should be muted once the current ramp finishes.
+    mute_pending: bool,
+    /// The mute state of the device before we started, if known.
+    initial_mute: Option<bool>,
+}
+
+impl MuteAction {
+    pub fn new(
+        device: Box<dyn AudioInputDeviceTrait>,
+        ramp_time: Duration,
+    ) -> Result<Self, ActionError> {
+        let initial_mute = device.get_mute().ok();
+        let fader = if ramp_time.is_zero() {
+            // Not fading, so we don't need volume control.
+            Fader::new(device, ramp_time, 1.)
+        } else {
+            Fader::with_current_volume(device, ramp_time)?
+        };
+        Ok(MuteAction {
+            volume: fader.level,
+            fader,
+            mute_pending: false,
+            initial_mute,
+        })
+    }
+}
+
+impl Action for MuteAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.mute_pending = false;
+        if self.fader.ramp_time.is_zero() {
+            self.fader.device.set_mute(false)?;
+            return Ok(());
+        }
+
+        if !self.fader.is_ramping() {
+            // Pick up any volume changes made while we were muted (eg: by a
+            // knob).
+            self.volume = self.fader.device.get_volume()?;
+            // Fully muted: start from silence.
+            self.fader.device.set_volume(0.)?;
+            self.fader.level = 0.;
+        }
+        self.fader.device.set_mute(false)?;
+        self.fader.fade_to(self.volume)
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        if self.fader.ramp_time.is_zero() {
+            self.fader.device.set_mute(true)?;
+            return Ok(());
+        }
+
+        if !self.fader.is_ramping() {
+            // Pick up any volume changes made while we were unmuted.
+            self.volume = self.fader.device.get_volume()?;
+            self.fader.level = self.volume;
+        }
+        self.mute_pending = true;
+        self.fader.fade_to(0.)
+    }
+
+    fn poll(&mut self) -> Result<Option<Duration>, ActionError> {
+        if self.fader.step()? && self.mute_pending {
+            // Now silent: mute, and put the volume back where it was.
+            self.mute_pending = false;
+            self.fader.device.set_mute(true)?;
+            self.fader.device.set_volume(self.volume)?;
+        }
+        Ok(self.fader.next_step())
+    }
+}
+
+impl Drop for MuteAction {
+    fn drop(&mut self) {
+        if self.fader.is_ramping() {
+            let _ = self.fader.device.set_volume(self.volume);
+        }
+        if let Some(mute) = self.initial_mute {
+            let _ = self.fader.device.set_mute(mute);
+        }
+    }
+}
+
+/// Lowers the microphone volume while the footswitch is released, rather than
+/// muting it.
+pub struct DuckAction {
+    fader: Fader<dyn AudioInputDeviceTrait>,
+    /// Level to duck to, in decibels relative to the original volume.
+    db: f32,
+    /// The volume to restore when un-ducking.
+    volume: f32,
+    ducked: bool,
+}
+
+impl DuckAction {
+    /// Creates a new `DuckAction`, which ducks the device by `db` decibels
+    /// over `ramp_time`.
+    pub fn new(
+        device: Box<dyn AudioInputDeviceTrait>,
+        db: f32,
+        ramp_time: Duration,
+    ) -> Result<Self, ActionError> {
+        let fader = Fader::with_current_volume(device, ramp_time)?;
+        Ok(DuckAction {
+            volume: fader.level,
+            fader,
+            db,
+            ducked: false,
+        })
+    }
+}
+
+impl Action for DuckAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        if self.ducked && !self.fader.is_ramping() {
+            // Pick up any volume changes made while we were ducked (eg: by a
+            // knob), by un-ducking from there.
+            let level = self.fader.device.get_volume()?;
+            if (level - self.fader.level).abs() > VOLUME_TOLERANCE {
+                self.fader.level = level;
+                self.volume = self.fader.device.adjust_volume_db(level, -self.db)?.min(1.);
+            }
+        }
+        self.ducked = false;
+        self.fader.fade_to(self.volume)
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        if !self.ducked && !self.fader.is_ramping() {
+            // Pick up any volume changes made while we were un-ducked.
+            self.volume = self.fader.device.get_volume()?;
+            self.fader.level = self.volume;
+        }
+        self.ducked = true;
+        let level = self.fader.device.adjust_volume_db(self.volume, self.db)?;
+        self.fader.fade_to(level)
+    }
+
+    fn poll(&mut self) -> Result<Option<Duration>, ActionError> {
+        self.fader.step()?;
+        Ok(self.fader.next_step())
+    }
+}
+
+impl Drop for DuckAction {
+    fn drop(&mut self) {
+        if self.ducked {
+            let _ = self.fader.device.set_volume(self.volume);
+        }
+    }
+}
+
+/// Path to the file where output volumes are saved while they are ducked.
+///
+/// This lets us restore them if the client crashes while the footswitch is
+/// held.
+fn output_volumes_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|p| p.join("footswitch").join("output-volumes.txt"))
+}
+
+/// The original volume of an output device which is being changed by one or
+/// more actions.
+struct SavedVolume {
+    id: String,
+    volume: f32,
+    /// How many actions are holding the device away from its original volume.
+    holders: usize,
+}
+
+/// Original volumes of output devices, shared by every action which changes
+/// them, and mirrored to a file.
+struct OutputVolumes {
+    path: Option<PathBuf>,
+    saved: Mutex<Vec<SavedVolume>>,
+}
+
+static OUTPUT_VOLUMES: OnceLock<OutputVolumes> = OnceLock::new();
+
+impl OutputVolumes {
+    fn new(path: Option<PathBuf>) -> Self {
+        OutputVolumes {
+            path,
+            saved: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The volumes shared by every action, saved to `output_volumes_path()`.
+    fn shared() -> &'static Self {
+        OUTPUT_VOLUMES.get_or_init(|| OutputVolumes::new(output_volumes_path()))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<SavedVolume>> {
+        self.saved.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes the saved volumes to disk, or removes the file if there are
+    /// none.
+    fn write(&self, saved: &[SavedVolume]) -> Result<(), ActionError> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let error = |e: io::Error| ActionError {
+            msg: format!("Error saving output volumes to {}: {}", path.display(), e),
+        };
+        if saved.is_empty() {
+            return remove_output_volumes(path).map_err(error);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(error)?;
+        }
+        let mut data = String::new();
+        for s in saved {
+            data.push_str(&format!("{}\t{}\n", s.volume, s.id));
+        }
+        fs::write(path, data).map_err(error)
+    }
+
+    /// Saves the original volume of an output device, before an action
+    /// changes it. If another action has already changed the device, its
+    /// original volume is kept, and the device is returned to that volume.
+    ///
+    /// Returns the original volume. Every call must be matched by a call to
+    /// `release` once the device is restored.
+    fn hold(&self, id: &str, volume: f32) -> Result<f32, ActionError> {
+        let mut saved = self.lock();
+        if let Some(s) = saved.iter_mut().find(|s| s.id == id) {
+            s.holders += 1;
+            return Ok(s.volume);
+        }
+        saved.push(SavedVolume {
+            id: id.to_string(),
+            volume,
+            holders: 1,
+        });
+        self.write(&saved)?;
+        Ok(volume)
+    }
+
+    /// Gets the original volume of an output device held by `hold`.
+    fn get(&self, id: &str) -> Option<f32> {
+        self.lock().iter().find(|s| s.id == id).map(|s| s.volume)
+    }
+
+    /// Scales the original volume of an output device, if it is held, after
+    /// the user changes its current volume from `from` to `to`.
+    fn rescale(&self, id: &str, from: f32, to: f32) -> Result<(), ActionError> {
+        let mut saved = self.lock();
+        let s = match saved.iter_mut().find(|s| s.id == id) {
+            Some(s) if from > 0. => s,
+            _ => return Ok(()),
+        };
+        let volume = (s.volume * to / from).clamp(0., 1.);
+        if volume == s.volume {
+            return Ok(());
+        }
+        s.volume = volume;
+        self.write(&saved)
+    }
+
+    /// Forgets the original volume of an output device, once an action has
+    /// restored it.
+    fn release(&self, id: &str) -> Result<(), ActionError> {
+        let mut saved = self.lock();
+        let i = match saved.iter().position(|s| s.id == id) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        saved[i].holders -= 1;
+        if saved[i].holders > 0 {
+            return Ok(());
+        }
+        saved.remove(i);
+        self.write(&saved)
+    }
+}
+
+fn remove_output_volumes(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Restores output volumes left over from a previous run that didn't exit
+/// cleanly.
+pub fn restore_output_volumes(audio: &dyn AudioControllerTrait) -> Result<(), ActionError> {
+    let path = match output_volumes_path() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let data = match fs::read_to_string(&path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(ActionError {
+                msg: format!("Error reading {}: {}", path.display(), e),
+            })
+        }
+    };
+
+    let devices = audio.get_output_devices()?;
+    for line in data.lines() {
+        let mut parts = line.splitn(2, '\t');
+        let (volume, id) = match (parts.next().map(str::parse::<f32>), parts.next()) {
+            (Some(Ok(volume)), Some(id)) => (volume, id),
+            _ => {
+                warn!("Ignoring bad line in {}: {:?}", path.display(), line);
+                continue;
+            }
+        };
+        match devices.iter().find(|d| d.id().is_ok_and(|i| i == id)) {
+            Some(d) => {
+                warn!(
+                    "Restoring volume of {} to {:.0}%",
+                    d.name().unwrap_or_else(|_| id.to_string()),
+                    volume * 100.
+                );
+                d.set_volume(volume)?;
+            }
+            None => warn!("Output device {} has gone away, not restoring volume", id),
+        }
+    }
+
+    remove_output_volumes(&path).map_err(|e| ActionError {
+        msg: format!("Error removing {}: {}", path.display(), e),
+    })
+}
+
+/// An output device being ducked by `OutputDuckAction`.
+struct DuckedOutput {
+    id: String,
+    fader: Fader<dyn AudioOutputDeviceTrait>,
+    /// The volume to restore when un-ducking, kept in sync with the saved
+    /// volume.
+    volume: f32,
+}
+
+/// Lowers the volume of output devices while the footswitch is held.
+///
+/// The original volumes are saved to disk while ducked, and are restored by
+/// `restore_output_volumes` if the client exits uncleanly.
+pub struct OutputDuckAction {
+    outputs: Vec<DuckedOutput>,
+    volumes: &'static OutputVolumes,
+    /// Level to duck to, in decibels relative to the original volume.
+    db: f32,
+    ducked: bool,
+    /// Whether we are ramping back up to the original volumes.
+    restoring: bool,
+}
+
+impl OutputDuckAction {
+    /// Creates a new `OutputDuckAction`, which ducks the devices by `db`
+    /// decibels over `ramp_time`.
+    pub fn new(
+        devices: Vec<Box<dyn AudioOutputDeviceTrait>>,
+        db: f32,
+        ramp_time: Duration,
+    ) -> Result<Self, ActionError> {
+        let mut outputs = Vec::with_capacity(devices.len());
+        for device in devices {
+            let id = device.id()?;
+            let fader = Fader::with_current_volume(device, ramp_time)?;
+            outputs.push(DuckedOutput {
+                id,
+                volume: fader.level,
+                fader,
+            });
+        }
+
+        Ok(OutputDuckAction {
+            outputs,
+            volumes: OutputVolumes::shared(),
+            db,
+            ducked: false,
+            restoring: false,
+        })
+    }
+
+    fn is_ramping(&self) -> bool {
+        self.outputs.iter().any(|o| o.fader.is_ramping())
+    }
+
+    /// Forgets the saved volumes once all devices are back to normal.
+    fn finish_restore(&mut self) -> Result<(), ActionError> {
+        if self.restoring && !self.is_ramping() {
+            self.restoring = false;
+            for o in self.outputs.iter() {
+                self.volumes.release(&o.id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Action for OutputDuckAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        if !self.ducked && !self.restoring {
+            // Pick up any volume changes made while we were un-ducked.
+            for o in self.outputs.iter_mut() {
+                let volume = o.fader.device.get_volume()?;
+                o.fader.level = volume;
+                o.volume = self.volumes.hold(&o.id, volume)?;
+            }
+        }
+
+        self.ducked = true;
+        self.restoring = false;
+        for o in self.outputs.iter_mut() {
+            let level = o.fader.device.adjust_volume_db(o.volume, self.db)?;
+            o.fader.fade_to(level)?;
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        if !self.ducked {
+            return Ok(());
+        }
+        self.ducked = false;
+        self.restoring = true;
+        for o in self.outputs.iter_mut() {
+            // Another action may have changed the original volume.
+            o.volume = self.volumes.get(&o.id).unwrap_or(o.volume);
+            o.fader.fade_to(o.volume)?;
+        }
+        self.finish_restore()
+    }
+
+    fn poll(&mut self) -> Result<Option<Duration>, ActionError> {
+        for o in self.outputs.iter_mut() {
+            o.fader.step()?;
+        }
+        self.finish_restore()?;
+        Ok(self
+            .outputs
+            .iter()
+            .filter_map(|o| o.fader.next_step())
+            .min())
+    }
+}
+
+impl Drop for OutputDuckAction {
+    fn drop(&mut self) {
+        if self.ducked || self.restoring {
+            for o in self.outputs.iter() {
+                let volume = self.volumes.get(&o.id).unwrap_or(o.volume);
+                let _ = o.fader.device.set_volume(volume);
+                let _ = self.volumes.release(&o.id);
+            }
+        }
+    }
+}
+
+/// Smallest change in pedal position that changes the volume.
+const AXIS_VOLUME_STEP: f32 = 0.01;
+
+/// Sets the volume of output devices from an analog pedal's position.
+///
+/// The pedal scales the devices' original volumes, which are saved to disk
+/// once the pedal first moves, and are restored when the action is dropped.
+pub struct AxisVolumeAction {
+    /// Devices, their IDs, and their original volumes.
+    devices: Vec<(Box<dyn AudioOutputDeviceTrait>, String, f32)>,
+    volumes: &'static OutputVolumes,
+    level: Option<f32>,
+}
+
+impl AxisVolumeAction {
+    pub fn new(devices: Vec<Box<dyn AudioOutputDeviceTrait>>) -> Result<Self, ActionError> {
+        let mut volumes = Vec::with_capacity(devices.len());
+        for device in devices {
+            let id = device.id()?;
+            let volume = device.get_volume()?;
+            volumes.push((device, id, volume));
+        }
+        Ok(AxisVolumeAction {
+            devices: volumes,
+            volumes: OutputVolumes::shared(),
+            level: None,
+        })
+    }
+}
+
+impl Action for AxisVolumeAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    fn axis(&mut self, value: f32) -> Result<(), ActionError> {
+        let value = value.clamp(0., 1.);
+        if self
+            .level
+            .is_some_and(|l| (l - value).abs() < AXIS_VOLUME_STEP && value != 0. && value != 1.)
+        {
+            return Ok(());
+        }
+        if self.level.is_none() {
+            for (device, id, volume) in self.devices.iter_mut() {
+                *volume = self.volumes.hold(id, device.get_volume()?)?;
+            }
+        }
+        self.level = Some(value);
+        for (device, id, volume) in self.devices.iter_mut() {
+            // Another action may have changed the original volume.
+            *volume = self.volumes.get(id).unwrap_or(*volume);
+            device.set_volume(*volume * value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AxisVolumeAction {
+    fn drop(&mut self) {
+        if self.level.is_some() {
+            for (device, id, volume) in self.devices.iter() {
+                let volume = self.volumes.get(id).unwrap_or(*volume);
+                let _ = device.set_volume(volume);
+                let _ = self.volumes.release(id);
+            }
+        }
+    }
+}
+
+/// Knob turns closer together than this are accelerated.
+const KNOB_ACCEL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Steps the volume of devices up and down with a knob.
+///
+/// Fast turns move the volume further, by up to `max_accel` times `step` for
+/// each step of the knob.
+///
+/// Turning the knob while another action has changed an output device's
+/// volume scales the device's saved original volume to match.
+pub struct KnobVolumeAction<D: ?Sized + Volume> {
+    devices: Vec<(Box<D>, Option<String>)>,
+    volumes: &'static OutputVolumes,
+    /// Volume change for each step of the knob.
+    step: f32,
+    max_accel: f32,
+    last_turn: Option<Instant>,
+}
+
+impl<D: ?Sized + Volume> KnobVolumeAction<D> {
+    /// Creates a new `KnobVolumeAction`, which changes the volume by `step`
+    /// (a scalar between 0.0 and 1.0) for each step of the knob.
+    pub fn new(devices: Vec<Box<D>>, step: f32, max_accel: f32) -> Result<Self, ActionError> {
+        let mut knobs = Vec::with_capacity(devices.len());
+        for device in devices {
+            // Check that the device has volume control.
+            device.get_volume()?;
+            let id = device.output_id();
+            knobs.push((device, id));
+        }
+        Ok(KnobVolumeAction {
+            devices: knobs,
+            volumes: OutputVolumes::shared(),
+            step,
+            max_accel,
+            last_turn: None,
+        })
+    }
+
+    /// Returns how much to multiply the next turn by, based on how quickly
+    /// the knob is turning.
+    fn acceleration(&mut self) -> f32 {
+        let now = Instant::now();
+        let accel = match self.last_turn {
+            Some(t) => (KNOB_ACCEL_INTERVAL.as_secs_f32()
+                / now.duration_since(t).as_secs_f32().max(f32::EPSILON))
+            .clamp(1., self.max_accel),
+            None => 1.,
+        };
+        self.last_turn = Some(now);
+        accel
+    }
+}
+
+impl<D: ?Sized + Volume> Action for KnobVolumeAction<D> {
+    fn press(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        Ok(())
+    }
+
+    fn delta(&mut self, steps: i32) -> Result<(), ActionError> {
+        let change = steps as f32 * self.step * self.acceleration();
+        debug!(
+            "Knob turned {} steps, changing volume by {:.3}",
+            steps, change
+        );
+        for (device, id) in self.devices.iter() {
+            let from = device.get_volume()?;
+            let volume = (from + change).clamp(0., 1.);
+            device.set_volume(volume)?;
+            if let Some(id) = id {
+                self.volumes.rescale(id, from, volume)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends a synthetic keypress while the footswitch is held.
+#[cfg(feature = "enigo")]
+pub struct KeyboardAction {
+    enigo: Enigo,
+}
+
+#[cfg(feature = "enigo")]
+impl Default for KeyboardAction {
+    fn default() -> Self {
+        KeyboardAction {
+            enigo: Enigo::new(),
+        }
+    }
+}
+
+#[cfg(feature = "enigo")]
+impl Action for KeyboardAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.enigo.key_up(KEYCODE);
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.enigo.key_down(KEYCODE);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::env;
+    use std::rc::Rc;
+
+    struct TestDevice(Rc<Cell<f32>>);
+
+    impl AudioOutputDeviceTrait for TestDevice {
+        fn id(&self) -> Result<String, AudioError> {
+            Ok("speakers".to_string())
+        }
+
+        fn name(&self) -> Result<String, AudioError> {
+            self.id()
+        }
+
+        fn get_volume(&self) -> Result<f32, AudioError> {
+            Ok(self.0.get())
+        }
+
+        fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+            self.0.set(volume);
+            Ok(())
+        }
+    }
+
+    /// A device which counts volume changes, and takes as long to step as
+    /// a command-based mixer.
+    struct SlowDevice(Rc<Cell<usize>>);
+
+    impl AudioOutputDeviceTrait for SlowDevice {
+        fn id(&self) -> Result<String, AudioError> {
+            Ok("slow".to_string())
+        }
+
+        fn name(&self) -> Result<String, AudioError> {
+            self.id()
+        }
+
+        fn get_volume(&self) -> Result<f32, AudioError> {
+            Ok(1.)
+        }
+
+        fn set_volume(&self, _: f32) -> Result<(), AudioError> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+
+        fn ramp_step(&self) -> Duration {
+            crate::audio_controller::COMMAND_RAMP_STEP
+        }
+    }
+
+    #[test]
+    fn ramp_steps() {
+        let changes = Rc::new(Cell::new(0));
+        let device: Box<dyn AudioOutputDeviceTrait> = Box::new(SlowDevice(changes.clone()));
+        let mut fader = Fader::new(device, Duration::from_millis(200), 1.);
+        fader.fade_to(0.).unwrap();
+        let started = Instant::now();
+        while let Some(next) = fader.next_step() {
+            assert!(next <= crate::audio_controller::COMMAND_RAMP_STEP);
+            // Polling early doesn't step the ramp any more often.
+            fader.step().unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(190));
+        assert!(changes.get() <= 6, "{} volume changes", changes.get());
+    }
+
+    struct TestMic(Rc<Cell<f32>>);
+
+    impl AudioInputDeviceTrait for TestMic {
+        fn name(&self) -> Result<String, AudioError> {
+            Ok("mic".to_string())
+        }
+
+        fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
+            Ok(state)
+        }
+
+        fn get_mute(&self) -> Result<bool, AudioError> {
+            Ok(false)
+        }
+
+        fn get_volume(&self) -> Result<f32, AudioError> {
+            Ok(self.0.get())
+        }
+
+        fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+            self.0.set(volume);
+            Ok(())
+        }
+    }
+
+    fn mic(volume: &Rc<Cell<f32>>) -> Box<dyn AudioInputDeviceTrait> {
+        Box::new(TestMic(volume.clone()))
+    }
+
+    fn finish(action: &mut dyn Action) {
+        while let Some(next) = action.poll().unwrap() {
+            std::thread::sleep(next);
+        }
+    }
+
+    #[test]
+    fn knob_while_muted() {
+        let volume = Rc::new(Cell::new(0.8));
+        let mut knob = KnobVolumeAction::new(vec![mic(&volume)], 0.1, 1.).unwrap();
+
+        let mut duck = DuckAction::new(mic(&volume), -6.0206, Duration::ZERO).unwrap();
+        duck.release().unwrap();
+        assert!((volume.get() - 0.4).abs() < 1e-4);
+        knob.delta(1).unwrap();
+        duck.press().unwrap();
+        assert!((volume.get() - 1.).abs() < 1e-4);
+        duck.release().unwrap();
+        assert!((volume.get() - 0.5).abs() < 1e-4);
+        // Rounding by the mixer isn't a change.
+        volume.set(0.502);
+        duck.press().unwrap();
+        assert!((volume.get() - 1.).abs() < 1e-4);
+        drop(duck);
+
+        volume.set(0.8);
+        let mut mute = MuteAction::new(mic(&volume), Duration::from_millis(20)).unwrap();
+        mute.release().unwrap();
+        finish(&mut mute);
+        assert_eq!(volume.get(), 0.8);
+        knob.delta(-1).unwrap();
+        mute.press().unwrap();
+        finish(&mut mute);
+        assert!((volume.get() - 0.7).abs() < 1e-6);
+    }
+
+    fn device(volume: &Rc<Cell<f32>>) -> Box<dyn AudioOutputDeviceTrait> {
+        Box::new(TestDevice(volume.clone()))
+    }
+
+    #[test]
+    fn saved_output_volumes() {
+        let dir = env::temp_dir().join(format!("footswitch-test-{}", std::process::id()));
+        let path = dir.join("output-volumes.txt");
+        let volumes = Box::leak(Box::new(OutputVolumes::new(Some(path.clone()))));
+        let volume = Rc::new(Cell::new(0.8));
+
+        let mut duck =
+            OutputDuckAction::new(vec![device(&volume)], -6.0206, Duration::ZERO).unwrap();
+        let mut axis = AxisVolumeAction::new(vec![device(&volume)]).unwrap();
+        let mut knob = KnobVolumeAction::new(vec![device(&volume)], 0.1, 1.).unwrap();
+        duck.volumes = volumes;
+        axis.volumes = volumes;
+        knob.volumes = volumes;
+
+        duck.press().unwrap();
+        assert!((volume.get() - 0.4).abs() < 1e-4);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0.8\tspeakers\n");
+
+        // Only changes to the saved volumes are written.
+        fs::remove_file(&path).unwrap();
+        duck.press().unwrap();
+        axis.axis(0.25).unwrap();
+        assert_eq!(volume.get(), 0.2);
+        assert!(!path.exists());
+
+        // The knob changes the original volume to restore.
+        knob.delta(1).unwrap();
+        assert!((volume.get() - 0.3).abs() < 1e-6);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\tspeakers\n");
+
+        duck.release().unwrap();
+        assert_eq!(volume.get(), 1.);
+        assert!(path.exists());
+
+        drop(axis);
+        assert_eq!(volume.get(), 1.);
+        assert!(!path.exists());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -1,8 +1,16 @@
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::result::Result;
+use std::time::Duration;
 
-#[derive(Debug)]
+/// How often a volume ramp is stepped.
+pub const RAMP_STEP: Duration = Duration::from_millis(10);
+
+/// How often a volume ramp is stepped on devices which run a command for
+/// every change, to limit how many commands a ramp runs.
+pub const COMMAND_RAMP_STEP: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug)]
 pub struct AudioError {
     pub msg: String,
 }
@@ -14,18 +22,70 @@ pub trait AudioInputDeviceTrait {
 
     /// Sets the mute state of the audio device.
     fn set_mute(&self, state: bool) -> Result<bool, AudioError>;
+
+    /// Gets the mute state of the audio device.
+    fn get_mute(&self) -> Result<bool, AudioError>;
+
+    /// Gets the volume (gain) of the audio device, as a scalar between 0.0
+    /// and 1.0.
+    fn get_volume(&self) -> Result<f32, AudioError>;
+
+    /// Sets the volume (gain) of the audio device, as a scalar between 0.0
+    /// and 1.0.
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError>;
+
+    /// Gets the volume scalar which is `db` decibels louder (or quieter, if
+    /// negative) than `volume`, following the device's volume curve.
+    ///
+    /// By default, volume scalars are assumed to be linear.
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(volume * db_to_scalar(db))
+    }
+
+    /// How often to step volume ramps.
+    fn ramp_step(&self) -> Duration {
+        RAMP_STEP
+    }
+}
+
+/// Trait that describes an audio output device.
+pub trait AudioOutputDeviceTrait {
+    /// A stable, unique identifier for the audio device.
+    fn id(&self) -> Result<String, AudioError>;
+
+    /// The human-readable name of the audio device.
+    fn name(&self) -> Result<String, AudioError>;
+
+    /// Gets the volume of the audio device, as a scalar between 0.0 and 1.0.
+    fn get_volume(&self) -> Result<f32, AudioError>;
+
+    /// Sets the volume of the audio device, as a scalar between 0.0 and 1.0.
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError>;
+
+    /// Gets the volume scalar which is `db` decibels louder (or quieter, if
+    /// negative) than `volume`, following the device's volume curve.
+    ///
+    /// By default, volume scalars are assumed to be linear.
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(volume * db_to_scalar(db))
+    }
+
+    /// How often to step volume ramps.
+    fn ramp_step(&self) -> Duration {
+        RAMP_STEP
+    }
 }
 
 /// Trait that describes the audio subsystem.
 pub trait AudioControllerTrait {
-    /// Create a new connection to the audio subsystem.
-    #[allow(clippy::new_ret_no_self)]
-    fn new() -> Box<dyn AudioControllerTrait>
-    where
-        Self: Sized;
-
     /// Gets the default communications device.
     fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError>;
+
+    /// Gets the default output device.
+    fn get_default_output_device(&self) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError>;
+
+    /// Gets all active output devices.
+    fn get_output_devices(&self) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, AudioError>;
 }
 
 impl Debug for dyn AudioInputDeviceTrait {
@@ -35,3 +95,24 @@ impl Debug for dyn AudioInputDeviceTrait {
             .finish()
     }
 }
+
+impl Debug for dyn AudioOutputDeviceTrait {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("AudioOutputDevice")
+            .field("id", &self.id().unwrap())
+            .field("name", &self.name().unwrap())
+            .finish()
+    }
+}
+
+/// Converts a level in decibels (relative to full scale) into a scalar
+/// volume multiplier.
+pub fn db_to_scalar(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// Adjusts a cubic volume scalar, as used by PulseAudio and PipeWire, by a
+/// level in decibels.
+pub fn adjust_cubic_volume(volume: f32, db: f32) -> f32 {
+    volume * 10f32.powf(db / 60.)
+}
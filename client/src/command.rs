@@ -0,0 +1,451 @@
+/*
+ * Command-based AudioControllerTrait implementation.
+ *
+ * This runs user-configured external commands (eg: `amixer`, `pactl` or
+ * `wpctl`) to control the microphone and output devices, and works on any
+ * platform.
+ */
+
+use std::process::Command;
+use std::time::Duration;
+
+use crate::audio_controller::{
+    adjust_cubic_volume, db_to_scalar, AudioControllerTrait, AudioError, AudioInputDeviceTrait,
+    AudioOutputDeviceTrait, COMMAND_RAMP_STEP,
+};
+
+/// Commands used to control the microphone and output devices.
+///
+/// Commands are run with the system shell. `{volume}` is replaced with the
+/// volume as a scalar between 0.0 and 1.0, `{percent}` is replaced with the
+/// volume as a percentage, and `{device}` is replaced with the (quoted) name
+/// of an output device.
+#[derive(Clone, Debug, Default)]
+pub struct CommandConfig {
+    /// Prints the name of the microphone.
+    pub name: Option<String>,
+    /// Mutes the microphone.
+    pub mute: Option<String>,
+    /// Unmutes the microphone.
+    pub unmute: Option<String>,
+    /// Prints the mute state of the microphone.
+    pub get_mute: Option<String>,
+    /// Prints the volume of the microphone.
+    pub get_volume: Option<String>,
+    /// Sets the volume of the microphone.
+    pub set_volume: Option<String>,
+    /// Prints the names of output devices, one per line.
+    pub list_outputs: Option<String>,
+    /// The name of the default output device.
+    pub default_output: Option<String>,
+    /// Prints the volume of an output device.
+    pub get_output_volume: Option<String>,
+    /// Sets the volume of an output device.
+    pub set_output_volume: Option<String>,
+    /// Whether volumes are cubic, like PulseAudio's and PipeWire's, rather
+    /// than linear.
+    pub cubic: bool,
+}
+
+impl CommandConfig {
+    /// Gets a preset configuration for a well-known mixer command.
+    pub fn preset(name: &str) -> Option<CommandConfig> {
+        let c = |s: &str| Some(s.to_string());
+        match name {
+            "pactl" => Some(CommandConfig {
+                name: c("pactl get-default-source"),
+                mute: c("pactl set-source-mute @DEFAULT_SOURCE@ 1"),
+                unmute: c("pactl set-source-mute @DEFAULT_SOURCE@ 0"),
+                get_mute: c("pactl get-source-mute @DEFAULT_SOURCE@"),
+                get_volume: c("pactl get-source-volume @DEFAULT_SOURCE@"),
+                set_volume: c("pactl set-source-volume @DEFAULT_SOURCE@ {percent}%"),
+                list_outputs: c("pactl list short sinks | cut -f2"),
+                default_output: c("@DEFAULT_SINK@"),
+                get_output_volume: c("pactl get-sink-volume {device}"),
+                set_output_volume: c("pactl set-sink-volume {device} {percent}%"),
+                cubic: true,
+            }),
+            "wpctl" => Some(CommandConfig {
+                name: None,
+                mute: c("wpctl set-mute @DEFAULT_AUDIO_SOURCE@ 1"),
+                unmute: c("wpctl set-mute @DEFAULT_AUDIO_SOURCE@ 0"),
+                get_mute: c("wpctl get-volume @DEFAULT_AUDIO_SOURCE@"),
+                get_volume: c("wpctl get-volume @DEFAULT_AUDIO_SOURCE@"),
+                set_volume: c("wpctl set-volume @DEFAULT_AUDIO_SOURCE@ {volume}"),
+                list_outputs: None,
+                default_output: c("@DEFAULT_AUDIO_SINK@"),
+                get_output_volume: c("wpctl get-volume {device}"),
+                set_output_volume: c("wpctl set-volume {device} {volume}"),
+                cubic: true,
+            }),
+            "amixer" => Some(CommandConfig {
+                name: None,
+                mute: c("amixer -q set Capture nocap"),
+                unmute: c("amixer -q set Capture cap"),
+                get_mute: c("amixer get Capture"),
+                get_volume: c("amixer get Capture"),
+                set_volume: c("amixer -q set Capture {percent}%"),
+                list_outputs: None,
+                default_output: c("Master"),
+                get_output_volume: c("amixer get {device}"),
+                set_output_volume: c("amixer -q set {device} {percent}%"),
+                cubic: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Runs a command with the system shell, and returns its output.
+fn run(command: &str) -> Result<String, AudioError> {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(command);
+        c
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(command);
+        c
+    };
+
+    debug!("Running: {}", command);
+    let output = cmd.env("LC_ALL", "C").output().map_err(|e| AudioError {
+        msg: format!("Error running {:?}: {}", command, e),
+    })?;
+    if !output.status.success() {
+        return Err(AudioError {
+            msg: format!(
+                "{:?} failed ({}): {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Substitutes a volume into a command.
+fn with_volume(command: &str, volume: f32) -> String {
+    command
+        .replace("{volume}", &format!("{:.3}", volume))
+        .replace("{percent}", &format!("{:.0}", volume * 100.))
+}
+
+/// Quotes an argument for the system shell.
+fn quote(arg: &str) -> String {
+    #[cfg(windows)]
+    return format!("\"{}\"", arg.replace('"', "\"\""));
+    #[cfg(not(windows))]
+    return format!("'{}'", arg.replace('\'', "'\\''"));
+}
+
+fn not_configured(what: &str) -> AudioError {
+    AudioError {
+        msg: format!("No command configured to {}", what),
+    }
+}
+
+/// Parses a mute state from command output.
+///
+/// This understands the output of `amixer` (`[on]` / `[off]`), `pactl`
+/// (`Mute: yes`), `wpctl` (`[MUTED]`), and commands that print `0` / `1`.
+pub fn parse_mute(output: &str) -> Option<bool> {
+    let output = output.trim().to_lowercase();
+    match output.as_str() {
+        "1" => return Some(true),
+        "0" => return Some(false),
+        _ => {}
+    }
+    if output.contains("[muted]") {
+        return Some(true);
+    }
+    for token in output.split_whitespace() {
+        match token.trim_matches(|c| c == '[' || c == ']' || c == ':' || c == ',') {
+            "yes" | "true" | "off" | "muted" => return Some(true),
+            "no" | "false" | "on" | "unmuted" => return Some(false),
+            _ => {}
+        }
+    }
+    if output.contains("volume:") {
+        // wpctl only says when the device is muted.
+        return Some(false);
+    }
+    None
+}
+
+/// Parses a volume, as a scalar between 0.0 and 1.0, from command output.
+///
+/// This uses the first percentage (eg: `[63%]` from `amixer`, or `63%` from
+/// `pactl`), or otherwise the first decimal number (eg: `Volume: 0.63` from
+/// `wpctl`).
+pub fn parse_volume(output: &str) -> Option<f32> {
+    let tokens = || {
+        output
+            .split_whitespace()
+            .map(|t| t.trim_matches(|c| c == '[' || c == ']' || c == ','))
+    };
+    tokens()
+        .find_map(|t| t.strip_suffix('%').and_then(|p| p.parse::<f32>().ok()))
+        .map(|p| p / 100.)
+        .or_else(|| {
+            tokens()
+                .filter(|t| t.contains('.'))
+                .find_map(|t| t.parse::<f32>().ok())
+        })
+}
+
+pub struct CommandAudioController {
+    config: CommandConfig,
+}
+
+impl CommandAudioController {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(config: CommandConfig) -> Result<Box<dyn AudioControllerTrait>, AudioError> {
+        if config.mute.is_none() || config.unmute.is_none() {
+            return Err(AudioError {
+                msg: "Both mute and unmute commands are required".to_string(),
+            });
+        }
+        Ok(Box::new(CommandAudioController { config }))
+    }
+}
+
+impl AudioControllerTrait for CommandAudioController {
+    fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
+        Ok(Box::new(CommandInputDevice {
+            config: self.config.clone(),
+        }))
+    }
+
+    fn get_default_output_device(&self) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError> {
+        let device = self
+            .config
+            .default_output
+            .as_ref()
+            .ok_or_else(|| not_configured("find the default output device"))?;
+        CommandOutputDevice::new(&self.config, device)
+    }
+
+    fn get_output_devices(&self) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, AudioError> {
+        let command = self
+            .config
+            .list_outputs
+            .as_ref()
+            .ok_or_else(|| not_configured("list output devices"))?;
+        run(command)?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| CommandOutputDevice::new(&self.config, l))
+            .collect()
+    }
+}
+
+pub struct CommandInputDevice {
+    config: CommandConfig,
+}
+
+impl AudioInputDeviceTrait for CommandInputDevice {
+    fn name(&self) -> Result<String, AudioError> {
+        match &self.config.name {
+            Some(c) => Ok(run(c)?.trim().to_string()),
+            None => Ok("Command-controlled microphone".to_string()),
+        }
+    }
+
+    fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
+        let command = if state {
+            &self.config.mute
+        } else {
+            &self.config.unmute
+        };
+        run(command.as_ref().unwrap())?; // Checked in CommandAudioController::new
+        Ok(state)
+    }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        let command = self
+            .config
+            .get_mute
+            .as_ref()
+            .ok_or_else(|| not_configured("get the mute state"))?;
+        let output = run(command)?;
+        parse_mute(&output).ok_or_else(|| AudioError {
+            msg: format!("Could not parse mute state from {:?}", output.trim()),
+        })
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        // Actions read the volume before they set it, so this catches a
+        // missing set command up front.
+        let command = match (&self.config.get_volume, &self.config.set_volume) {
+            (Some(g), Some(_)) => g,
+            _ => return Err(not_configured("control the volume")),
+        };
+        let output = run(command)?;
+        parse_volume(&output).ok_or_else(|| AudioError {
+            msg: format!("Could not parse volume from {:?}", output.trim()),
+        })
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        let command = self
+            .config
+            .set_volume
+            .as_ref()
+            .ok_or_else(|| not_configured("set the volume"))?;
+        run(&with_volume(command, volume)).map(|_| ())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(if self.config.cubic {
+            adjust_cubic_volume(volume, db)
+        } else {
+            volume * db_to_scalar(db)
+        })
+    }
+
+    fn ramp_step(&self) -> Duration {
+        COMMAND_RAMP_STEP
+    }
+}
+
+pub struct CommandOutputDevice {
+    device: String,
+    get_volume: String,
+    set_volume: String,
+    cubic: bool,
+}
+
+impl CommandOutputDevice {
+    #[allow(clippy::new_ret_no_self)]
+    fn new(
+        config: &CommandConfig,
+        device: &str,
+    ) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError> {
+        let (get_volume, set_volume) = match (&config.get_output_volume, &config.set_output_volume)
+        {
+            (Some(g), Some(s)) => (g, s),
+            _ => return Err(not_configured("control output devices")),
+        };
+        Ok(Box::new(CommandOutputDevice {
+            device: device.to_string(),
+            get_volume: get_volume.replace("{device}", &quote(device)),
+            set_volume: set_volume.replace("{device}", &quote(device)),
+            cubic: config.cubic,
+        }))
+    }
+}
+
+impl AudioOutputDeviceTrait for CommandOutputDevice {
+    fn id(&self) -> Result<String, AudioError> {
+        Ok(self.device.clone())
+    }
+
+    fn name(&self) -> Result<String, AudioError> {
+        Ok(self.device.clone())
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        let output = run(&self.get_volume)?;
+        parse_volume(&output).ok_or_else(|| AudioError {
+            msg: format!("Could not parse volume from {:?}", output.trim()),
+        })
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        run(&with_volume(&self.set_volume, volume)).map(|_| ())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(if self.cubic {
+            adjust_cubic_volume(volume, db)
+        } else {
+            volume * db_to_scalar(db)
+        })
+    }
+
+    fn ramp_step(&self) -> Duration {
+        COMMAND_RAMP_STEP
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_output() {
+        assert_eq!(
+            parse_volume("Volume: front-left: 41287 /  63% / -12.04 dB"),
+            Some(0.63)
+        );
+        assert_eq!(parse_volume("Volume: 0.40 [MUTED]"), Some(0.4));
+        assert_eq!(parse_mute("Volume: 0.40 [MUTED]"), Some(true));
+        assert_eq!(parse_mute("Volume: 0.40"), Some(false));
+        assert_eq!(parse_mute("Mute: no"), Some(false));
+    }
+
+    #[test]
+    fn output_devices() {
+        let config = CommandConfig {
+            list_outputs: Some("printf 'speakers\\nit'\\''s a headset\\n'".to_string()),
+            get_output_volume: Some("echo {device} 25%".to_string()),
+            set_output_volume: Some(
+                "test {device} = \"it's a headset\" -a {percent} = 50".to_string(),
+            ),
+            ..CommandConfig::default()
+        };
+        let audio = CommandAudioController {
+            config: config.clone(),
+        };
+        assert!(audio.get_default_output_device().is_err());
+
+        let devices = audio.get_output_devices().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].id().unwrap(), "it's a headset");
+        assert_eq!(devices[1].get_volume().unwrap(), 0.25);
+        devices[1].set_volume(0.5).unwrap();
+        assert!(devices[0].set_volume(0.5).is_err());
+
+        let audio = CommandAudioController {
+            config: CommandConfig {
+                set_output_volume: None,
+                default_output: Some("speakers".to_string()),
+                ..config
+            },
+        };
+        assert!(audio.get_default_output_device().is_err());
+    }
+
+    #[test]
+    fn volume_actions_need_volume_commands() {
+        use crate::actions::{DuckAction, KnobVolumeAction, MuteAction};
+        use std::time::Duration;
+
+        let config = CommandConfig {
+            mute: Some("true".to_string()),
+            unmute: Some("true".to_string()),
+            get_volume: Some("echo 40%".to_string()),
+            ..CommandConfig::default()
+        };
+        let audio = CommandAudioController::new(config.clone()).unwrap();
+        let ramp = Duration::from_millis(100);
+        assert!(MuteAction::new(audio.get_comms_device().unwrap(), Duration::ZERO).is_ok());
+        assert!(MuteAction::new(audio.get_comms_device().unwrap(), ramp).is_err());
+        assert!(DuckAction::new(audio.get_comms_device().unwrap(), -20., ramp).is_err());
+        assert!(KnobVolumeAction::new(vec![audio.get_comms_device().unwrap()], 0.02, 1.).is_err());
+
+        let audio = CommandAudioController::new(CommandConfig {
+            set_volume: Some("true {percent}".to_string()),
+            ..config
+        })
+        .unwrap();
+        assert!(MuteAction::new(audio.get_comms_device().unwrap(), ramp).is_ok());
+        assert_eq!(audio.get_comms_device().unwrap().get_volume().unwrap(), 0.4);
+    }
+}
@@ -0,0 +1,567 @@
+/*
+ * Discord local RPC action, which sets Discord's mute or input mode directly.
+ *
+ * Simulated keypresses don't trigger Discord's push-to-talk on macOS, so this
+ * talks to the Discord client's IPC socket instead. Each message is framed
+ * as a little-endian opcode and length, followed by a JSON payload.
+ */
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::actions::{Action, ActionError};
+use crate::ipc;
+
+const OP_HANDSHAKE: u32 = 0;
+const OP_FRAME: u32 = 1;
+const OP_CLOSE: u32 = 2;
+const OP_PING: u32 = 3;
+const OP_PONG: u32 = 4;
+const RPC_VERSION: u32 = 1;
+/// Largest message we'll accept from Discord.
+const MAX_FRAME_SIZE: usize = 1 << 20;
+
+const SCOPES: &[&str] = &["rpc", "rpc.voice.read", "rpc.voice.write"];
+const TOKEN_URL: &str = "https://discord.com/api/oauth2/token";
+
+/// How long to wait for Discord to reply to a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait for the user to approve the client in Discord.
+const AUTHORIZE_TIMEOUT: Duration = Duration::from_secs(120);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the footswitch changes in Discord.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiscordMode {
+    /// Unmutes while pressed, and mutes while released.
+    Mute,
+    /// Uses voice activity while pressed, and push-to-talk while released.
+    Voice,
+}
+
+pub struct DiscordConfig {
+    /// The application's OAuth2 client ID.
+    pub client_id: String,
+    /// The application's OAuth2 client secret, needed to authorize the
+    /// client the first time.
+    pub client_secret: Option<String>,
+    /// The IPC socket or named pipe, or `None` to find Discord's.
+    pub ipc_path: Option<PathBuf>,
+    pub mode: DiscordMode,
+}
+
+/// Path to the file where the OAuth2 tokens are saved.
+fn token_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|p| p.join("footswitch").join("discord-token.json"))
+}
+
+fn load_token() -> Option<Value> {
+    serde_json::from_str(&fs::read_to_string(token_path()?).ok()?).ok()
+}
+
+fn save_token(token: &Value) -> io::Result<()> {
+    let path = match token_path() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(token.to_string().as_bytes())
+}
+
+/// Requests OAuth2 tokens from Discord, with an authorization code or a
+/// refresh token.
+fn request_token(config: &DiscordConfig, grant: &[(&str, &str)]) -> Result<Value, String> {
+    let secret = config
+        .client_secret
+        .as_deref()
+        .ok_or("A client secret is needed to authorize with Discord")?;
+    let mut form = vec![
+        ("client_id", config.client_id.as_str()),
+        ("client_secret", secret),
+    ];
+    form.extend_from_slice(grant);
+    let token: Value = ureq::post(TOKEN_URL)
+        .timeout(Duration::from_secs(10))
+        .send_form(&form)
+        .map_err(|e| format!("Error requesting Discord token: {}", e))?
+        .into_json()
+        .map_err(|e| format!("Error reading Discord token: {}", e))?;
+    if token["access_token"].is_string() {
+        Ok(token)
+    } else {
+        Err(format!("Discord didn't return a token: {}", token))
+    }
+}
+
+/// Possible locations of Discord's IPC socket or named pipe.
+fn ipc_paths() -> Vec<PathBuf> {
+    let dirs: Vec<PathBuf> = if cfg!(windows) {
+        vec![PathBuf::from(r"\\.\pipe")]
+    } else {
+        ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"]
+            .iter()
+            .filter_map(|v| env::var_os(v).map(PathBuf::from))
+            .chain(Some(PathBuf::from("/tmp")))
+            // Flatpak and Snap packages of Discord.
+            .flat_map(|d| {
+                vec![
+                    d.join("app/com.discordapp.Discord"),
+                    d.join("snap.discord"),
+                    d,
+                ]
+            })
+            .collect()
+    };
+    dirs.iter()
+        .flat_map(|d| (0..10).map(move |i| d.join(format!("discord-ipc-{}", i))))
+        .collect()
+}
+
+struct Connection {
+    stream: Box<dyn ipc::Stream>,
+    nonce: u64,
+}
+
+impl Connection {
+    fn send(&mut self, op: u32, payload: &Value) -> Result<(), String> {
+        let payload = payload.to_string();
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.extend_from_slice(&op.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(payload.as_bytes());
+        self.stream
+            .write_all(&frame)
+            .map_err(|e| format!("Error writing to Discord: {}", e))
+    }
+
+    /// Reads the next frame, answering any pings.
+    fn recv(&mut self) -> Result<Value, String> {
+        loop {
+            let mut header = [0; 8];
+            self.stream
+                .read_exact(&mut header)
+                .map_err(|e| format!("Error reading from Discord: {}", e))?;
+            let op = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            if len > MAX_FRAME_SIZE {
+                return Err(format!("Discord message too long ({} bytes)", len));
+            }
+            let mut payload = vec![0; len];
+            self.stream
+                .read_exact(&mut payload)
+                .map_err(|e| format!("Error reading from Discord: {}", e))?;
+            let payload: Value = serde_json::from_slice(&payload)
+                .map_err(|e| format!("Bad message from Discord: {}", e))?;
+            match op {
+                OP_FRAME => return Ok(payload),
+                OP_PING => self.send(OP_PONG, &payload)?,
+                OP_CLOSE => {
+                    return Err(format!(
+                        "Discord closed the connection: {}",
+                        payload["message"].as_str().unwrap_or("no reason given")
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Runs an RPC command, and returns its data.
+    fn command(&mut self, cmd: &str, args: Value, timeout: Duration) -> Result<Value, String> {
+        self.nonce += 1;
+        let nonce = self.nonce.to_string();
+        self.send(
+            OP_FRAME,
+            &json!({ "cmd": cmd, "args": args, "nonce": nonce }),
+        )?;
+        self.stream
+            .set_timeout(timeout)
+            .map_err(|e| e.to_string())?;
+        loop {
+            let mut reply = self.recv()?;
+            // Skip events, and replies to commands that timed out.
+            if reply["nonce"].as_str() != Some(nonce.as_str()) {
+                continue;
+            }
+            if reply["evt"] == "ERROR" {
+                return Err(format!(
+                    "Discord {} failed: {}",
+                    cmd,
+                    reply["data"]["message"].as_str().unwrap_or("unknown error")
+                ));
+            }
+            return Ok(reply["data"].take());
+        }
+    }
+
+    /// Connects to Discord, and waits for it to be ready.
+    fn open(config: &DiscordConfig) -> Result<Self, String> {
+        let (path, stream) = match &config.ipc_path {
+            Some(p) => (
+                p.clone(),
+                ipc::connect(p).map_err(|e| format!("Failed to open {}: {}", p.display(), e))?,
+            ),
+            None => ipc_paths()
+                .into_iter()
+                .find_map(|p| ipc::connect(&p).ok().map(|s| (p, s)))
+                .ok_or("Discord isn't running")?,
+        };
+        debug!("Connected to Discord at {}", path.display());
+        let mut conn = Connection { stream, nonce: 0 };
+        conn.send(
+            OP_HANDSHAKE,
+            &json!({ "v": RPC_VERSION, "client_id": config.client_id }),
+        )?;
+        conn.stream
+            .set_timeout(REPLY_TIMEOUT)
+            .map_err(|e| e.to_string())?;
+        let ready = conn.recv()?;
+        if ready["evt"] != "READY" {
+            return Err(format!("Unexpected reply from Discord: {}", ready));
+        }
+        Ok(conn)
+    }
+
+    /// Authenticates with a saved token, refreshing it or asking the user to
+    /// authorize the client if needed.
+    fn authenticate(
+        &mut self,
+        config: &DiscordConfig,
+        token: &mut Option<Value>,
+        interactive: bool,
+    ) -> Result<(), String> {
+        let mut error = None;
+        if let Some(t) = token.as_ref() {
+            match self.command(
+                "AUTHENTICATE",
+                json!({ "access_token": t["access_token"] }),
+                REPLY_TIMEOUT,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(e) => error = Some(e),
+            }
+            if let Some(refresh) = t["refresh_token"].as_str() {
+                if let Ok(t) = request_token(
+                    config,
+                    &[("grant_type", "refresh_token"), ("refresh_token", refresh)],
+                ) {
+                    *token = Some(t);
+                    return self.authenticate_new(token);
+                }
+            }
+        }
+        if !interactive {
+            return Err(error.unwrap_or_else(|| "Not authorized with Discord".to_string()));
+        }
+
+        info!("Waiting for you to authorize footswitch in Discord...");
+        let code = self.command(
+            "AUTHORIZE",
+            json!({ "client_id": config.client_id, "scopes": SCOPES }),
+            AUTHORIZE_TIMEOUT,
+        )?;
+        let code = code["code"]
+            .as_str()
+            .ok_or("Discord didn't return an authorization code")?;
+        *token = Some(request_token(
+            config,
+            &[("grant_type", "authorization_code"), ("code", code)],
+        )?);
+        self.authenticate_new(token)
+    }
+
+    /// Saves a new token, and authenticates with it.
+    fn authenticate_new(&mut self, token: &Option<Value>) -> Result<(), String> {
+        let token = token.as_ref().unwrap();
+        if let Err(e) = save_token(token) {
+            warn!("Error saving Discord token: {}", e);
+        }
+        self.command(
+            "AUTHENTICATE",
+            json!({ "access_token": token["access_token"] }),
+            REPLY_TIMEOUT,
+        )
+        .map(|_| ())
+    }
+}
+
+/// Keeps Discord's state in line with the footswitch, from its own thread.
+struct Worker {
+    config: DiscordConfig,
+    token: Option<Value>,
+    conn: Option<Connection>,
+    pressed: bool,
+    /// Discord's voice settings before we changed them.
+    initial: Option<Value>,
+}
+
+impl Worker {
+    /// Connects and authenticates, asking the user to authorize the client if
+    /// it never has been.
+    fn connect(&mut self) -> Result<Connection, String> {
+        let interactive = self.token.is_none();
+        let mut conn = Connection::open(&self.config)?;
+        conn.authenticate(&self.config, &mut self.token, interactive)?;
+        Ok(conn)
+    }
+
+    /// Starts using a new connection, and applies the footswitch's state.
+    fn connected(&mut self, mut conn: Connection) {
+        info!("Connected to Discord");
+        if self.initial.is_none() {
+            self.initial = conn
+                .command("GET_VOICE_SETTINGS", json!({}), REPLY_TIMEOUT)
+                .map_err(|e| warn!("{}", e))
+                .ok();
+        }
+        self.conn = Some(conn);
+        self.apply();
+    }
+
+    /// Sets Discord's state to match the footswitch.
+    fn apply(&mut self) {
+        let conn = match self.conn.as_mut() {
+            Some(c) => c,
+            None => return,
+        };
+        let args = match self.config.mode {
+            DiscordMode::Mute => json!({ "mute": !self.pressed }),
+            DiscordMode::Voice => json!({ "mode": { "type": if self.pressed {
+                "VOICE_ACTIVITY"
+            } else {
+                "PUSH_TO_TALK"
+            } } }),
+        };
+        if let Err(e) = conn.command("SET_VOICE_SETTINGS", args, REPLY_TIMEOUT) {
+            warn!("{}", e);
+            self.conn = None;
+        }
+    }
+
+    /// Puts back Discord's original settings.
+    fn restore(&mut self) {
+        if let (Some(conn), Some(initial)) = (self.conn.as_mut(), self.initial.as_ref()) {
+            let args = match self.config.mode {
+                DiscordMode::Mute => json!({ "mute": initial["mute"] }),
+                DiscordMode::Voice => json!({ "mode": { "type": initial["mode"]["type"] } }),
+            };
+            let _ = conn.command("SET_VOICE_SETTINGS", args, REPLY_TIMEOUT);
+        }
+    }
+
+    /// Follows the footswitch's state from `states`, reconnecting whenever
+    /// Discord goes away, until `states` is closed.
+    ///
+    /// `_running` is dropped when this finishes.
+    fn run(mut self, states: Receiver<bool>, _running: Sender<()>) {
+        loop {
+            if self.conn.is_none() {
+                match self.connect() {
+                    Ok(conn) => self.connected(conn),
+                    Err(e) => debug!("{}", e),
+                }
+            }
+            match states.recv_timeout(RECONNECT_INTERVAL) {
+                Ok(pressed) => {
+                    // Skip over states that have already been replaced.
+                    self.pressed = states.try_iter().last().unwrap_or(pressed);
+                    self.apply();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.restore();
+    }
+}
+
+/// Sets Discord's mute or input mode when the footswitch changes state.
+///
+/// Discord is driven from another thread, so that a slow or missing Discord
+/// never holds up the footswitch. If Discord goes away, this keeps trying to
+/// reconnect, and applies the footswitch's current state when it comes back.
+pub struct DiscordAction {
+    /// Footswitch states for the worker to apply.
+    states: Option<Sender<bool>>,
+    /// Disconnected when the worker has finished.
+    running: Receiver<()>,
+}
+
+impl DiscordAction {
+    /// Connects to Discord, and asks the user to authorize the client if
+    /// there's no saved token.
+    pub fn new(config: DiscordConfig) -> Result<Self, ActionError> {
+        Self::with_token(config, load_token())
+    }
+
+    fn with_token(config: DiscordConfig, token: Option<Value>) -> Result<Self, ActionError> {
+        let mut worker = Worker {
+            config,
+            token,
+            conn: None,
+            pressed: false,
+            initial: None,
+        };
+        match Connection::open(&worker.config) {
+            Ok(mut conn) => {
+                conn.authenticate(&worker.config, &mut worker.token, true)
+                    .map_err(|msg| ActionError { msg })?;
+                worker.connected(conn);
+            }
+            Err(e) => warn!("{}; will keep trying to connect", e),
+        }
+
+        let (states, rx) = mpsc::channel();
+        let (running_tx, running) = mpsc::channel();
+        thread::spawn(move || worker.run(rx, running_tx));
+        Ok(DiscordAction {
+            states: Some(states),
+            running,
+        })
+    }
+
+    fn send(&mut self, pressed: bool) {
+        if let Some(states) = self.states.as_ref() {
+            let _ = states.send(pressed);
+        }
+    }
+}
+
+impl Action for DiscordAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.send(true);
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.send(false);
+        Ok(())
+    }
+}
+
+impl Drop for DiscordAction {
+    fn drop(&mut self) {
+        // Give the worker a chance to restore Discord's settings.
+        self.states = None;
+        let _ = self.running.recv_timeout(REPLY_TIMEOUT);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn read_frame(stream: &mut UnixStream) -> Option<(u32, Value)> {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).ok()?;
+        let op = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).ok()?;
+        Some((op, serde_json::from_slice(&payload).unwrap()))
+    }
+
+    fn write_frame(stream: &mut UnixStream, op: u32, payload: &Value) {
+        let payload = payload.to_string();
+        stream.write_all(&op.to_le_bytes()).unwrap();
+        stream
+            .write_all(&(payload.len() as u32).to_le_bytes())
+            .unwrap();
+        stream.write_all(payload.as_bytes()).unwrap();
+    }
+
+    /// Runs a stand-in Discord which answers every command, and sends each
+    /// message it gets to `log`.
+    fn serve(listener: UnixListener, log: Sender<(u32, Value)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let (op, handshake) = read_frame(&mut stream).unwrap();
+        log.send((op, handshake)).unwrap();
+        write_frame(
+            &mut stream,
+            OP_FRAME,
+            &json!({ "cmd": "DISPATCH", "evt": "READY", "data": {} }),
+        );
+        while let Some((op, msg)) = read_frame(&mut stream) {
+            let data = match msg["cmd"].as_str() {
+                Some("GET_VOICE_SETTINGS") => {
+                    json!({ "mute": true, "mode": { "type": "PUSH_TO_TALK" } })
+                }
+                _ => json!({}),
+            };
+            write_frame(
+                &mut stream,
+                OP_FRAME,
+                &json!({ "cmd": msg["cmd"], "nonce": msg["nonce"], "data": data }),
+            );
+            log.send((op, msg)).unwrap();
+        }
+    }
+
+    #[test]
+    fn press_and_release() {
+        let path = env::temp_dir().join(format!("footswitch-discord-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (log_tx, log) = mpsc::channel();
+        thread::spawn(move || serve(listener, log_tx));
+        let next = || log.recv_timeout(TIMEOUT).unwrap();
+
+        let config = DiscordConfig {
+            client_id: "1234".to_string(),
+            client_secret: None,
+            ipc_path: Some(path.clone()),
+            mode: DiscordMode::Mute,
+        };
+        let mut action =
+            DiscordAction::with_token(config, Some(json!({ "access_token": "hunter2" }))).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            next(),
+            (OP_HANDSHAKE, json!({ "v": 1, "client_id": "1234" }))
+        );
+        let (op, msg) = next();
+        assert_eq!(op, OP_FRAME);
+        assert_eq!(msg["cmd"], "AUTHENTICATE");
+        assert_eq!(msg["args"], json!({ "access_token": "hunter2" }));
+        assert_eq!(next().1["cmd"], "GET_VOICE_SETTINGS");
+        let msg = next().1;
+        assert_eq!(msg["cmd"], "SET_VOICE_SETTINGS");
+        assert_eq!(msg["args"], json!({ "mute": true }));
+
+        action.press().unwrap();
+        let msg = next().1;
+        assert_eq!(msg["cmd"], "SET_VOICE_SETTINGS");
+        assert_eq!(msg["args"], json!({ "mute": false }));
+
+        action.release().unwrap();
+        let msg = next().1;
+        assert_eq!(msg["cmd"], "SET_VOICE_SETTINGS");
+        assert_eq!(msg["args"], json!({ "mute": true }));
+
+        // The original settings are restored.
+        action.press().unwrap();
+        assert_eq!(next().1["args"], json!({ "mute": false }));
+        drop(action);
+        assert_eq!(next().1["args"], json!({ "mute": true }));
+    }
+}
@@ -0,0 +1,398 @@
+/*
+ * evdev input source, for USB HID footswitches, keyboard keys and analog
+ * pedals on Linux.
+ */
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use evdev::{AbsoluteAxisType, Device, InputEventKind, Key, RelativeAxisType};
+
+use crate::input::{EventSender, InputError, InputEvent, InputSource, SourceOptions, Threshold};
+
+const MISSING_DEVICE_WAIT_TIME: Duration = Duration::from_secs(1);
+
+/// How to find an input device.
+#[derive(Debug)]
+enum DeviceMatcher {
+    /// A device node, eg: `/dev/input/event3` or `/dev/input/by-id/...`.
+    Path(PathBuf),
+    /// The device's name, eg: `PCsensor FootSwitch`.
+    Name(String),
+    /// The device's USB vendor and product ID.
+    Id(u16, u16),
+}
+
+impl DeviceMatcher {
+    fn matches(&self, path: &Path, device: &Device) -> bool {
+        match self {
+            DeviceMatcher::Path(p) => p == path,
+            DeviceMatcher::Name(n) => device.name() == Some(n.as_str()),
+            DeviceMatcher::Id(vendor, product) => {
+                let id = device.input_id();
+                id.vendor() == *vendor && id.product() == *product
+            }
+        }
+    }
+}
+
+/// The control on an input device which is used as the footswitch.
+#[derive(Clone, Copy, Debug)]
+enum Control {
+    /// A key, or any key if `None`.
+    Key(Option<Key>),
+    /// An absolute axis, eg: a pedal or a trigger.
+    Axis(AbsoluteAxisType),
+}
+
+impl Control {
+    /// Returns `true` if the device has this control.
+    fn is_on(&self, device: &Device) -> bool {
+        match *self {
+            Control::Key(key) => device
+                .supported_keys()
+                .is_some_and(|keys| key.is_none_or(|k| keys.contains(k))),
+            Control::Axis(axis) => device
+                .supported_absolute_axes()
+                .is_some_and(|axes| axes.contains(axis)),
+        }
+    }
+}
+
+/// Parses a USB ID, in the form `VVVV:PPPP`.
+pub fn parse_id(id: &str) -> Option<(u16, u16)> {
+    let (vendor, product) = id.split_once(':')?;
+    Some((
+        u16::from_str_radix(vendor, 16).ok()?,
+        u16::from_str_radix(product, 16).ok()?,
+    ))
+}
+
+/// Parses a key name (eg: `KEY_F13` or `BTN_LEFT`), or a numeric key code.
+fn parse_key(key: &str) -> Option<Key> {
+    Key::from_str(key)
+        .ok()
+        .or_else(|| key.parse().ok().map(Key::new))
+}
+
+/// Gets the minimum and maximum values of an absolute axis.
+fn axis_range(device: &Device, axis: AbsoluteAxisType) -> Result<(i32, i32), InputError> {
+    let state = device.get_abs_state().map_err(|e| InputError {
+        msg: format!("Failed to get axis state: {}", e),
+    })?;
+    let info = state[axis.0 as usize];
+    if info.maximum <= info.minimum {
+        return Err(InputError {
+            msg: format!("Axis {:?} has no range", axis),
+        });
+    }
+    Ok((info.minimum, info.maximum))
+}
+
+/// Finds and opens an input device.
+///
+/// Many USB HID devices have several event devices with the same name and
+/// ID, so this picks the first one which has the control we're looking for.
+fn open_device(
+    matcher: &DeviceMatcher,
+    control: Control,
+    grab: bool,
+) -> Result<(PathBuf, Device), InputError> {
+    let (path, mut device) = match matcher {
+        DeviceMatcher::Path(p) => {
+            Device::open(p)
+                .map(|d| (p.clone(), d))
+                .map_err(|e| InputError {
+                    msg: format!("Failed to open {}: {}", p.display(), e),
+                })?
+        }
+        _ => evdev::enumerate()
+            .find(|(path, device)| matcher.matches(path, device) && control.is_on(device))
+            .ok_or_else(|| InputError {
+                msg: format!("No input device found matching {:?}", matcher),
+            })?,
+    };
+
+    if !control.is_on(&device) {
+        warn!("{} doesn't report having {:?}", path.display(), control);
+    }
+    if grab {
+        device.grab().map_err(|e| InputError {
+            msg: format!("Failed to grab {}: {}", path.display(), e),
+        })?;
+    }
+    Ok((path, device))
+}
+
+/// Reads key or axis events from a Linux input device.
+pub struct EvdevSource {
+    matcher: DeviceMatcher,
+    control: Control,
+    /// Press and release thresholds for `Control::Axis`.
+    threshold: Threshold,
+    /// A relative axis (eg: a dial) which sends knob events.
+    knob: Option<RelativeAxisType>,
+    grab: bool,
+    path: PathBuf,
+    device: Device,
+}
+
+impl EvdevSource {
+    /// Creates an evdev source from options in the form
+    /// `path=DEVICE|name=NAME|id=VVVV:PPPP[,key=KEY|axis=AXIS][,knob=AXIS][,grab]`.
+    pub fn new(options: &str) -> Result<Self, InputError> {
+        let options = SourceOptions::parse(
+            options,
+            &[
+                "path", "name", "id", "key", "axis", "knob", "press", "release", "grab",
+            ],
+        )?;
+        let matcher = if let Some(path) = options.get("path") {
+            DeviceMatcher::Path(PathBuf::from(path))
+        } else if let Some(name) = options.get("name") {
+            DeviceMatcher::Name(name.to_string())
+        } else if let Some(id) = options.get("id") {
+            let (vendor, product) = parse_id(id).ok_or_else(|| InputError {
+                msg: format!("Invalid USB ID {:?}, expected VVVV:PPPP", id),
+            })?;
+            DeviceMatcher::Id(vendor, product)
+        } else {
+            return Err(InputError {
+                msg: "evdev source needs a path, name or id".to_string(),
+            });
+        };
+        let control = match (options.get("key"), options.get("axis")) {
+            (Some(_), Some(_)) => {
+                return Err(InputError {
+                    msg: "evdev source can't use both a key and an axis".to_string(),
+                });
+            }
+            (Some(k), None) => Control::Key(Some(parse_key(k).ok_or_else(|| InputError {
+                msg: format!("Unknown key {:?}", k),
+            })?)),
+            (None, Some(a)) => {
+                Control::Axis(AbsoluteAxisType::from_str(a).map_err(|_| InputError {
+                    msg: format!("Unknown axis {:?}", a),
+                })?)
+            }
+            (None, None) => Control::Key(None),
+        };
+        let threshold = Threshold::from_options(&options)?;
+        let knob = match options.get("knob") {
+            Some(k) => Some(RelativeAxisType::from_str(k).map_err(|_| InputError {
+                msg: format!("Unknown relative axis {:?}", k),
+            })?),
+            None => None,
+        };
+        let grab = options.flag("grab");
+
+        let (path, device) = open_device(&matcher, control, grab)?;
+        if let Control::Axis(axis) = control {
+            axis_range(&device, axis)?;
+        }
+        if let Some(knob) = knob {
+            if !device
+                .supported_relative_axes()
+                .is_some_and(|axes| axes.contains(knob))
+            {
+                return Err(InputError {
+                    msg: format!("{} doesn't have {:?}", path.display(), knob),
+                });
+            }
+        }
+        Ok(EvdevSource {
+            matcher,
+            control,
+            threshold,
+            knob,
+            grab,
+            path,
+            device,
+        })
+    }
+}
+
+impl InputSource for EvdevSource {
+    fn name(&self) -> String {
+        format!(
+            "Input device: {} ({})",
+            self.device.name().unwrap_or("unnamed"),
+            self.path.display()
+        )
+    }
+
+    /// Sends events from the input device to the channel.
+    ///
+    /// The footswitch is pressed while the key (or any key, if none was
+    /// given) is held down, or while the axis is past the press threshold.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let EvdevSource {
+            matcher,
+            control,
+            mut threshold,
+            knob,
+            grab,
+            mut path,
+            mut device,
+        } = *self;
+        let mut held = HashSet::new();
+        let get_range = |device: &Device| match control {
+            Control::Axis(axis) => axis_range(device, axis).unwrap_or((0, 1)),
+            Control::Key(_) => (0, 1),
+        };
+        let mut range = get_range(&device);
+
+        loop {
+            let error = match device.fetch_events() {
+                Ok(events) => {
+                    for event in events {
+                        let mut events = Vec::with_capacity(2);
+                        match (control, event.kind()) {
+                            (Control::Key(key), InputEventKind::Key(k))
+                                if key.is_none_or(|key| key == k) =>
+                            {
+                                let was_held = !held.is_empty();
+                                match event.value() {
+                                    0 => held.remove(&k),
+                                    1 => held.insert(k),
+                                    // Key repeat
+                                    _ => continue,
+                                };
+                                if was_held == held.is_empty() {
+                                    events.push(if was_held {
+                                        InputEvent::Release
+                                    } else {
+                                        InputEvent::Press
+                                    });
+                                }
+                            }
+                            (Control::Axis(axis), InputEventKind::AbsAxis(a)) if axis == a => {
+                                let (min, max) = range;
+                                let value = ((event.value() - min) as f32 / (max - min) as f32)
+                                    .clamp(0., 1.);
+                                events.push(InputEvent::Axis(value));
+                                events.extend(threshold.update(value));
+                            }
+                            (_, InputEventKind::RelAxis(r)) if knob == Some(r) => {
+                                events.push(InputEvent::Delta(event.value()));
+                            }
+                            _ => continue,
+                        }
+                        for e in events {
+                            if !chan.send(e) {
+                                // Other end of the channel has probably gone
+                                // away. Shut down the thread.
+                                return;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Err(error) => error,
+            };
+            warn!("Error reading {}: {}", path.display(), error);
+
+            // Don't leave the footswitch held while the device is missing.
+            held.clear();
+            threshold.reset();
+            if !chan.release() {
+                return;
+            }
+
+            // Something went wrong - wait for the device to come back.
+            (path, device) = loop {
+                thread::sleep(MISSING_DEVICE_WAIT_TIME);
+                if let Ok(d) = open_device(&matcher, control, grab) {
+                    warn!("Reconnecting device {}", d.0.display());
+                    break d;
+                }
+            };
+            range = get_range(&device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{spawn_sources, SourceEvent};
+    use evdev::uinput::VirtualDeviceBuilder;
+    use evdev::{AttributeSet, EventType};
+    use std::sync::mpsc;
+
+    #[test]
+    fn options() {
+        assert_eq!(parse_id("0c45:7403"), Some((0x0c45, 0x7403)));
+        assert_eq!(parse_id("0c45"), None);
+        assert_eq!(parse_key("KEY_F13"), Some(Key::KEY_F13));
+        assert_eq!(parse_key("183"), Some(Key::KEY_F13));
+        assert_eq!(parse_key("KEY_NOPE"), None);
+        assert!(EvdevSource::new("key=KEY_F13").is_err());
+        assert!(EvdevSource::new("path=/dev/null,key=KEY_F13,axis=ABS_X").is_err());
+        assert!(EvdevSource::new("path=/dev/null,press=0.2,release=0.5").is_err());
+    }
+
+    #[test]
+    fn uinput_device() {
+        let name = format!("footswitch test {}", std::process::id());
+        let mut keys = AttributeSet::<Key>::new();
+        keys.insert(Key::KEY_A);
+        keys.insert(Key::KEY_F13);
+        let mut axes = AttributeSet::<RelativeAxisType>::new();
+        axes.insert(RelativeAxisType::REL_DIAL);
+        let mut uinput = match VirtualDeviceBuilder::new()
+            .and_then(|b| b.name(&name).with_keys(&keys))
+            .and_then(|b| b.with_relative_axes(&axes))
+            .and_then(|b| b.build())
+        {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("Skipping test, can't create a device with uinput: {}", e);
+                return;
+            }
+        };
+        let path = uinput
+            .enumerate_dev_nodes_blocking()
+            .unwrap()
+            .find_map(Result::ok)
+            .unwrap();
+
+        let source = EvdevSource::new(&format!(
+            "path={},key=KEY_F13,knob=REL_DIAL",
+            path.display()
+        ))
+        .unwrap();
+        let (tx, rx) = mpsc::channel();
+        spawn_sources(vec![Box::new(source)], tx);
+        let next = || {
+            let SourceEvent { button, event, .. } =
+                rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            (button, event)
+        };
+        let mut emit = |kind, code, value| {
+            uinput
+                .emit(&[evdev::InputEvent::new(kind, code, value)])
+                .unwrap()
+        };
+
+        // Other keys are ignored.
+        emit(EventType::KEY, Key::KEY_A.code(), 1);
+        emit(EventType::KEY, Key::KEY_F13.code(), 1);
+        assert_eq!(next(), (1, InputEvent::Press));
+        emit(EventType::KEY, Key::KEY_F13.code(), 2);
+        emit(EventType::RELATIVE, RelativeAxisType::REL_DIAL.0, -2);
+        assert_eq!(next(), (1, InputEvent::Delta(-2)));
+        emit(EventType::KEY, Key::KEY_F13.code(), 0);
+        assert_eq!(next(), (1, InputEvent::Release));
+        emit(EventType::KEY, Key::KEY_A.code(), 0);
+
+        // The footswitch is released when the device goes away.
+        emit(EventType::KEY, Key::KEY_F13.code(), 1);
+        assert_eq!(next(), (1, InputEvent::Press));
+        drop(uinput);
+        assert_eq!(next(), (1, InputEvent::Release));
+    }
+}
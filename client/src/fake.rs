@@ -0,0 +1,96 @@
+/*
+ * Fake AudioControllerTrait implementation.
+ *
+ * This is only used when explicitly requested with `--audio fake`.
+ */
+
+use std::cell::Cell;
+
+use crate::audio_controller::{
+    AudioControllerTrait, AudioError, AudioInputDeviceTrait, AudioOutputDeviceTrait,
+};
+
+pub struct AudioController {}
+
+pub struct AudioInputDevice {
+    name: String,
+    mute: Cell<bool>,
+    volume: Cell<f32>,
+}
+
+pub struct AudioOutputDevice {
+    name: String,
+    volume: Cell<f32>,
+}
+
+impl AudioController {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> Box<dyn AudioControllerTrait> {
+        warn!("Using fake audio controller device!");
+        Box::new(AudioController {})
+    }
+}
+
+impl AudioControllerTrait for AudioController {
+    fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
+        Ok(Box::new(AudioInputDevice {
+            name: "Fake Microphone".to_string(),
+            mute: Cell::new(false),
+            volume: Cell::new(1.),
+        }))
+    }
+
+    fn get_default_output_device(&self) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError> {
+        Ok(Box::new(AudioOutputDevice {
+            name: "Fake Speakers".to_string(),
+            volume: Cell::new(1.),
+        }))
+    }
+
+    fn get_output_devices(&self) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, AudioError> {
+        Ok(vec![self.get_default_output_device()?])
+    }
+}
+
+impl AudioInputDeviceTrait for AudioInputDevice {
+    fn name(&self) -> Result<String, AudioError> {
+        Ok(self.name.clone())
+    }
+
+    fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
+        self.mute.set(state);
+        Ok(!state)
+    }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        Ok(self.mute.get())
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        Ok(self.volume.get())
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        self.volume.set(volume);
+        Ok(())
+    }
+}
+
+impl AudioOutputDeviceTrait for AudioOutputDevice {
+    fn id(&self) -> Result<String, AudioError> {
+        Ok(self.name.clone())
+    }
+
+    fn name(&self) -> Result<String, AudioError> {
+        Ok(self.name.clone())
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        Ok(self.volume.get())
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        self.volume.set(volume);
+        Ok(())
+    }
+}
@@ -0,0 +1,516 @@
+/*
+ * GPIO character device input source, for footswitches wired straight to a
+ * GPIO pin (eg: on a Raspberry Pi) on Linux.
+ *
+ * This uses the v2 GPIO character device ABI (Linux 5.10 and later), which
+ * supports bias and debounce settings. See `include/uapi/linux/gpio.h`.
+ */
+
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+
+use crate::input::{EventSender, InputError, InputEvent, InputSource, SourceOptions};
+
+const MISSING_DEVICE_WAIT_TIME: Duration = Duration::from_secs(1);
+const MAX_DEBOUNCE: Duration = Duration::from_secs(1);
+const CONSUMER: &[u8] = b"footswitch";
+
+const MAX_NAME_SIZE: usize = 32;
+const LINES_MAX: usize = 64;
+const LINE_NUM_ATTRS_MAX: usize = 10;
+
+const LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+const LINE_FLAG_INPUT: u64 = 1 << 2;
+const LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+const LINE_FLAG_BIAS_PULL_UP: u64 = 1 << 8;
+const LINE_FLAG_BIAS_PULL_DOWN: u64 = 1 << 9;
+const LINE_FLAG_BIAS_DISABLED: u64 = 1 << 10;
+
+const LINE_ATTR_ID_DEBOUNCE: u32 = 3;
+
+const LINE_EVENT_RISING_EDGE: u32 = 1;
+/// Size of `struct gpio_v2_line_event`.
+const LINE_EVENT_SIZE: usize = 48;
+
+/// `struct gpiochip_info`
+#[repr(C)]
+struct ChipInfo {
+    name: [u8; MAX_NAME_SIZE],
+    label: [u8; MAX_NAME_SIZE],
+    lines: u32,
+}
+
+/// The value of `struct gpio_v2_line_attribute`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+union LineAttributeValue {
+    flags: u64,
+    debounce_period_us: u32,
+}
+
+/// `struct gpio_v2_line_attribute`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: LineAttributeValue,
+}
+
+/// `struct gpio_v2_line_config_attribute`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+/// `struct gpio_v2_line_config`
+#[repr(C)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; LINE_NUM_ATTRS_MAX],
+}
+
+/// `struct gpio_v2_line_request`
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; LINES_MAX],
+    consumer: [u8; MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+/// `struct gpio_v2_line_info`
+#[repr(C)]
+struct LineInfo {
+    name: [u8; MAX_NAME_SIZE],
+    consumer: [u8; MAX_NAME_SIZE],
+    offset: u32,
+    num_attrs: u32,
+    flags: u64,
+    attrs: [LineAttribute; LINE_NUM_ATTRS_MAX],
+    padding: [u32; 4],
+}
+
+/// `struct gpio_v2_line_values`
+#[repr(C)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+mod ioctl {
+    use super::{ChipInfo, LineInfo, LineRequest, LineValues};
+
+    nix::ioctl_read!(get_chip_info, 0xB4, 0x01, ChipInfo);
+    nix::ioctl_readwrite!(get_line_info, 0xB4, 0x05, LineInfo);
+    nix::ioctl_readwrite!(get_line, 0xB4, 0x07, LineRequest);
+    nix::ioctl_readwrite!(get_line_values, 0xB4, 0x0E, LineValues);
+}
+
+/// Converts a NUL-terminated name from the kernel to a string.
+fn c_str(name: &[u8]) -> String {
+    CStr::from_bytes_until_nul(name)
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn get_chip_info(chip: &File) -> io::Result<ChipInfo> {
+    // Safety: ChipInfo is plain old data, and the kernel fills it in.
+    let mut info: ChipInfo = unsafe { mem::zeroed() };
+    unsafe { ioctl::get_chip_info(chip.as_raw_fd(), &mut info) }?;
+    Ok(info)
+}
+
+fn get_line_info(chip: &File, offset: u32) -> io::Result<LineInfo> {
+    // Safety: LineInfo is plain old data, and the kernel fills it in.
+    let mut info: LineInfo = unsafe { mem::zeroed() };
+    info.offset = offset;
+    unsafe { ioctl::get_line_info(chip.as_raw_fd(), &mut info) }?;
+    Ok(info)
+}
+
+/// Finds a GPIO chip by path (eg: `/dev/gpiochip0`), name (eg: `gpiochip0`)
+/// or label (eg: `pinctrl-bcm2711`).
+fn find_chip(chip: &str) -> Result<PathBuf, InputError> {
+    if chip.contains('/') {
+        return Ok(PathBuf::from(chip));
+    }
+    let path = Path::new("/dev").join(chip);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let entries = fs::read_dir("/dev").map_err(|e| InputError {
+        msg: format!("Failed to list GPIO chips: {}", e),
+    })?;
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("gpiochip"))
+        .map(|e| e.path())
+        .find(|p| {
+            File::open(p)
+                .and_then(|f| get_chip_info(&f))
+                .is_ok_and(|info| c_str(&info.label) == chip)
+        })
+        .ok_or_else(|| InputError {
+            msg: format!("No GPIO chip found matching {:?}", chip),
+        })
+}
+
+/// Finds a line on a GPIO chip by offset (eg: `17`) or name (eg: `GPIO17`).
+fn find_line(chip: &File, line: &str) -> Result<u32, InputError> {
+    let info = get_chip_info(chip).map_err(|e| InputError {
+        msg: format!("Failed to get GPIO chip info: {}", e),
+    })?;
+    if let Ok(offset) = line.parse::<u32>() {
+        if offset >= info.lines {
+            return Err(InputError {
+                msg: format!(
+                    "GPIO chip {} only has {} lines",
+                    c_str(&info.name),
+                    info.lines
+                ),
+            });
+        }
+        return Ok(offset);
+    }
+    (0..info.lines)
+        .find(|&offset| get_line_info(chip, offset).is_ok_and(|i| c_str(&i.name) == line))
+        .ok_or_else(|| InputError {
+            msg: format!("No GPIO line named {:?} on {}", line, c_str(&info.name)),
+        })
+}
+
+/// Requests a line for edge events, and returns its file.
+fn request_line(
+    chip: &Path,
+    offset: u32,
+    flags: u64,
+    debounce: Option<Duration>,
+) -> Result<File, InputError> {
+    let error = |e: io::Error| InputError {
+        msg: format!(
+            "Failed to request GPIO line {} on {}: {}",
+            offset,
+            chip.display(),
+            e
+        ),
+    };
+    let chip = File::open(chip).map_err(error)?;
+
+    // Safety: LineRequest is plain old data.
+    let mut req: LineRequest = unsafe { mem::zeroed() };
+    req.offsets[0] = offset;
+    req.num_lines = 1;
+    req.consumer[..CONSUMER.len()].copy_from_slice(CONSUMER);
+    req.config.flags = flags;
+    if let Some(debounce) = debounce {
+        req.config.attrs[0] = LineConfigAttribute {
+            attr: LineAttribute {
+                id: LINE_ATTR_ID_DEBOUNCE,
+                padding: 0,
+                value: LineAttributeValue {
+                    debounce_period_us: debounce.as_micros() as u32,
+                },
+            },
+            mask: 1,
+        };
+        req.config.num_attrs = 1;
+    }
+
+    if let Err(e) = unsafe { ioctl::get_line(chip.as_raw_fd(), &mut req) } {
+        if e == Errno::EBUSY {
+            if let Ok(info) = get_line_info(&chip, offset) {
+                return Err(InputError {
+                    msg: format!(
+                        "GPIO line {} on {} is being used by {:?}",
+                        offset,
+                        chip_name(&chip),
+                        c_str(&info.consumer)
+                    ),
+                });
+            }
+        }
+        return Err(error(e.into()));
+    }
+    // Safety: the kernel gave us a new file descriptor for the line.
+    Ok(unsafe { File::from_raw_fd(req.fd) })
+}
+
+fn chip_name(chip: &File) -> String {
+    get_chip_info(chip)
+        .map(|i| c_str(&i.name))
+        .unwrap_or_default()
+}
+
+/// Gets the (logical) value of a requested line.
+fn get_value(line: &File) -> io::Result<bool> {
+    let mut values = LineValues { bits: 0, mask: 1 };
+    unsafe { ioctl::get_line_values(line.as_raw_fd(), &mut values) }?;
+    Ok(values.bits & 1 != 0)
+}
+
+/// Reads edge events from a GPIO line.
+pub struct GpioSource {
+    chip: PathBuf,
+    offset: u32,
+    flags: u64,
+    debounce: Option<Duration>,
+    line: File,
+}
+
+impl GpioSource {
+    /// Creates a GPIO source from options in the form
+    /// `[chip=CHIP,]line=LINE[,active_low][,bias=BIAS][,debounce=MSEC]`.
+    pub fn new(options: &str) -> Result<Self, InputError> {
+        let options =
+            SourceOptions::parse(options, &["chip", "line", "active_low", "bias", "debounce"])?;
+        let chip = find_chip(options.get("chip").unwrap_or("gpiochip0"))?;
+        let line = options.get("line").ok_or_else(|| InputError {
+            msg: "GPIO source needs a line".to_string(),
+        })?;
+        let offset = find_line(
+            &File::open(&chip).map_err(|e| InputError {
+                msg: format!("Failed to open {}: {}", chip.display(), e),
+            })?,
+            line,
+        )?;
+
+        let mut flags = LINE_FLAG_INPUT | LINE_FLAG_EDGE_RISING | LINE_FLAG_EDGE_FALLING;
+        if options.flag("active_low") {
+            flags |= LINE_FLAG_ACTIVE_LOW;
+        }
+        flags |= match options.get("bias") {
+            None => 0,
+            Some("pull-up") => LINE_FLAG_BIAS_PULL_UP,
+            Some("pull-down") => LINE_FLAG_BIAS_PULL_DOWN,
+            Some("disabled") => LINE_FLAG_BIAS_DISABLED,
+            Some(b) => {
+                return Err(InputError {
+                    msg: format!("Unknown GPIO bias {:?}", b),
+                });
+            }
+        };
+        let debounce = match options.get("debounce") {
+            None => None,
+            Some(d) => Some(
+                d.parse()
+                    .ok()
+                    .map(Duration::from_millis)
+                    .filter(|d| *d <= MAX_DEBOUNCE)
+                    .ok_or_else(|| InputError {
+                        msg: format!(
+                            "GPIO debounce must be a number of milliseconds, up to {}",
+                            MAX_DEBOUNCE.as_millis()
+                        ),
+                    })?,
+            ),
+        };
+
+        let line = request_line(&chip, offset, flags, debounce)?;
+        Ok(GpioSource {
+            chip,
+            offset,
+            flags,
+            debounce,
+            line,
+        })
+    }
+}
+
+impl InputSource for GpioSource {
+    fn name(&self) -> String {
+        format!("GPIO line: {} on {}", self.offset, self.chip.display())
+    }
+
+    /// Sends edge events from the GPIO line to the channel.
+    ///
+    /// The footswitch is pressed while the line is active.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let GpioSource {
+            chip,
+            offset,
+            flags,
+            debounce,
+            mut line,
+        } = *self;
+        let mut buf = [0; LINE_EVENT_SIZE * 16];
+
+        loop {
+            // Edge events only tell us about changes, so start with the
+            // line's current state.
+            if get_value(&line).unwrap_or(false) && !chan.send(InputEvent::Press) {
+                return;
+            }
+
+            loop {
+                let len = match line.read(&mut buf) {
+                    Ok(len) if len > 0 => len,
+                    Ok(_) => break,
+                    Err(error) => {
+                        warn!("Error reading GPIO line {}: {}", offset, error);
+                        break;
+                    }
+                };
+                for event in buf[..len].chunks_exact(LINE_EVENT_SIZE) {
+                    let id = u32::from_ne_bytes(event[8..12].try_into().unwrap());
+                    if !chan.send(if id == LINE_EVENT_RISING_EDGE {
+                        InputEvent::Press
+                    } else {
+                        InputEvent::Release
+                    }) {
+                        // Other end of the channel has probably gone away.
+                        // Shut down the thread.
+                        return;
+                    }
+                }
+            }
+
+            // Don't leave the footswitch held while the line is missing.
+            if !chan.release() {
+                return;
+            }
+
+            // Something went wrong - request the line again.
+            line = loop {
+                thread::sleep(MISSING_DEVICE_WAIT_TIME);
+                if let Ok(l) = request_line(&chip, offset, flags, debounce) {
+                    warn!("Reconnecting GPIO line {} on {}", offset, chip.display());
+                    break l;
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{spawn_sources, SourceEvent};
+    use std::sync::mpsc;
+
+    /// Sizes of the structs in `include/uapi/linux/gpio.h`.
+    #[test]
+    fn abi() {
+        assert_eq!(mem::size_of::<ChipInfo>(), 68);
+        assert_eq!(mem::size_of::<LineAttribute>(), 16);
+        assert_eq!(mem::size_of::<LineConfigAttribute>(), 24);
+        assert_eq!(mem::size_of::<LineConfig>(), 272);
+        assert_eq!(mem::size_of::<LineRequest>(), 592);
+        assert_eq!(mem::size_of::<LineInfo>(), 256);
+        assert_eq!(mem::size_of::<LineValues>(), 16);
+    }
+
+    #[test]
+    fn options() {
+        assert!(GpioSource::new("chip=/nonexistent,line=1").is_err());
+        assert!(GpioSource::new("line=1,bias=sideways").is_err());
+    }
+
+    const GPIO_SIM: &str = "/sys/kernel/config/gpio-sim";
+
+    /// A simulated GPIO chip, which goes away when dropped.
+    struct SimChip {
+        config: PathBuf,
+        /// The chip's directory in sysfs, eg:
+        /// `/sys/devices/platform/gpio-sim.0/gpiochip1`.
+        sysfs: PathBuf,
+        chip: String,
+    }
+
+    impl SimChip {
+        fn create(name: &str) -> io::Result<SimChip> {
+            let config = Path::new(GPIO_SIM).join(name);
+            fs::create_dir(&config)?;
+            let mut sim = SimChip {
+                config,
+                sysfs: PathBuf::new(),
+                chip: String::new(),
+            };
+            let bank = sim.config.join("bank0");
+            fs::create_dir(&bank)?;
+            fs::write(bank.join("num_lines"), "4")?;
+            fs::create_dir(bank.join("line2"))?;
+            fs::write(bank.join("line2/name"), "FOOTSWITCH")?;
+            fs::write(sim.config.join("live"), "1")?;
+
+            sim.chip = fs::read_to_string(bank.join("chip_name"))?
+                .trim()
+                .to_string();
+            let device = fs::read_to_string(sim.config.join("dev_name"))?;
+            sim.sysfs = Path::new("/sys/devices/platform")
+                .join(device.trim())
+                .join(&sim.chip);
+            Ok(sim)
+        }
+
+        fn pull(&self, offset: u32, up: bool) {
+            fs::write(
+                self.sysfs.join(format!("sim_gpio{}/pull", offset)),
+                if up { "pull-up" } else { "pull-down" },
+            )
+            .unwrap();
+        }
+    }
+
+    impl Drop for SimChip {
+        fn drop(&mut self) {
+            let _ = fs::write(self.config.join("live"), "0");
+            let _ = fs::remove_dir(self.config.join("bank0/line2"));
+            let _ = fs::remove_dir(self.config.join("bank0"));
+            let _ = fs::remove_dir(&self.config);
+        }
+    }
+
+    #[test]
+    fn gpio_sim() {
+        let sim = match SimChip::create(&format!("footswitch-test-{}", std::process::id())) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Skipping test, can't create a gpio-sim chip: {}", e);
+                return;
+            }
+        };
+
+        // Lines can be found by name, and an active line is pressed at start.
+        sim.pull(2, true);
+        let source = GpioSource::new(&format!("chip={},line=FOOTSWITCH", sim.chip)).unwrap();
+        assert_eq!(source.offset, 2);
+        assert!(GpioSource::new(&format!("chip={},line=4", sim.chip)).is_err());
+        let (tx, rx) = mpsc::channel();
+        spawn_sources(vec![Box::new(source)], tx);
+        let next = || {
+            let SourceEvent { button, event, .. } =
+                rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            (button, event)
+        };
+        assert_eq!(next(), (1, InputEvent::Press));
+
+        sim.pull(2, false);
+        assert_eq!(next(), (1, InputEvent::Release));
+
+        // Other lines are ignored.
+        sim.pull(1, true);
+        sim.pull(2, true);
+        assert_eq!(next(), (1, InputEvent::Press));
+        sim.pull(2, false);
+        assert_eq!(next(), (1, InputEvent::Release));
+    }
+}
@@ -0,0 +1,523 @@
+/*
+ * hidraw input source, for USB HID devices which send vendor-specific
+ * reports (eg: transcription pedals) on Linux.
+ */
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::evdev_source::parse_id;
+use crate::input::{EventSender, InputError, InputEvent, InputSource, SourceOptions};
+
+const MISSING_DEVICE_WAIT_TIME: Duration = Duration::from_secs(1);
+const SYSFS_HIDRAW: &str = "/sys/class/hidraw";
+/// Largest report we'll read.
+const MAX_REPORT_SIZE: usize = 64;
+/// Offset of the first key code in a keyboard report. Key codes are in
+/// bytes 2 to 7 without a report ID, or 3 to 8 with one (in which case byte
+/// 2 is always zero).
+const FIRST_KEY_BYTE: usize = 2;
+
+/// Where a button is in an input report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Position {
+    /// A bit, where byte 0 is the first byte of the report (including any
+    /// report ID), and bit 0 is the least significant bit.
+    Bit(usize, u8),
+    /// A USB HID key code in a keyboard report.
+    Key(u8),
+}
+
+impl Position {
+    fn is_pressed(self, report: &[u8]) -> bool {
+        match self {
+            Position::Bit(byte, bit) => report.get(byte).is_some_and(|b| b & (1 << bit) != 0),
+            Position::Key(key) => report.iter().skip(FIRST_KEY_BYTE).any(|k| *k == key),
+        }
+    }
+}
+
+/// A button in an input report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ButtonMapping {
+    button: u8,
+    position: Position,
+}
+
+/// Well-known devices, and the buttons in their input reports.
+struct Preset {
+    name: &'static str,
+    /// Other names the preset can be given as.
+    aliases: &'static [&'static str],
+    vendor: u16,
+    product: u16,
+    buttons: &'static [(u8, Position)],
+}
+
+/// Buttons of VEC footswitches.
+const VEC_BUTTONS: &[(u8, Position)] = &[
+    (1, Position::Bit(0, 0)),
+    (2, Position::Bit(0, 1)),
+    (3, Position::Bit(0, 2)),
+];
+
+/// Buttons of PCsensor footswitches, with their pedals set to F13, F14 and
+/// F15.
+const PCSENSOR_BUTTONS: &[(u8, Position)] = &[
+    (1, Position::Key(0x68)),
+    (2, Position::Key(0x69)),
+    (3, Position::Key(0x6a)),
+];
+
+const PRESETS: &[Preset] = &[
+    // Infinity IN-USB-1, IN-USB-2 and IN-USB-3 three-pedal transcription
+    // footswitches (left, middle, right), made by VEC.
+    Preset {
+        name: "infinity",
+        aliases: &["vec"],
+        vendor: 0x05f3,
+        product: 0x00ff,
+        buttons: VEC_BUTTONS,
+    },
+    // PCsensor single and triple "FootSwitch" pedals.
+    Preset {
+        name: "pcsensor",
+        aliases: &[],
+        vendor: 0x0c45,
+        product: 0x7403,
+        buttons: PCSENSOR_BUTTONS,
+    },
+    // Newer PCsensor triple pedals (FootSwitch3-F1.8), which add a report ID.
+    Preset {
+        name: "pcsensor3",
+        aliases: &[],
+        vendor: 0x1a86,
+        product: 0xe026,
+        buttons: PCSENSOR_BUTTONS,
+    },
+];
+
+/// Finds a preset by its name or one of its aliases.
+fn find_preset(name: &str) -> Option<&'static Preset> {
+    PRESETS
+        .iter()
+        .find(|p| p.name == name || p.aliases.contains(&name))
+}
+
+/// Parses a button mapping, in the form `BUTTON:BYTE.BIT` or
+/// `BUTTON:key=KEY`.
+fn parse_mapping(mapping: &str) -> Option<ButtonMapping> {
+    let (button, position) = mapping.split_once(':')?;
+    let position = match position.strip_prefix("key=") {
+        Some(key) => Position::Key(parse_byte(key)?),
+        None => {
+            let (byte, bit) = position.split_once('.')?;
+            Position::Bit(
+                byte.parse().ok().filter(|b| *b < MAX_REPORT_SIZE)?,
+                bit.parse().ok().filter(|b| *b < 8)?,
+            )
+        }
+    };
+    Some(ButtonMapping {
+        button: button.parse().ok()?,
+        position,
+    })
+}
+
+/// Parses a decimal or `0x` hexadecimal byte.
+fn parse_byte(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// How to find a hidraw device.
+#[derive(Debug)]
+enum DeviceMatcher {
+    /// A device node, eg: `/dev/hidraw0`.
+    Path(PathBuf),
+    /// The device's name, eg: `VEC VEC USB Footpedal`.
+    Name(String),
+    /// The device's USB vendor and product ID.
+    Id(u16, u16),
+}
+
+/// A hidraw device's properties, from sysfs.
+struct DeviceInfo {
+    name: String,
+    vendor: u16,
+    product: u16,
+}
+
+impl DeviceInfo {
+    /// Reads the properties of a hidraw device (eg: `hidraw0`).
+    fn read(device: &str) -> Option<DeviceInfo> {
+        let uevent =
+            fs::read_to_string(Path::new(SYSFS_HIDRAW).join(device).join("device/uevent")).ok()?;
+        let mut name = String::new();
+        let mut id = None;
+        for line in uevent.lines() {
+            if let Some(n) = line.strip_prefix("HID_NAME=") {
+                name = n.to_string();
+            } else if let Some(i) = line.strip_prefix("HID_ID=") {
+                // BUS:VENDOR:PRODUCT, with 32-bit vendor and product IDs.
+                let mut parts = i.split(':').skip(1);
+                id = Some((
+                    u32::from_str_radix(parts.next()?, 16).ok()? as u16,
+                    u32::from_str_radix(parts.next()?, 16).ok()? as u16,
+                ));
+            }
+        }
+        let (vendor, product) = id?;
+        Some(DeviceInfo {
+            name,
+            vendor,
+            product,
+        })
+    }
+}
+
+impl DeviceMatcher {
+    fn matches(&self, path: &Path, info: &DeviceInfo) -> bool {
+        match self {
+            DeviceMatcher::Path(p) => p == path,
+            DeviceMatcher::Name(n) => info.name == *n,
+            DeviceMatcher::Id(vendor, product) => {
+                info.vendor == *vendor && info.product == *product
+            }
+        }
+    }
+}
+
+/// Finds and opens a hidraw device.
+fn open_device(matcher: &DeviceMatcher) -> Result<(PathBuf, File), InputError> {
+    let path = match matcher {
+        DeviceMatcher::Path(p) => p.clone(),
+        _ => fs::read_dir(SYSFS_HIDRAW)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let device = e.file_name().to_string_lossy().into_owned();
+                let path = Path::new("/dev").join(&device);
+                DeviceInfo::read(&device)
+                    .filter(|info| matcher.matches(&path, info))
+                    .map(|_| path)
+            })
+            .min()
+            .ok_or_else(|| InputError {
+                msg: format!("No hidraw device found matching {:?}", matcher),
+            })?,
+    };
+    let device = File::open(&path).map_err(|e| InputError {
+        msg: format!("Failed to open {}: {}", path.display(), e),
+    })?;
+    Ok((path, device))
+}
+
+/// Reads buttons from a hidraw device's input reports.
+pub struct HidrawSource {
+    matcher: DeviceMatcher,
+    buttons: Vec<ButtonMapping>,
+    path: PathBuf,
+    device: File,
+}
+
+impl HidrawSource {
+    /// Creates a hidraw source from options in the form
+    /// `preset=PRESET|path=DEVICE|name=NAME|id=VVVV:PPPP[,button=BUTTON:BYTE.BIT|BUTTON:key=KEY...]`.
+    pub fn new(options: &str) -> Result<Self, InputError> {
+        let options = SourceOptions::parse(options, &["preset", "path", "name", "id", "button"])?;
+        let preset = match options.get("preset") {
+            Some(p) => Some(find_preset(p).ok_or_else(|| InputError {
+                msg: format!(
+                    "Unknown hidraw preset {:?} (available: {})",
+                    p,
+                    PRESETS
+                        .iter()
+                        .map(|x| x.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })?),
+            None => None,
+        };
+
+        let matcher = if let Some(path) = options.get("path") {
+            DeviceMatcher::Path(PathBuf::from(path))
+        } else if let Some(name) = options.get("name") {
+            DeviceMatcher::Name(name.to_string())
+        } else if let Some(id) = options.get("id") {
+            let (vendor, product) = parse_id(id).ok_or_else(|| InputError {
+                msg: format!("Invalid USB ID {:?}, expected VVVV:PPPP", id),
+            })?;
+            DeviceMatcher::Id(vendor, product)
+        } else if let Some(preset) = preset {
+            DeviceMatcher::Id(preset.vendor, preset.product)
+        } else {
+            return Err(InputError {
+                msg: "hidraw source needs a preset, path, name or id".to_string(),
+            });
+        };
+
+        let mut buttons = options
+            .get_all("button")
+            .map(|b| {
+                parse_mapping(b).ok_or_else(|| InputError {
+                    msg: format!(
+                        "Invalid button mapping {:?}, expected BUTTON:BYTE.BIT or BUTTON:key=KEY",
+                        b
+                    ),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if buttons.is_empty() {
+            buttons = preset
+                .ok_or_else(|| InputError {
+                    msg: "hidraw source needs a preset or button mappings".to_string(),
+                })?
+                .buttons
+                .iter()
+                .map(|&(button, position)| ButtonMapping { button, position })
+                .collect();
+        }
+
+        let (path, device) = open_device(&matcher)?;
+        Ok(HidrawSource {
+            matcher,
+            buttons,
+            path,
+            device,
+        })
+    }
+}
+
+impl InputSource for HidrawSource {
+    fn name(&self) -> String {
+        format!("HID device: {}", self.path.display())
+    }
+
+    /// Sends button events from the device's input reports to the channel.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let HidrawSource {
+            matcher,
+            buttons,
+            mut path,
+            mut device,
+        } = *self;
+        let mut report = [0; MAX_REPORT_SIZE];
+        let mut state = vec![false; buttons.len()];
+
+        loop {
+            let error = match device.read(&mut report) {
+                Ok(len) if len > 0 => {
+                    for (m, pressed) in buttons.iter().zip(state.iter_mut()) {
+                        let p = m.position.is_pressed(&report[..len]);
+                        if p == *pressed {
+                            continue;
+                        }
+                        *pressed = p;
+                        if !chan.send_button(
+                            m.button,
+                            if p {
+                                InputEvent::Press
+                            } else {
+                                InputEvent::Release
+                            },
+                        ) {
+                            // Other end of the channel has probably gone away.
+                            // Shut down the thread.
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                Ok(_) => "end of file".to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!("Error reading {}: {}", path.display(), error);
+
+            // Don't leave any buttons held while the device is missing.
+            state.iter_mut().for_each(|p| *p = false);
+            if !chan.release() {
+                return;
+            }
+
+            // Something went wrong - wait for the device to come back.
+            (path, device) = loop {
+                thread::sleep(MISSING_DEVICE_WAIT_TIME);
+                if let Ok(d) = open_device(&matcher) {
+                    warn!("Reconnecting device {}", d.0.display());
+                    break d;
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{spawn_sources, SourceEvent};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    #[test]
+    fn mappings() {
+        assert_eq!(
+            parse_mapping("2:1.7"),
+            Some(ButtonMapping {
+                button: 2,
+                position: Position::Bit(1, 7)
+            })
+        );
+        assert_eq!(
+            parse_mapping("1:key=0x68"),
+            Some(ButtonMapping {
+                button: 1,
+                position: Position::Key(0x68)
+            })
+        );
+        assert_eq!(
+            parse_mapping("1:key=104").unwrap().position,
+            Position::Key(0x68)
+        );
+        assert_eq!(parse_mapping("1:0.8"), None);
+        assert_eq!(parse_mapping("1:64.0"), None);
+        assert_eq!(parse_mapping("1:key=0x100"), None);
+        assert_eq!(parse_mapping("1:0"), None);
+
+        assert!(Position::Bit(0, 2).is_pressed(&[0b100]));
+        assert!(!Position::Bit(1, 0).is_pressed(&[0xff]));
+        // Without and with a report ID.
+        assert!(Position::Key(0x69).is_pressed(&[0, 0, 0x68, 0x69, 0, 0, 0, 0]));
+        assert!(Position::Key(0x68).is_pressed(&[1, 0, 0, 0x68, 0, 0, 0, 0, 0]));
+        // The modifier byte isn't a key.
+        assert!(!Position::Key(0x02).is_pressed(&[0x02, 0, 0, 0, 0, 0, 0, 0]));
+
+        assert_eq!(find_preset("vec").unwrap().name, "infinity");
+        assert_eq!(find_preset("pcsensor3").unwrap().product, 0xe026);
+        assert!(find_preset("bogus").is_none());
+    }
+
+    const UHID_DESTROY: u32 = 1;
+    const UHID_CREATE2: u32 = 11;
+    const UHID_INPUT2: u32 = 12;
+    /// Size of `struct uhid_event`.
+    const UHID_EVENT_SIZE: usize = 4 + 4372;
+
+    /// Vendor-defined 8-byte input report, without a report ID.
+    const REPORT_DESCRIPTOR: &[u8] = &[
+        0x06, 0x00, 0xff, // Usage page (vendor defined)
+        0x09, 0x01, // Usage (1)
+        0xa1, 0x01, // Collection (application)
+        0x15, 0x00, // Logical minimum (0)
+        0x26, 0xff, 0x00, // Logical maximum (255)
+        0x75, 0x08, // Report size (8)
+        0x95, 0x08, // Report count (8)
+        0x09, 0x01, // Usage (1)
+        0x81, 0x02, // Input (data, variable, absolute)
+        0xc0, // End collection
+    ];
+
+    /// A virtual HID device, which goes away when dropped.
+    struct UhidDevice(File);
+
+    impl UhidDevice {
+        fn create(name: &str) -> Option<UhidDevice> {
+            let mut uhid = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/uhid")
+                .ok()?;
+            let mut event = vec![0; UHID_EVENT_SIZE];
+            event[..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+            event[4..4 + name.len()].copy_from_slice(name.as_bytes());
+            // After name[128], phys[64] and uniq[64].
+            let mut offset = 4 + 256;
+            for field in &[
+                &(REPORT_DESCRIPTOR.len() as u16).to_ne_bytes()[..],
+                &0x03u16.to_ne_bytes(), // BUS_USB
+                &0x05f3u32.to_ne_bytes(),
+                &0x00ffu32.to_ne_bytes(),
+                &0u32.to_ne_bytes(), // version
+                &0u32.to_ne_bytes(), // country
+                REPORT_DESCRIPTOR,
+            ] {
+                event[offset..offset + field.len()].copy_from_slice(field);
+                offset += field.len();
+            }
+            uhid.write_all(&event).ok()?;
+            Some(UhidDevice(uhid))
+        }
+
+        fn send(&mut self, report: &[u8]) {
+            let mut event = vec![0; UHID_EVENT_SIZE];
+            event[..4].copy_from_slice(&UHID_INPUT2.to_ne_bytes());
+            event[4..6].copy_from_slice(&(report.len() as u16).to_ne_bytes());
+            event[6..6 + report.len()].copy_from_slice(report);
+            self.0.write_all(&event).unwrap();
+        }
+    }
+
+    impl Drop for UhidDevice {
+        fn drop(&mut self) {
+            let mut event = vec![0; UHID_EVENT_SIZE];
+            event[..4].copy_from_slice(&UHID_DESTROY.to_ne_bytes());
+            let _ = self.0.write_all(&event);
+        }
+    }
+
+    #[test]
+    fn uhid_device() {
+        let name = format!("footswitch test {}", std::process::id());
+        let mut uhid = match UhidDevice::create(&name) {
+            Some(u) => u,
+            None => {
+                eprintln!("Skipping test, can't create a device with /dev/uhid");
+                return;
+            }
+        };
+
+        // Wait for the hidraw device to show up.
+        let options = format!("name={},button=1:0.0,button=2:0.2,button=3:key=0x68", name);
+        let started = Instant::now();
+        let source = loop {
+            match HidrawSource::new(&options) {
+                Ok(s) => break s,
+                Err(e) if started.elapsed() > Duration::from_secs(5) => panic!("{}", e.msg),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        let (tx, rx) = mpsc::channel();
+        spawn_sources(vec![Box::new(source)], tx);
+        let next = || {
+            let SourceEvent { button, event, .. } =
+                rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            (button, event)
+        };
+
+        uhid.send(&[0b001, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(next(), (1, InputEvent::Press));
+        uhid.send(&[0b101, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(next(), (2, InputEvent::Press));
+        uhid.send(&[0b100, 0, 0x68, 0, 0, 0, 0, 0]);
+        assert_eq!(next(), (1, InputEvent::Release));
+        assert_eq!(next(), (3, InputEvent::Press));
+
+        // Buttons are released when the device goes away.
+        drop(uhid);
+        let mut released = vec![next(), next()];
+        released.sort_by_key(|(b, _)| *b);
+        assert_eq!(
+            released,
+            vec![(2, InputEvent::Release), (3, InputEvent::Release)]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+#[cfg(target_os = "linux")]
+use crate::evdev_source::EvdevSource;
+#[cfg(target_os = "linux")]
+use crate::gpio::GpioSource;
+#[cfg(target_os = "linux")]
+use crate::hidraw::HidrawSource;
+use crate::lines::LineSource;
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+use crate::midi::MidiSeqSource;
+use crate::midi::MidiSerialSource;
+use crate::net::NetSource;
+use crate::osc::OscSource;
+use crate::serial::SerialSource;
+
+/// An event from an input source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    /// The footswitch was pressed.
+    Press,
+
+    /// The footswitch was released.
+    Release,
+
+    /// An analog pedal moved, from 0.0 (released) to 1.0 (fully pressed).
+    Axis(f32),
+
+    /// A knob (rotary encoder) turned by this many steps, where positive is
+    /// clockwise.
+    Delta(i32),
+
+    /// Something (eg: a home automation system) started or stopped forcing
+    /// the microphone to stay muted, whatever the footswitch is doing.
+    ForceMute(bool),
+
+    /// Switches how buttons hold the footswitch.
+    Mode(Mode),
+}
+
+/// How buttons hold the footswitch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// The footswitch is held while any button is pressed.
+    Hold,
+    /// Each press toggles the footswitch.
+    Latch,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "hold" => Ok(Mode::Hold),
+            "latch" => Ok(Mode::Latch),
+            _ => Err(format!("Mode must be hold or latch: {:?}", s)),
+        }
+    }
+}
+
+/// The button used by sources which only have one.
+pub const DEFAULT_BUTTON: u8 = 1;
+
+/// An `InputEvent`, tagged with the index of the source it came from, and
+/// the button on that source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SourceEvent {
+    pub source: usize,
+    pub button: u8,
+    pub event: InputEvent,
+}
+
+#[derive(Debug)]
+pub struct InputError {
+    pub msg: String,
+}
+
+/// Options for an input source, in the form `key=value,flag,...`.
+pub struct SourceOptions<'a> {
+    options: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> SourceOptions<'a> {
+    /// Parses `options`, returning an error if it has any keys that aren't
+    /// in `allowed`.
+    pub fn parse(options: &'a str, allowed: &[&str]) -> Result<Self, InputError> {
+        let options: Vec<_> = options
+            .split(',')
+            .filter(|o| !o.is_empty())
+            .map(|o| match o.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (o, None),
+            })
+            .collect();
+        if let Some((k, _)) = options.iter().find(|(k, _)| !allowed.contains(k)) {
+            return Err(InputError {
+                msg: format!("Unknown input source option {:?}", k),
+            });
+        }
+        Ok(SourceOptions { options })
+    }
+
+    /// Gets the value of a `key=value` option.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.options
+            .iter()
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, v)| *v)
+    }
+
+    /// Gets all values of a `key=value` option which may be repeated.
+    pub fn get_all(&self, key: &'a str) -> impl Iterator<Item = &'a str> + '_ {
+        self.options
+            .iter()
+            .filter(move |(k, _)| *k == key)
+            .filter_map(|(_, v)| *v)
+    }
+
+    /// Returns `true` if a flag is set.
+    pub fn flag(&self, key: &str) -> bool {
+        self.options.iter().any(|(k, v)| *k == key && v.is_none())
+    }
+}
+
+/// Turns an analog value into press and release events.
+///
+/// The release threshold is lower than the press threshold, so that a pedal
+/// resting near one of them doesn't flicker between pressed and released.
+#[derive(Clone, Debug)]
+pub struct Threshold {
+    press: f32,
+    release: f32,
+    pressed: bool,
+}
+
+impl Threshold {
+    const DEFAULT_PRESS: f32 = 0.6;
+    const DEFAULT_RELEASE: f32 = 0.4;
+
+    /// Creates a threshold from `press=VALUE` and `release=VALUE` options.
+    pub fn from_options(options: &SourceOptions) -> Result<Self, InputError> {
+        let get = |key, default| match options.get(key) {
+            None => Ok(default),
+            Some(v) => v
+                .parse::<f32>()
+                .ok()
+                .filter(|v| (0. ..=1.).contains(v))
+                .ok_or_else(|| InputError {
+                    msg: format!("{} threshold must be between 0.0 and 1.0", key),
+                }),
+        };
+        let press = get("press", Self::DEFAULT_PRESS)?;
+        let release = get("release", Self::DEFAULT_RELEASE.min(press))?;
+        if release > press {
+            return Err(InputError {
+                msg: "release threshold must not be higher than the press threshold".to_string(),
+            });
+        }
+        Ok(Threshold {
+            press,
+            release,
+            pressed: false,
+        })
+    }
+
+    /// Forgets whether the value was past the press threshold.
+    pub fn reset(&mut self) {
+        self.pressed = false;
+    }
+
+    /// Updates the threshold with a new value, and returns the event to send
+    /// if the state changed.
+    pub fn update(&mut self, value: f32) -> Option<InputEvent> {
+        if !self.pressed && value >= self.press {
+            self.pressed = true;
+            Some(InputEvent::Press)
+        } else if self.pressed && value <= self.release {
+            self.pressed = false;
+            Some(InputEvent::Release)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sends events from an input source to the `MicController`.
+///
+/// If the source goes away while any buttons are pressed, release events are
+/// sent.
+pub struct EventSender {
+    source: usize,
+    chan: mpsc::Sender<SourceEvent>,
+    pressed: HashSet<u8>,
+}
+
+impl EventSender {
+    /// Sends an event for `DEFAULT_BUTTON`. Returns `false` if the other end
+    /// of the channel has gone away, and the source should shut down.
+    pub fn send(&mut self, event: InputEvent) -> bool {
+        self.send_button(DEFAULT_BUTTON, event)
+    }
+
+    /// Sends an event for a button. Returns `false` if the other end of the
+    /// channel has gone away, and the source should shut down.
+    pub fn send_button(&mut self, button: u8, event: InputEvent) -> bool {
+        match event {
+            InputEvent::Press => self.pressed.insert(button),
+            InputEvent::Release => self.pressed.remove(&button),
+            InputEvent::Axis(_)
+            | InputEvent::Delta(_)
+            | InputEvent::ForceMute(_)
+            | InputEvent::Mode(_) => false,
+        };
+        self.chan
+            .send(SourceEvent {
+                source: self.source,
+                button,
+                event,
+            })
+            .is_ok()
+    }
+
+    /// Releases any buttons this source has pressed. Returns `false` if the
+    /// other end of the channel has gone away.
+    pub fn release(&mut self) -> bool {
+        let pressed: Vec<u8> = self.pressed.iter().copied().collect();
+        pressed
+            .into_iter()
+            .all(|b| self.send_button(b, InputEvent::Release))
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Trait that describes something which produces footswitch events.
+pub trait InputSource: Send {
+    /// A human-readable description of the source.
+    fn name(&self) -> String;
+
+    /// Sends events to `chan`, until the other end of the channel goes away.
+    fn run(self: Box<Self>, chan: EventSender);
+}
+
+/// Creates an input source from a command-line specification, in the form
+/// `type:options`.
+pub fn parse_source(spec: &str) -> Result<Box<dyn InputSource>, InputError> {
+    let (kind, options) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind {
+        "serial" => Ok(Box::new(SerialSource::new(options)?)),
+        "udp" => Ok(Box::new(NetSource::new(false, options)?)),
+        "tcp" => Ok(Box::new(NetSource::new(true, options)?)),
+        "midi" => Ok(Box::new(MidiSerialSource::new(options)?)),
+        "mdns" => Ok(Box::new(NetSource::with_mdns(options)?)),
+        "osc" => Ok(Box::new(OscSource::new(options)?)),
+        "stdin" => Ok(Box::new(LineSource::stdin())),
+        "file" | "fifo" => Ok(Box::new(LineSource::file(options)?)),
+        #[cfg(target_os = "linux")]
+        "evdev" => Ok(Box::new(EvdevSource::new(options)?)),
+        #[cfg(target_os = "linux")]
+        "gpio" => Ok(Box::new(GpioSource::new(options)?)),
+        #[cfg(target_os = "linux")]
+        "hidraw" => Ok(Box::new(HidrawSource::new(options)?)),
+        #[cfg(all(target_os = "linux", feature = "alsa"))]
+        "alsa_midi" => Ok(Box::new(MidiSeqSource::new(options)?)),
+        _ => Err(InputError {
+            msg: format!("Unknown input source type {:?}", kind),
+        }),
+    }
+}
+
+/// Runs input sources on their own threads, sending events to `chan`.
+pub fn spawn_sources(
+    sources: Vec<Box<dyn InputSource>>,
+    chan: mpsc::Sender<SourceEvent>,
+) -> Vec<thread::JoinHandle<()>> {
+    sources
+        .into_iter()
+        .enumerate()
+        .map(|(source, s)| {
+            let sender = EventSender {
+                source,
+                chan: chan.clone(),
+                pressed: HashSet::new(),
+            };
+            thread::spawn(move || s.run(sender))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threshold(options: &str) -> Result<Threshold, InputError> {
+        Threshold::from_options(&SourceOptions::parse(options, &["press", "release"])?)
+    }
+
+    #[test]
+    fn thresholds() {
+        let mut t = threshold("").unwrap();
+        assert_eq!(t.update(0.5), None);
+        assert_eq!(t.update(0.6), Some(InputEvent::Press));
+        assert_eq!(t.update(1.0), None);
+        // Between the thresholds, the pedal stays pressed.
+        assert_eq!(t.update(0.5), None);
+        assert_eq!(t.update(0.4), Some(InputEvent::Release));
+        assert_eq!(t.update(0.5), None);
+
+        t.update(0.9);
+        t.reset();
+        assert_eq!(t.update(0.9), Some(InputEvent::Press));
+
+        // The release threshold follows a low press threshold.
+        let mut t = threshold("press=0.3").unwrap();
+        assert_eq!(t.update(0.3), Some(InputEvent::Press));
+        assert_eq!(t.update(0.35), None);
+        assert_eq!(t.update(0.3), Some(InputEvent::Release));
+
+        let mut t = threshold("press=0.8,release=0.1").unwrap();
+        assert_eq!(t.update(0.8), Some(InputEvent::Press));
+        assert_eq!(t.update(0.2), None);
+        assert_eq!(t.update(0.1), Some(InputEvent::Release));
+
+        assert!(threshold("press=1.5").is_err());
+        assert!(threshold("release=x").is_err());
+        assert!(threshold("press=0.3,release=0.5").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ * Local IPC connections to other apps, over Unix sockets or Windows named
+ * pipes.
+ */
+
+#[cfg(windows)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+#[cfg(windows)]
+use std::thread;
+use std::time::Duration;
+#[cfg(windows)]
+use std::time::Instant;
+
+/// How often to check a named pipe for data, while waiting to read.
+#[cfg(windows)]
+const PIPE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A connection to an app's Unix socket or named pipe.
+pub trait Stream: Read + Write + Send {
+    /// Sets how long reads wait before failing.
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+/// A named pipe, opened for synchronous I/O.
+///
+/// Synchronous reads from a pipe can't time out, and a reader thread would
+/// block writes to the same handle, so reads wait for data to arrive with
+/// `PeekNamedPipe` first.
+#[cfg(windows)]
+pub struct Pipe {
+    file: fs::File,
+    timeout: Option<Duration>,
+}
+
+#[cfg(windows)]
+impl Pipe {
+    /// Waits until there's data to read, or the timeout passes.
+    fn wait(&self, timeout: Duration) -> io::Result<()> {
+        use std::os::windows::io::AsRawHandle;
+        use std::ptr::null_mut;
+        use winapi::um::namedpipeapi::PeekNamedPipe;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut available = 0;
+            let ok = unsafe {
+                PeekNamedPipe(
+                    self.file.as_raw_handle() as _,
+                    null_mut(),
+                    0,
+                    null_mut(),
+                    &mut available,
+                    null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if available > 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(PIPE_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(timeout) = self.timeout {
+            self.wait(timeout)?;
+        }
+        self.file.read(buf)
+    }
+}
+
+#[cfg(windows)]
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(windows)]
+impl Stream for Pipe {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+/// Connects to a Unix socket, or opens a named pipe (eg:
+/// `\\.\pipe\discord-ipc-0`) on Windows.
+pub fn connect(path: &Path) -> io::Result<Box<dyn Stream>> {
+    #[cfg(unix)]
+    return Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?));
+    #[cfg(windows)]
+    return Ok(Box::new(Pipe {
+        file: fs::OpenOptions::new().read(true).write(true).open(path)?,
+        timeout: None,
+    }));
+    #[cfg(not(any(unix, windows)))]
+    return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Can't open {}", path.display()),
+    ));
+}
@@ -0,0 +1,213 @@
+/*
+ * Line-based input sources, for driving the client from scripts and other
+ * programs through stdin or a named pipe (FIFO).
+ */
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+
+use crate::input::{EventSender, InputError, InputEvent, InputSource, DEFAULT_BUTTON};
+
+/// Parses a command, in the form `press [BUTTON]`, `release [BUTTON]`,
+/// `axis VALUE`, `delta STEPS`, `force_mute on|off` or `mode hold|latch`.
+///
+/// Returns `None` for blank lines and comments (starting with `#`).
+pub fn parse_line(line: &str) -> Result<Option<(u8, InputEvent)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default().to_lowercase();
+    let arg = words.next();
+    if words.next().is_some() {
+        return Err(format!("Too many arguments: {:?}", line));
+    }
+    let button = || match arg {
+        None => Ok(DEFAULT_BUTTON),
+        Some(b) => b.parse().map_err(|_| format!("Invalid button: {:?}", b)),
+    };
+    Ok(Some(match command.as_str() {
+        "press" => (button()?, InputEvent::Press),
+        "release" => (button()?, InputEvent::Release),
+        "axis" => {
+            let value = arg
+                .and_then(|v| v.parse::<f32>().ok())
+                .filter(|v| (0. ..=1.).contains(v))
+                .ok_or_else(|| format!("Axis value must be between 0.0 and 1.0: {:?}", line))?;
+            (DEFAULT_BUTTON, InputEvent::Axis(value))
+        }
+        "delta" => {
+            let steps = arg
+                .and_then(|v| v.parse::<i32>().ok())
+                .ok_or_else(|| format!("Delta must be a whole number of steps: {:?}", line))?;
+            (DEFAULT_BUTTON, InputEvent::Delta(steps))
+        }
+        "force_mute" => {
+            let muted = match arg.map(str::to_lowercase).as_deref() {
+                Some("on") | Some("1") | Some("true") => true,
+                Some("off") | Some("0") | Some("false") => false,
+                _ => return Err(format!("Force mute must be on or off: {:?}", line)),
+            };
+            (DEFAULT_BUTTON, InputEvent::ForceMute(muted))
+        }
+        "mode" => (
+            DEFAULT_BUTTON,
+            InputEvent::Mode(arg.unwrap_or_default().parse()?),
+        ),
+        _ => return Err(format!("Unknown command: {:?}", line)),
+    }))
+}
+
+/// Sends commands from `reader` to the channel, until it ends. Returns
+/// `false` if the other end of the channel has gone away.
+fn send_lines(reader: impl BufRead, chan: &mut EventSender) -> bool {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Error reading commands: {}", e);
+                break;
+            }
+        };
+        match parse_line(&line) {
+            Ok(Some((button, event))) => {
+                if !chan.send_button(button, event) {
+                    return false;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("{}", e),
+        }
+    }
+    true
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &PathBuf) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &PathBuf) -> bool {
+    false
+}
+
+/// Reads commands from stdin, or from a file or named pipe.
+///
+/// A named pipe is opened again when its writer closes it, so that several
+/// programs can send commands in turn. Otherwise, the source stops at the end
+/// of its input, and releases any buttons it pressed.
+pub struct LineSource {
+    path: Option<PathBuf>,
+}
+
+impl LineSource {
+    /// Creates a source which reads commands from stdin.
+    pub fn stdin() -> Self {
+        LineSource { path: None }
+    }
+
+    /// Creates a source which reads commands from a file or named pipe.
+    pub fn file(path: &str) -> Result<Self, InputError> {
+        if path.is_empty() {
+            return Err(InputError {
+                msg: "No file specified".to_string(),
+            });
+        }
+        let path = PathBuf::from(path);
+        fs::metadata(&path).map_err(|e| InputError {
+            msg: format!("Failed to open {}: {}", path.display(), e),
+        })?;
+        Ok(LineSource { path: Some(path) })
+    }
+}
+
+impl InputSource for LineSource {
+    fn name(&self) -> String {
+        match &self.path {
+            Some(p) => format!("Commands from: {}", p.display()),
+            None => "Commands from stdin".to_string(),
+        }
+    }
+
+    /// Sends commands to the channel.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let path = match self.path {
+            Some(p) => p,
+            None => {
+                send_lines(io::stdin().lock(), &mut chan);
+                return;
+            }
+        };
+        let fifo = is_fifo(&path);
+
+        loop {
+            // Opening a named pipe waits for a writer.
+            let file = match File::open(&path) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Failed to open {}: {}", path.display(), e);
+                    return;
+                }
+            };
+            if !send_lines(BufReader::new(file), &mut chan) || !fifo {
+                return;
+            }
+            // Don't leave buttons held after the writer goes away.
+            if !chan.release() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Mode;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_line("press"), Ok(Some((1, InputEvent::Press))));
+        assert_eq!(
+            parse_line("  RELEASE 3 "),
+            Ok(Some((3, InputEvent::Release)))
+        );
+        assert_eq!(
+            parse_line("axis 0.25"),
+            Ok(Some((1, InputEvent::Axis(0.25))))
+        );
+        assert_eq!(parse_line("delta -2"), Ok(Some((1, InputEvent::Delta(-2)))));
+        assert_eq!(
+            parse_line("force_mute on"),
+            Ok(Some((1, InputEvent::ForceMute(true))))
+        );
+        assert_eq!(
+            parse_line("force_mute 0"),
+            Ok(Some((1, InputEvent::ForceMute(false))))
+        );
+        assert_eq!(
+            parse_line("mode latch"),
+            Ok(Some((1, InputEvent::Mode(Mode::Latch))))
+        );
+        assert_eq!(parse_line(""), Ok(None));
+        assert_eq!(parse_line("# press"), Ok(None));
+
+        for bad in &[
+            "press x",
+            "press 256",
+            "press 1 2",
+            "axis",
+            "axis 1.5",
+            "delta 0.5",
+            "force_mute maybe",
+            "mode",
+            "jump",
+        ] {
+            assert!(parse_line(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -3,17 +3,24 @@ extern crate coreaudio;
 
 use core_foundation_sys::string::{kCFStringEncodingUTF8, CFStringGetCString, CFStringRef};
 use coreaudio::sys::{
-    kAudioDevicePropertyDeviceNameCFString, kAudioDevicePropertyMute,
-    kAudioDevicePropertyScopeOutput, kAudioHardwareNoError,
-    kAudioHardwarePropertyDefaultInputDevice, kAudioObjectPropertyElementMaster,
-    kAudioObjectPropertyScopeGlobal, kAudioObjectSystemObject, AudioDeviceID,
-    AudioDeviceSetProperty, AudioObjectGetPropertyData, AudioObjectPropertyAddress,
+    kAudioDevicePropertyDeviceNameCFString, kAudioDevicePropertyDeviceUID,
+    kAudioDevicePropertyMute, kAudioDevicePropertyPreferredChannelsForStereo,
+    kAudioDevicePropertyScopeOutput, kAudioDevicePropertyStreams,
+    kAudioDevicePropertyVolumeDecibelsToScalar, kAudioDevicePropertyVolumeScalar,
+    kAudioDevicePropertyVolumeScalarToDecibels, kAudioHardwareNoError,
+    kAudioHardwarePropertyDefaultInputDevice, kAudioHardwarePropertyDefaultOutputDevice,
+    kAudioHardwarePropertyDevices, kAudioObjectPropertyElementMaster,
+    kAudioObjectPropertyScopeGlobal, kAudioObjectSystemObject, AudioDeviceGetProperty,
+    AudioDeviceID, AudioDeviceSetProperty, AudioObjectGetPropertyData,
+    AudioObjectGetPropertyDataSize, AudioObjectPropertyAddress, AudioObjectPropertySelector,
 };
 use std::ffi::CStr;
 use std::mem;
 use std::ptr::null;
 
-use crate::audio_controller::{AudioControllerTrait, AudioError, AudioInputDeviceTrait};
+use crate::audio_controller::{
+    AudioControllerTrait, AudioError, AudioInputDeviceTrait, AudioOutputDeviceTrait,
+};
 
 #[macro_export]
 macro_rules! EXAMPLE_PORT {
@@ -28,6 +35,10 @@ pub struct AudioInputDevice {
     audio_device_id: AudioDeviceID,
 }
 
+pub struct AudioOutputDevice {
+    audio_device_id: AudioDeviceID,
+}
+
 macro_rules! try_cf {
     ($expr:expr) => {
         #[allow(non_upper_case_globals)]
@@ -47,80 +58,301 @@ macro_rules! try_cf {
 
 // Implementation largely copied from cpal
 
-impl AudioControllerTrait for AudioController {
-    fn new() -> Box<dyn AudioControllerTrait> {
+/// Gets the native audio controller for this platform, if there is one.
+pub fn native_audio_controller() -> Option<Box<dyn AudioControllerTrait>> {
+    Some(AudioController::new())
+}
+
+impl AudioController {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> Box<dyn AudioControllerTrait> {
         Box::new(AudioController {})
     }
+}
 
+impl AudioControllerTrait for AudioController {
     fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
+        let audio_device_id = get_default_device(kAudioHardwarePropertyDefaultInputDevice)?;
+        Ok(Box::new(AudioInputDevice { audio_device_id }))
+    }
+
+    fn get_default_output_device(&self) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError> {
+        let audio_device_id = get_default_device(kAudioHardwarePropertyDefaultOutputDevice)?;
+        Ok(Box::new(AudioOutputDevice { audio_device_id }))
+    }
+
+    fn get_output_devices(&self) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, AudioError> {
         let property_address = AudioObjectPropertyAddress {
-            mSelector: kAudioHardwarePropertyDefaultInputDevice,
+            mSelector: kAudioHardwarePropertyDevices,
             mScope: kAudioObjectPropertyScopeGlobal,
             mElement: kAudioObjectPropertyElementMaster,
         };
 
-        let audio_device_id: AudioDeviceID = 0;
-        let data_size = mem::size_of::<AudioDeviceID>();
-        let status = unsafe {
-            AudioObjectGetPropertyData(
+        let mut data_size: u32 = 0;
+        unsafe {
+            try_cf!(AudioObjectGetPropertyDataSize(
                 kAudioObjectSystemObject,
                 &property_address as *const _,
                 0,
                 null(),
-                &data_size as *const _ as *mut _,
-                &audio_device_id as *const _ as *mut _,
-            )
-        };
-        if status != kAudioHardwareNoError as i32 {
+                &mut data_size as *mut _,
+            ));
+        }
+        let mut audio_device_ids: Vec<AudioDeviceID> =
+            vec![0; data_size as usize / mem::size_of::<AudioDeviceID>()];
+        unsafe {
+            try_cf!(AudioObjectGetPropertyData(
+                kAudioObjectSystemObject,
+                &property_address as *const _,
+                0,
+                null(),
+                &mut data_size as *mut _,
+                audio_device_ids.as_mut_ptr() as *mut _,
+            ));
+        }
+        audio_device_ids.truncate(data_size as usize / mem::size_of::<AudioDeviceID>());
+
+        let mut devices: Vec<Box<dyn AudioOutputDeviceTrait>> = Vec::new();
+        for audio_device_id in audio_device_ids {
+            if has_output_streams(audio_device_id)? {
+                devices.push(Box::new(AudioOutputDevice { audio_device_id }));
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/// Gets the default device for a `kAudioHardwareProperty*Device` selector.
+fn get_default_device(selector: AudioObjectPropertySelector) -> Result<AudioDeviceID, AudioError> {
+    let property_address = AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+
+    let audio_device_id: AudioDeviceID = 0;
+    let data_size = mem::size_of::<AudioDeviceID>();
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            kAudioObjectSystemObject,
+            &property_address as *const _,
+            0,
+            null(),
+            &data_size as *const _ as *mut _,
+            &audio_device_id as *const _ as *mut _,
+        )
+    };
+    if status != kAudioHardwareNoError as i32 {
+        return Err(AudioError {
+            msg: format!("Error: 0x{:X}", status),
+        });
+    }
+
+    Ok(audio_device_id)
+}
+
+/// Returns true if the device has any output streams.
+fn has_output_streams(audio_device_id: AudioDeviceID) -> Result<bool, AudioError> {
+    let property_address = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyStreams,
+        mScope: kAudioDevicePropertyScopeOutput,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+
+    let mut data_size: u32 = 0;
+    unsafe {
+        try_cf!(AudioObjectGetPropertyDataSize(
+            audio_device_id,
+            &property_address as *const _,
+            0,
+            null(),
+            &mut data_size as *mut _,
+        ));
+    }
+    Ok(data_size > 0)
+}
+
+/// Gets a string property of a device.
+fn get_string_property(
+    audio_device_id: AudioDeviceID,
+    selector: AudioObjectPropertySelector,
+) -> Result<String, AudioError> {
+    let property_address = AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: kAudioDevicePropertyScopeOutput,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    let mut buf: [u8; 255] = [0; 255];
+    unsafe {
+        let value: CFStringRef = null();
+        let data_size = mem::size_of::<CFStringRef>();
+        try_cf!(AudioObjectGetPropertyData(
+            audio_device_id,
+            &property_address as *const _,
+            0,
+            null(),
+            &data_size as *const _ as *mut _,
+            &value as *const _ as *mut _,
+        ));
+
+        // We could use CFStringGetCStringPtr here first for an "efficient"
+        // reference, but this has lifetime issues.
+        // https://developer.apple.com/documentation/corefoundation/1542133-cfstringgetcstringptr
+        if CFStringGetCString(
+            value,
+            buf.as_mut_ptr() as *mut i8,
+            buf.len() as _,
+            kCFStringEncodingUTF8,
+        ) == 0
+        {
             return Err(AudioError {
-                msg: format!("Error: 0x{:X}", status),
+                msg: "CFStringGetCString failed to return device property string".to_string(),
             });
         }
+    };
+    CStr::from_bytes_until_nul(&buf)
+        .map_err(|e| AudioError {
+            msg: format!("Bad audio device property string: {}", e),
+        })
+        .map(|r| r.to_string_lossy().into_owned())
+}
 
-        Ok(Box::new(AudioInputDevice { audio_device_id }))
+/// Gets the channels which control a device's volume.
+///
+/// Not all devices have a master volume control (channel 0), so this falls
+/// back to the device's preferred stereo channels, which are the same
+/// channel on mono devices.
+fn volume_channels(audio_device_id: AudioDeviceID, is_input: bool) -> Vec<u32> {
+    let mut volume: f32 = 0.;
+    let data_size = mem::size_of::<f32>() as u32;
+    let status = unsafe {
+        AudioDeviceGetProperty(
+            audio_device_id,
+            0,
+            is_input as _,
+            kAudioDevicePropertyVolumeScalar,
+            &data_size as *const _ as *mut _,
+            &mut volume as *mut _ as *mut _,
+        )
+    };
+    if status == kAudioHardwareNoError as i32 {
+        return vec![0];
     }
+
+    let mut channels: [u32; 2] = [1, 2];
+    let data_size = mem::size_of_val(&channels) as u32;
+    let status = unsafe {
+        AudioDeviceGetProperty(
+            audio_device_id,
+            0,
+            is_input as _,
+            kAudioDevicePropertyPreferredChannelsForStereo,
+            &data_size as *const _ as *mut _,
+            channels.as_mut_ptr() as *mut _,
+        )
+    };
+    if status != kAudioHardwareNoError as i32 {
+        return vec![1];
+    }
+    let mut channels = channels.to_vec();
+    channels.dedup();
+    channels
 }
 
-impl AudioInputDeviceTrait for AudioInputDevice {
-    fn name(&self) -> Result<String, AudioError> {
-        let property_address = AudioObjectPropertyAddress {
-            mSelector: kAudioDevicePropertyDeviceNameCFString,
-            mScope: kAudioDevicePropertyScopeOutput,
-            mElement: kAudioObjectPropertyElementMaster,
-        };
-        let mut buf: [u8; 255] = [0; 255];
+/// Gets the volume scalar of a device, from the first of its
+/// `volume_channels`.
+fn get_volume(audio_device_id: AudioDeviceID, is_input: bool) -> Result<f32, AudioError> {
+    let channel = volume_channels(audio_device_id, is_input)[0];
+    let mut volume: f32 = 0.;
+    let data_size = mem::size_of::<f32>() as u32;
+    unsafe {
+        try_cf!(AudioDeviceGetProperty(
+            audio_device_id,
+            channel,
+            is_input as _,
+            kAudioDevicePropertyVolumeScalar,
+            &data_size as *const _ as *mut _,
+            &mut volume as *mut _ as *mut _,
+        ));
+    }
+    Ok(volume)
+}
+
+/// Sets the volume scalar of a device, on all of its `volume_channels`.
+fn set_volume(
+    audio_device_id: AudioDeviceID,
+    is_input: bool,
+    volume: f32,
+) -> Result<(), AudioError> {
+    let data_size = mem::size_of::<f32>() as u32;
+    for channel in volume_channels(audio_device_id, is_input) {
         unsafe {
-            let device_name: CFStringRef = null();
-            let data_size = mem::size_of::<CFStringRef>();
-            try_cf!(AudioObjectGetPropertyData(
-                self.audio_device_id,
-                &property_address as *const _,
-                0,
-                null(),
-                &data_size as *const _ as *mut _,
-                &device_name as *const _ as *mut _,
+            try_cf!(AudioDeviceSetProperty(
+                audio_device_id,
+                /* when */ null(),
+                channel,
+                is_input as _,
+                kAudioDevicePropertyVolumeScalar,
+                data_size,
+                &volume as *const _ as _,
             ));
+        }
+    }
+    Ok(())
+}
 
-            // We could use CFStringGetCStringPtr here first for an "efficient"
-            // reference, but this has lifetime issues.
-            // https://developer.apple.com/documentation/corefoundation/1542133-cfstringgetcstringptr
-            if CFStringGetCString(
-                device_name,
-                buf.as_mut_ptr() as *mut i8,
-                buf.len() as _,
-                kCFStringEncodingUTF8,
-            ) == 0
-            {
-                return Err(AudioError {
-                    msg: "CFStringGetCString failed to return device name string".to_string(),
-                });
-            }
-        };
-        CStr::from_bytes_with_nul(&buf)
-            .map_err(|e| AudioError {
-                msg: format!("Bad audio device name: {}", e),
-            })
-            .and_then(|r| Ok(r.to_string_lossy().into_owned()))
+/// Converts a volume between a scalar and decibels with `selector`
+/// (`kAudioDevicePropertyVolumeScalarToDecibels` or
+/// `kAudioDevicePropertyVolumeDecibelsToScalar`), following the device's
+/// volume curve.
+///
+/// This uses the same channel as `get_volume`.
+fn convert_volume(
+    audio_device_id: AudioDeviceID,
+    is_input: bool,
+    selector: AudioObjectPropertySelector,
+    value: f32,
+) -> Result<f32, AudioError> {
+    let channel = volume_channels(audio_device_id, is_input)[0];
+    let mut converted = value;
+    let data_size = mem::size_of::<f32>() as u32;
+    unsafe {
+        try_cf!(AudioDeviceGetProperty(
+            audio_device_id,
+            channel,
+            is_input as _,
+            selector,
+            &data_size as *const _ as *mut _,
+            &mut converted as *mut _ as *mut _,
+        ));
+    }
+    Ok(converted)
+}
+
+/// Gets the volume scalar which is `db` decibels away from `volume`.
+fn adjust_volume_db(
+    audio_device_id: AudioDeviceID,
+    is_input: bool,
+    volume: f32,
+    db: f32,
+) -> Result<f32, AudioError> {
+    let level = convert_volume(
+        audio_device_id,
+        is_input,
+        kAudioDevicePropertyVolumeScalarToDecibels,
+        volume,
+    )?;
+    convert_volume(
+        audio_device_id,
+        is_input,
+        kAudioDevicePropertyVolumeDecibelsToScalar,
+        level + db,
+    )
+}
+
+impl AudioInputDeviceTrait for AudioInputDevice {
+    fn name(&self) -> Result<String, AudioError> {
+        get_string_property(self.audio_device_id, kAudioDevicePropertyDeviceNameCFString)
     }
 
     fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
@@ -140,4 +372,55 @@ impl AudioInputDeviceTrait for AudioInputDevice {
 
         Ok(state)
     }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        let cf_state: u32 = 0;
+        let data_size = mem::size_of::<u32>() as u32;
+        unsafe {
+            try_cf!(AudioDeviceGetProperty(
+                self.audio_device_id,
+                /* channel */ 0,
+                /* is_input */ 1,
+                kAudioDevicePropertyMute,
+                &data_size as *const _ as *mut _,
+                &cf_state as *const _ as *mut _,
+            ));
+        }
+
+        Ok(cf_state != 0)
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        get_volume(self.audio_device_id, true)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        set_volume(self.audio_device_id, true, volume)
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        adjust_volume_db(self.audio_device_id, true, volume, db)
+    }
+}
+
+impl AudioOutputDeviceTrait for AudioOutputDevice {
+    fn id(&self) -> Result<String, AudioError> {
+        get_string_property(self.audio_device_id, kAudioDevicePropertyDeviceUID)
+    }
+
+    fn name(&self) -> Result<String, AudioError> {
+        get_string_property(self.audio_device_id, kAudioDevicePropertyDeviceNameCFString)
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        get_volume(self.audio_device_id, false)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        set_volume(self.audio_device_id, false, volume)
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        adjust_volume_db(self.audio_device_id, false, volume, db)
+    }
 }
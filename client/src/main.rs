@@ -1,37 +1,123 @@
 #[macro_use]
 extern crate log;
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+extern crate alsa;
+extern crate base64;
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
+extern crate dirs;
 #[cfg(feature = "enigo")]
 extern crate enigo;
+#[cfg(target_os = "linux")]
+extern crate evdev;
+extern crate hex;
+extern crate hmac;
+extern crate mdns_sd;
+#[cfg(target_os = "linux")]
+extern crate nix;
+extern crate rumqttc;
+extern crate serde_json;
 extern crate serialport;
+extern crate sha2;
+extern crate tungstenite;
+extern crate ureq;
 
 use std::cmp::{max, min};
-use std::io;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use clap::ArgMatches;
+
+mod actions;
 #[cfg(feature = "enigo")]
-use enigo::{Enigo, Key, KeyboardControllable};
-use serialport::{FlowControl, SerialPort};
+use actions::KeyboardAction;
+use actions::{
+    restore_output_volumes, Action, ActionError, AxisVolumeAction, DuckAction, KnobVolumeAction,
+    MuteAction, OutputDuckAction,
+};
 
 mod audio_controller;
-use audio_controller::{AudioControllerTrait, AudioError, AudioInputDeviceTrait};
+use audio_controller::{
+    AudioControllerTrait, AudioError, AudioInputDeviceTrait, AudioOutputDeviceTrait,
+};
 
 #[macro_use]
 #[cfg_attr(target_os = "macos", path = "macos.rs")]
 #[cfg_attr(target_os = "windows", path = "windows.rs")]
 mod os;
-use os::AudioController;
 
-#[cfg(feature = "enigo")]
-const KEYCODE: Key = Key::F13;
+mod command;
+use command::{CommandAudioController, CommandConfig};
+
+mod ipc;
+
+mod discord;
+use discord::{DiscordAction, DiscordConfig, DiscordMode};
+
+mod mumble;
+use mumble::{MumbleAction, MumbleMode};
+
+mod teamspeak;
+use teamspeak::{TeamSpeakAction, TeamSpeakMode};
+
+mod obs;
+use obs::{ObsAction, ObsConfig};
+
+mod mqtt;
+use mqtt::MqttConfig;
+
+mod webhook;
+use webhook::{WebhookAction, WebhookConfig};
+
+mod websocket;
+use websocket::WebSocketAction;
+
+mod osc;
+use osc::{OscAction, OscMapping};
+
+mod fake;
+
+#[cfg(target_os = "linux")]
+mod pulse;
+
+mod input;
+use input::{parse_source, spawn_sources, InputEvent, InputSource, Mode, SourceEvent};
+
+mod serial;
+
+mod net;
+
+mod mdns;
+
+mod lines;
+
+mod midi;
+
+#[cfg(target_os = "linux")]
+mod evdev_source;
+
+#[cfg(target_os = "linux")]
+mod gpio;
+
+#[cfg(target_os = "linux")]
+mod hidraw;
+
 const CHANNEL_TIMEOUT: Duration = Duration::from_secs(1);
 const MAX_DEBOUNCE: Duration = Duration::from_secs(10);
 const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
-const MISSING_SERIAL_WAIT_TIME: Duration = Duration::from_secs(10);
+const MAX_RAMP: Duration = Duration::from_secs(5);
+const VIRTUAL_SOURCE_RAMP: Duration = Duration::from_millis(20);
+const MAX_KNOB_ACCEL: f32 = 20.;
+const MAX_LONG_PRESS: Duration = Duration::from_secs(10);
+const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, PartialEq)]
 pub enum ControllerState {
@@ -49,74 +135,60 @@ pub enum ControllerState {
     ReleaseWait(Instant),
 }
 
-pub struct MicController<'a> {
-    chan: mpsc::Receiver<bool>,
-    comms_device: Option<&'a dyn AudioInputDeviceTrait>,
-    #[cfg(feature = "enigo")]
-    enigo: Option<Enigo>,
+pub struct MicController {
+    chan: mpsc::Receiver<SourceEvent>,
+    actions: Vec<Box<dyn Action>>,
     debounce: Duration,
     controller_state: ControllerState,
+    /// Buttons which are currently pressed, as (source, button).
+    pressed_buttons: HashSet<(usize, u8)>,
+    /// Buttons which hold the footswitch, or `None` for all buttons.
+    ptt_buttons: Option<HashSet<u8>>,
+    /// Whether the footswitch is being held released by force mute.
+    force_muted: bool,
+    mode: Mode,
+    /// Whether the footswitch is toggled on, in latch mode.
+    latched: bool,
+    shutdown: Arc<AtomicBool>,
 }
 
-impl MicController<'_> {
-    pub fn new<T: AudioControllerTrait>(
-        chan: mpsc::Receiver<bool>,
-        #[cfg(feature = "enigo")] keyboard_emulation: bool,
-        microphone_control: bool,
+impl MicController {
+    pub fn new(
+        chan: mpsc::Receiver<SourceEvent>,
+        actions: Vec<Box<dyn Action>>,
         debounce: Duration,
+        ptt_buttons: Option<HashSet<u8>>,
+        mode: Mode,
+        shutdown: Arc<AtomicBool>,
     ) -> Self {
         MicController {
             chan,
-            comms_device: if microphone_control {
-                let audio = Box::leak(T::new());
-                Some(Box::leak(audio.get_comms_device().unwrap()))
-            } else {
-                None
-            },
-            #[cfg(feature = "enigo")]
-            enigo: if keyboard_emulation {
-                Some(Enigo::new())
-            } else {
-                None
-            },
+            actions,
             debounce,
             controller_state: ControllerState::Released,
+            pressed_buttons: HashSet::new(),
+            ptt_buttons,
+            force_muted: false,
+            mode,
+            latched: false,
+            shutdown,
         }
     }
 
-    pub fn device_name(&self) -> Result<String, AudioError> {
-        match self.comms_device {
-            Some(c) => c.name(),
-            None => Ok("None".to_string()),
-        }
-    }
-
-    fn dispatch(&mut self) -> Result<(), AudioError> {
+    fn dispatch(&mut self) -> Result<(), ActionError> {
         match self.controller_state {
             ControllerState::Pressed => {
                 debug!("Button pressing");
                 self.controller_state = ControllerState::Held;
-                #[cfg(feature = "enigo")]
-                if let Some(e) = self.enigo.as_mut() {
-                    e.key_up(KEYCODE)
-                };
-                return match self.comms_device {
-                    Some(c) => c.set_mute(false).map(|_| ()),
-                    None => Ok(()),
-                };
-            }
-            ControllerState::ReleaseWait(released_at) => {
-                if released_at.elapsed() >= self.debounce {
-                    debug!("Button releasing");
-                    self.controller_state = ControllerState::Released;
-                    #[cfg(feature = "enigo")]
-                    if let Some(e) = self.enigo.as_mut() {
-                        e.key_down(KEYCODE)
-                    };
-                    return match self.comms_device {
-                        Some(c) => c.set_mute(true).map(|_| ()),
-                        None => Ok(()),
-                    };
+                for a in self.actions.iter_mut() {
+                    a.press()?;
+                }
+            }
+            ControllerState::ReleaseWait(released_at) if released_at.elapsed() >= self.debounce => {
+                debug!("Button releasing");
+                self.controller_state = ControllerState::Released;
+                for a in self.actions.iter_mut() {
+                    a.release()?;
                 }
             }
             _ => {}
@@ -124,21 +196,110 @@ impl MicController<'_> {
         Ok(())
     }
 
-    pub fn pumpit(&mut self) -> Result<(), AudioError> {
+    /// Tells all actions that the client is stopping because of an error.
+    fn error(&mut self, e: &ActionError) {
+        for a in self.actions.iter_mut() {
+            a.error(&e.msg);
+        }
+    }
+
+    /// Polls all actions, and returns the longest we can wait before polling
+    /// them again.
+    fn poll(&mut self) -> Result<Duration, ActionError> {
+        let mut timeout = CHANNEL_TIMEOUT;
+        for a in self.actions.iter_mut() {
+            if let Some(t) = a.poll()? {
+                timeout = min(timeout, t);
+            }
+        }
+        Ok(timeout)
+    }
+
+    pub fn pumpit(&mut self) -> Result<(), ActionError> {
         loop {
+            if self.shutdown.load(Ordering::SeqCst) {
+                info!("Shutting down");
+                return Ok(());
+            }
+            let timeout = self.poll()?;
             let res = self.chan.recv_timeout(match self.controller_state {
                 ControllerState::ReleaseWait(released_at) => min(
-                    CHANNEL_TIMEOUT,
+                    timeout,
                     max(
                         Duration::from_millis(1),
-                        self.debounce - released_at.elapsed(),
+                        self.debounce
+                            .checked_sub(released_at.elapsed())
+                            .unwrap_or_default(),
                     ),
                 ),
-                _ => CHANNEL_TIMEOUT,
+                _ => timeout,
             });
             match res {
                 Ok(msg) => {
-                    if msg {
+                    if let InputEvent::Press | InputEvent::Release = msg.event {
+                        for a in self.actions.iter_mut() {
+                            a.button(msg.button, msg.event == InputEvent::Press)?;
+                        }
+                    }
+
+                    // The footswitch is held while any button is pressed.
+                    let button = (msg.source, msg.button);
+                    match msg.event {
+                        InputEvent::Press | InputEvent::Release
+                            if self
+                                .ptt_buttons
+                                .as_ref()
+                                .is_some_and(|b| !b.contains(&msg.button)) =>
+                        {
+                            continue;
+                        }
+                        InputEvent::Press => {
+                            if self.mode == Mode::Latch && self.pressed_buttons.is_empty() {
+                                self.latched = !self.latched;
+                            }
+                            self.pressed_buttons.insert(button);
+                        }
+                        InputEvent::Release => {
+                            self.pressed_buttons.remove(&button);
+                        }
+                        InputEvent::Axis(value) => {
+                            for a in self.actions.iter_mut() {
+                                a.axis(value)?;
+                            }
+                            continue;
+                        }
+                        InputEvent::Delta(steps) => {
+                            for a in self.actions.iter_mut() {
+                                a.delta(steps)?;
+                            }
+                            continue;
+                        }
+                        InputEvent::ForceMute(muted) => {
+                            if muted == self.force_muted {
+                                continue;
+                            }
+                            info!("Force mute {}", if muted { "on" } else { "off" });
+                            self.force_muted = muted;
+                            for a in self.actions.iter_mut() {
+                                a.force_mute(muted)?;
+                            }
+                        }
+                        InputEvent::Mode(mode) => {
+                            if mode == self.mode {
+                                continue;
+                            }
+                            info!("Mode: {:?}", mode);
+                            self.mode = mode;
+                            // Stay held if a button is held down.
+                            self.latched = !self.pressed_buttons.is_empty();
+                        }
+                    }
+
+                    let held = match self.mode {
+                        Mode::Hold => !self.pressed_buttons.is_empty(),
+                        Mode::Latch => self.latched,
+                    };
+                    if held && !self.force_muted {
                         match self.controller_state {
                             ControllerState::Released => {
                                 self.controller_state = ControllerState::Pressed
@@ -148,7 +309,11 @@ impl MicController<'_> {
                             }
                             _ => {}
                         }
-                    } else {
+                    } else if let ControllerState::Pressed | ControllerState::Held =
+                        self.controller_state
+                    {
+                        // Other events (eg: a second button being released)
+                        // don't restart the debounce.
                         self.controller_state = ControllerState::ReleaseWait(Instant::now());
                     }
                     self.dispatch()?;
@@ -158,7 +323,16 @@ impl MicController<'_> {
                         self.dispatch()?;
                     }
                     _ => {
-                        // The other side has probably gone away!
+                        // The other side has probably gone away! Finish
+                        // releasing the button first.
+                        if let ControllerState::ReleaseWait(released_at) = self.controller_state {
+                            thread::sleep(
+                                self.debounce
+                                    .checked_sub(released_at.elapsed())
+                                    .unwrap_or_default(),
+                            );
+                            self.dispatch()?;
+                        }
                         info!("Closing pumpit thread");
                         return Ok(());
                     }
@@ -168,68 +342,12 @@ impl MicController<'_> {
     }
 }
 
-fn create_serial_port(serial_device: &str) -> Result<Box<dyn SerialPort>, serialport::Error> {
-    serialport::new(serial_device, 9600)
-        .flow_control(FlowControl::Hardware)
-        .timeout(CHANNEL_TIMEOUT)
-        .open()
-        .map_err(|e| {
-            error!("Failed to open {}: {}", serial_device, e.description);
-            e
-        })
-}
-
-/// Sends events from the serial port to the channel.
-fn interact(mut port: Box<dyn SerialPort>, serial_device: String, chan: mpsc::Sender<bool>) {
-    let mut buf = [0; 1];
-
-    'outer: loop {
-        'inner: loop {
-            let res = port.read(&mut buf[..]);
-            match res {
-                Ok(len) => {
-                    if len == 1 {
-                        match chan.send(match buf[0] {
-                            b'0' => false,
-                            b'1' => true,
-                            _ => {
-                                warn!("Unhandled serial input: {}", buf[0]);
-                                break 'inner;
-                            }
-                        }) {
-                            Ok(()) => {}
-                            Err(_) => {
-                                // Other end of the channel has probably gone away.
-                                // Shut down the thread.
-                                break 'outer;
-                            }
-                        }
-                    } else {
-                        warn!("Unhandled serial input length ({}): {:?}", len, &buf[..len]);
-                        break 'inner;
-                    }
-                }
-                Err(error) => match error.kind() {
-                    io::ErrorKind::TimedOut => continue,
-                    _ => {
-                        warn!("Error reading serial device: {:?}", error);
-                        break 'inner;
-                    }
-                },
-            }
-        }
-
-        // Something went wrong - reset the serial port if possible.
-        port = 'reset: loop {
-            match create_serial_port(&serial_device) {
-                Ok(p) => {
-                    warn!("Reconnecting device {}", &serial_device);
-                    break 'reset p;
-                }
-                Err(_) => thread::sleep(MISSING_SERIAL_WAIT_TIME),
-            }
-        };
-    }
+/// Lists serial ports and network footswitches.
+fn list_devices() {
+    error!("Available serial ports:");
+    serial::list_ports();
+    error!("Network footswitches (use with --source mdns:NAME):");
+    mdns::list_footswitches();
 }
 
 fn main() {
@@ -241,12 +359,32 @@ fn main() {
         ")"
     );
     let default_debounce = DEFAULT_DEBOUNCE.as_millis().to_string();
+    let default_webhook_timeout = DEFAULT_WEBHOOK_TIMEOUT.as_millis().to_string();
 
     let matches = clap_app!(footswitch =>
         (version: "0.1")
         (author: "Michael Farrell <https://github.com/micolous/footswitch>")
         (about: "Serial control client for a USB footswitch")
         (@arg DEVICE: port_help)
+        (@arg source: -s --source
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("SPEC")
+            "Input source, in the form TYPE:OPTIONS (eg: serial:/dev/ttyUSB0). May be given multiple times.")
+        (@arg list_devices: -l --list_devices
+            "Lists serial ports and network footswitches, then exits")
+        (@arg ptt_button: --ptt_button
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("BUTTON")
+            "Only this button holds the footswitch, on sources with several buttons (default: all buttons). May be given multiple times.")
+        (@arg mode: --mode
+            default_value("hold")
+            possible_values(&["hold", "latch"])
+            value_name("MODE")
+            "Whether the footswitch is held while a button is pressed, or each press toggles it")
         (@arg keyboard_emulation: -k --keyboard
             "Enables keyboard input emulation; only needed for serial.ino")
         (@arg debounce_duration: -d --debounce
@@ -255,6 +393,217 @@ fn main() {
             "Debounce duration, in milliseconds")
         (@arg no_mute: -M --no_mute
             "Disables automatic microphone mute control")
+        (@arg duck: --duck
+            +takes_value
+            allow_hyphen_values(true)
+            value_name("DB")
+            "Lowers the microphone to this level (in dB, eg: -30) instead of muting it")
+        (@arg ramp_duration: --ramp
+            default_value("0")
+            value_name("MSEC")
+            "Fades the microphone in and out over this many milliseconds")
+        (@arg duck_output: --duck_output
+            +takes_value
+            allow_hyphen_values(true)
+            value_name("DB")
+            "Lowers output devices by this amount (in dB, eg: -20) while the footswitch is held")
+        (@arg axis_volume: --axis_volume
+            conflicts_with("duck_output")
+            "Sets the volume of output devices from an analog pedal's position")
+        (@arg output: --output
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("NAME")
+            "Output device to control with --duck_output, --axis_volume or --knob (default: the default output device)")
+        (@arg knob: --knob
+            +takes_value
+            possible_values(&["mic", "output"])
+            value_name("TARGET")
+            "Turning a knob steps the volume of the microphone or output devices")
+        (@arg knob_step: --knob_step
+            default_value("2")
+            value_name("PERCENT")
+            "Volume change for each step of the knob, in percent")
+        (@arg knob_accel: --knob_accel
+            default_value("4")
+            value_name("FACTOR")
+            "Multiplies steps by up to this much when the knob turns quickly (1 disables acceleration)")
+        (@arg discord: --discord
+            +takes_value
+            value_name("CLIENT_ID")
+            "Sets Discord's mute state over its local RPC interface, as this Discord application")
+        (@arg discord_secret: --discord_secret
+            +takes_value
+            requires("discord")
+            value_name("SECRET")
+            "The Discord application's client secret, needed to authorize the client the first time")
+        (@arg discord_mode: --discord_mode
+            default_value("mute")
+            possible_values(&["mute", "voice"])
+            value_name("MODE")
+            "Whether the footswitch unmutes Discord, or switches it to voice activity")
+        (@arg discord_ipc: --discord_ipc
+            +takes_value
+            requires("discord")
+            value_name("PATH")
+            "Discord's IPC socket or named pipe (default: find it automatically)")
+        (@arg teamspeak: --teamspeak
+            +takes_value
+            value_name("API_KEY")
+            "Sets TeamSpeak's mute or talk state over ClientQuery, with this API key")
+        (@arg teamspeak_mode: --teamspeak_mode
+            default_value("mute")
+            possible_values(&["mute", "talk"])
+            value_name("MODE")
+            "Whether the footswitch unmutes the TeamSpeak microphone, or activates it like push-to-talk")
+        (@arg teamspeak_addr: --teamspeak_addr
+            default_value(teamspeak::DEFAULT_ADDR)
+            value_name("ADDRESS")
+            "Address of TeamSpeak's ClientQuery interface")
+        (@arg mumble: --mumble
+            possible_values(&["talk", "mute", "deaf"])
+            +takes_value
+            value_name("MODE")
+            "Starts and stops talking (or unmutes or undeafens) in Mumble while the footswitch is held")
+        (@arg mumble_socket: --mumble_socket
+            +takes_value
+            requires("mumble")
+            value_name("PATH")
+            "Mumble's RPC socket (default: $XDG_RUNTIME_DIR/MumbleSocket or ~/.MumbleSocket)")
+        (@arg obs_input: --obs_input
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("NAME")
+            "Unmutes this OBS audio input while the footswitch is pressed, over obs-websocket")
+        (@arg obs_scene: --obs_scene
+            +takes_value
+            value_name("NAME")
+            "Switches OBS to this scene when the footswitch is held down, over obs-websocket")
+        (@arg obs_long_press: --obs_long_press
+            default_value("1000")
+            value_name("MSEC")
+            "How long the footswitch must be held down to switch OBS scene, in milliseconds")
+        (@arg obs_addr: --obs_addr
+            default_value(obs::DEFAULT_ADDR)
+            value_name("ADDRESS")
+            "Address of OBS's WebSocket server")
+        (@arg obs_password: --obs_password
+            +takes_value
+            value_name("PASSWORD")
+            "Password for OBS's WebSocket server, if authentication is enabled")
+        (@arg mqtt: --mqtt
+            +takes_value
+            value_name("HOST[:PORT]")
+            "Publishes the footswitch's state to this MQTT broker, and takes commands from it")
+        (@arg mqtt_topic: --mqtt_topic
+            default_value(mqtt::DEFAULT_TOPIC)
+            value_name("TOPIC")
+            "Prefix for MQTT topics")
+        (@arg mqtt_user: --mqtt_user
+            +takes_value
+            requires("mqtt")
+            value_name("USER")
+            "Username for the MQTT broker")
+        (@arg mqtt_password: --mqtt_password
+            +takes_value
+            requires("mqtt_user")
+            value_name("PASSWORD")
+            "Password for the MQTT broker")
+        (@arg mqtt_discovery: --mqtt_discovery
+            requires("mqtt")
+            "Publishes Home Assistant discovery messages to MQTT")
+        (@arg webhook: --webhook
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("URL")
+            "POSTs to this URL when the footswitch is pressed or released, or the client stops with an error")
+        (@arg webhook_body: --webhook_body
+            default_value(webhook::DEFAULT_BODY)
+            value_name("TEMPLATE")
+            "Webhook request body, where {event}, {timestamp} and {message} are replaced")
+        (@arg webhook_timeout: --webhook_timeout
+            default_value(&default_webhook_timeout)
+            value_name("MSEC")
+            "Timeout for each webhook request, in milliseconds")
+        (@arg webhook_retries: --webhook_retries
+            default_value("3")
+            value_name("COUNT")
+            "Number of times to retry a webhook request that fails, waiting longer each time")
+        (@arg websocket: --websocket
+            +takes_value
+            value_name("ADDRESS")
+            "Pushes the footswitch's state to WebSocket clients, and serves a stream overlay, on this address (eg: 127.0.0.1:8765)")
+        (@arg osc: --osc
+            +takes_value
+            +multiple
+            number_of_values(1)
+            requires("osc_message")
+            value_name("HOST:PORT")
+            "Sends OSC messages over UDP to this mixer or DAW")
+        (@arg osc_message: --osc_message
+            +takes_value
+            +multiple
+            number_of_values(1)
+            requires("osc")
+            value_name("MAPPING")
+            "OSC message to send, as [BUTTON:]ADDRESS [PRESS_ARGS][/RELEASE_ARGS] (eg: \"/ch/01/mix/on 1/0\")")
+        (@arg midi_out: --midi_out
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("MAPPING")
+            "MIDI message to send from an ALSA sequencer port, as [BUTTON:]MESSAGE[/RELEASE_MESSAGE] (eg: \"mmc punch_in/mmc punch_out\") (Linux only)")
+        (@arg midi_out_channel: --midi_out_channel
+            default_value("1")
+            value_name("CHANNEL")
+            "MIDI channel (1 - 16) for --midi_out")
+        (@arg midi_out_to: --midi_out_to
+            +takes_value
+            requires("midi_out")
+            value_name("CLIENT:PORT")
+            "Connects the MIDI output port to this ALSA sequencer port (eg: a DAW)")
+        (@arg app: --app
+            +takes_value
+            +multiple
+            number_of_values(1)
+            value_name("NAME")
+            "Only mutes capture streams from this application, rather than the whole microphone (Linux only)")
+        (@arg audio: --audio
+            default_value("native")
+            possible_values(&["native", "command", "pactl", "wpctl", "amixer", "fake"])
+            value_name("BACKEND")
+            "Audio controller to use")
+        (@arg name_command: --name_command +takes_value value_name("CMD")
+            "Command which prints the microphone name, for --audio command")
+        (@arg mute_command: --mute_command +takes_value value_name("CMD")
+            "Command which mutes the microphone, for --audio command")
+        (@arg unmute_command: --unmute_command +takes_value value_name("CMD")
+            "Command which unmutes the microphone, for --audio command")
+        (@arg get_mute_command: --get_mute_command +takes_value value_name("CMD")
+            "Command which prints the microphone mute state, for --audio command")
+        (@arg get_volume_command: --get_volume_command +takes_value value_name("CMD")
+            "Command which prints the microphone volume, for --audio command")
+        (@arg set_volume_command: --set_volume_command +takes_value value_name("CMD")
+            "Command which sets the microphone volume to {volume} (0.0 - 1.0) or {percent}, for --audio command")
+        (@arg list_outputs_command: --list_outputs_command +takes_value value_name("CMD")
+            "Command which prints the names of output devices, one per line, for --audio command")
+        (@arg default_output: --default_output +takes_value value_name("DEVICE")
+            "Name of the default output device, for --audio command")
+        (@arg get_output_volume_command: --get_output_volume_command +takes_value value_name("CMD")
+            "Command which prints the volume of output device {device}, for --audio command")
+        (@arg set_output_volume_command: --set_output_volume_command +takes_value value_name("CMD")
+            "Command which sets the volume of output device {device} to {volume} (0.0 - 1.0) or {percent}, for --audio command")
+        (@arg virtual_source: --virtual_source
+            conflicts_with("app")
+            "Creates a virtual microphone for apps to use, and gates that instead of the real microphone (Linux only)")
+        (@arg physical_source: --physical_source
+            +takes_value
+            requires("virtual_source")
+            value_name("NAME")
+            "Microphone to feed the virtual microphone from (default: the default source)")
     )
     .get_matches();
 
@@ -266,83 +615,653 @@ fn main() {
     }
     let microphone_control = !matches.is_present("no_mute");
 
-    let serial_device = match matches.value_of("DEVICE") {
-        Some(v) => v.to_string(),
-        None => {
-            error!("No device specified. Available serial ports:");
-            let ports = serialport::available_ports().unwrap_or_else(|_| {
-                error!("Unable to probe for available serial ports!");
-                Vec::with_capacity(0)
-            });
-            if ports.is_empty() {
-                error!("No serial ports found!");
-            } else {
-                for p in ports {
-                    error!("* {}", p.port_name);
-                }
-            }
+    let mut source_specs: Vec<String> = matches
+        .values_of("source")
+        .map(|v| v.map(str::to_string).collect())
+        .unwrap_or_default();
+    if let Some(v) = matches.value_of("DEVICE") {
+        source_specs.insert(0, format!("serial:{}", v));
+    }
+    // MQTT can be the only source, when home automation drives the
+    // footswitch.
+    if matches.is_present("list_devices")
+        || (source_specs.is_empty() && !matches.is_present("mqtt"))
+    {
+        if !matches.is_present("list_devices") {
+            error!("No device specified.");
+        }
+        list_devices();
+        return;
+    }
+
+    let debounce_duration = match parse_millis(&matches, "debounce_duration", MAX_DEBOUNCE) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("--debounce: {}", e);
             return;
         }
     };
 
-    let debounce_duration = match u64::from_str(
-        matches.value_of("debounce_duration").unwrap(), // Default set in clap_app! macro
-    )
-    .map(Duration::from_millis)
+    let ptt_buttons = match matches
+        .values_of("ptt_button")
+        .map(|v| v.map(u8::from_str).collect::<Result<HashSet<_>, _>>())
+        .transpose()
     {
+        Ok(b) => b,
         Err(e) => {
-            error!("Error parsing debounce duration: {}", e);
+            error!("--ptt_button: {}", e);
             return;
         }
-        Ok(d) => {
-            if d > MAX_DEBOUNCE {
-                error!(
-                    "--debounce must be less than or equal to {} milliseconds",
-                    MAX_DEBOUNCE.as_millis()
-                );
-                return;
-            }
-            d
+    };
+
+    let ramp_duration = match parse_millis(&matches, "ramp_duration", MAX_RAMP) {
+        // Gate the virtual microphone with a short fade, unless told otherwise.
+        Ok(_)
+            if matches.is_present("virtual_source")
+                && matches.occurrences_of("ramp_duration") == 0 =>
+        {
+            VIRTUAL_SOURCE_RAMP
+        }
+        Ok(d) => d,
+        Err(e) => {
+            error!("--ramp: {}", e);
+            return;
         }
     };
 
-    let (tx, rx) = mpsc::channel();
+    let duck_level = match parse_db(&matches, "duck") {
+        Ok(d) => d,
+        Err(e) => {
+            error!("--duck: {}", e);
+            return;
+        }
+    };
+
+    let duck_output_level = match parse_db(&matches, "duck_output") {
+        Ok(d) => d,
+        Err(e) => {
+            error!("--duck_output: {}", e);
+            return;
+        }
+    };
+
+    let knob_step = match parse_range(&matches, "knob_step", 0., 100.) {
+        Ok(s) => s / 100.,
+        Err(e) => {
+            error!("--knob_step: {}", e);
+            return;
+        }
+    };
+
+    let knob_accel = match parse_range(&matches, "knob_accel", 1., MAX_KNOB_ACCEL) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("--knob_accel: {}", e);
+            return;
+        }
+    };
+
+    let mut sources: Vec<Box<dyn InputSource>> = Vec::with_capacity(source_specs.len());
+    for spec in source_specs.iter() {
+        match parse_source(spec) {
+            Ok(s) => {
+                info!("{}", s.name());
+                sources.push(s);
+            }
+            Err(e) => {
+                error!("Error setting up input source {:?}: {}", spec, e.msg);
+                return;
+            }
+        }
+    }
 
-    info!("Serial port: {}", &serial_device);
     #[cfg(feature = "enigo")]
     info!(
         "Keyboard emulation: {}",
         if keyboard_emulation { "on" } else { "off" }
     );
     info!("Debounce: {} ms", debounce_duration.as_millis());
-    let port = create_serial_port(&serial_device).expect("Failed to open port");
 
-    let serial_thread = thread::spawn(move || {
-        interact(port, serial_device, tx);
-    });
+    let audio = get_audio_controller(&matches);
+    if let Ok(a) = &audio {
+        if let Err(e) = restore_output_volumes(a.as_ref()) {
+            error!("Error restoring output volumes: {:?}", e);
+        }
+    }
 
-    let mut mc = MicController::new::<AudioController>(
-        rx,
-        #[cfg(feature = "enigo")]
-        keyboard_emulation,
-        microphone_control,
-        debounce_duration,
-    );
+    let mut actions: Vec<Box<dyn Action>> = Vec::new();
+    #[cfg(feature = "enigo")]
+    if keyboard_emulation {
+        actions.push(Box::new(KeyboardAction::default()));
+    }
     if microphone_control {
+        let comms_device = match get_input_device(&audio, &matches) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Failed to get microphone device: {:?}", e);
+                return;
+            }
+        };
         info!(
             "Microphone device: {}",
-            mc.device_name().unwrap_or_else(|_| "unknown".to_string())
+            comms_device
+                .name()
+                .unwrap_or_else(|_| "unknown".to_string())
         );
+        let action: Result<Box<dyn Action>, ActionError> = match duck_level {
+            Some(db) => {
+                info!("Duck level: {} dB", db);
+                DuckAction::new(comms_device, db, ramp_duration)
+                    .map(|a| Box::new(a) as Box<dyn Action>)
+            }
+            None => {
+                MuteAction::new(comms_device, ramp_duration).map(|a| Box::new(a) as Box<dyn Action>)
+            }
+        };
+        match action {
+            Ok(a) => actions.push(a),
+            Err(e) => {
+                error!("Error setting up microphone control: {:?}", e);
+                return;
+            }
+        }
+        info!("Ramp: {} ms", ramp_duration.as_millis());
     } else {
         info!("Microphone control disabled.");
     }
+
+    if duck_output_level.is_some() || matches.is_present("axis_volume") {
+        let audio = match &audio {
+            Ok(a) => a,
+            Err(e) => {
+                error!("{}", e.msg);
+                return;
+            }
+        };
+        let devices = match select_output_devices(audio.as_ref(), matches.values_of("output")) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        for d in devices.iter() {
+            info!(
+                "Output device: {}",
+                d.name().unwrap_or_else(|_| "unknown".to_string())
+            );
+        }
+        let action: Result<Box<dyn Action>, ActionError> = match duck_output_level {
+            Some(db) => {
+                info!("Output duck level: {} dB", db);
+                OutputDuckAction::new(devices, db, ramp_duration)
+                    .map(|a| Box::new(a) as Box<dyn Action>)
+            }
+            None => {
+                info!("Output volume follows the analog pedal");
+                AxisVolumeAction::new(devices).map(|a| Box::new(a) as Box<dyn Action>)
+            }
+        };
+        match action {
+            Ok(a) => actions.push(a),
+            Err(e) => {
+                error!("Error setting up output device control: {:?}", e);
+                return;
+            }
+        }
+    }
+
+    if let Some(client_id) = matches.value_of("discord") {
+        let config = DiscordConfig {
+            client_id: client_id.to_string(),
+            client_secret: matches.value_of("discord_secret").map(str::to_string),
+            ipc_path: matches.value_of("discord_ipc").map(PathBuf::from),
+            mode: match matches.value_of("discord_mode") {
+                Some("voice") => DiscordMode::Voice,
+                _ => DiscordMode::Mute,
+            },
+        };
+        info!("Discord mode: {:?}", config.mode);
+        match DiscordAction::new(config) {
+            Ok(a) => actions.push(Box::new(a)),
+            Err(e) => {
+                error!("Error setting up Discord: {}", e.msg);
+                return;
+            }
+        }
+    }
+
+    if let Some(mode) = matches.value_of("mumble") {
+        let mode = match mode {
+            "mute" => MumbleMode::Mute,
+            "deaf" => MumbleMode::Deaf,
+            _ => MumbleMode::Talk,
+        };
+        info!("Mumble mode: {:?}", mode);
+        actions.push(Box::new(MumbleAction::new(
+            matches.value_of("mumble_socket").map(PathBuf::from),
+            mode,
+        )));
+    }
+
+    if let Some(api_key) = matches.value_of("teamspeak") {
+        let addr = match matches.value_of("teamspeak_addr").unwrap().parse() {
+            Ok(a) => a,
+            Err(e) => {
+                error!("--teamspeak_addr: {}", e);
+                return;
+            }
+        };
+        let mode = match matches.value_of("teamspeak_mode") {
+            Some("talk") => TeamSpeakMode::Talk,
+            _ => TeamSpeakMode::Mute,
+        };
+        info!("TeamSpeak mode: {:?}", mode);
+        actions.push(Box::new(TeamSpeakAction::new(addr, api_key, mode)));
+    }
+
+    if matches.is_present("obs_input") || matches.is_present("obs_scene") {
+        let addr = match matches.value_of("obs_addr").unwrap().parse() {
+            Ok(a) => a,
+            Err(e) => {
+                error!("--obs_addr: {}", e);
+                return;
+            }
+        };
+        let long_press = match parse_millis(&matches, "obs_long_press", MAX_LONG_PRESS) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("--obs_long_press: {}", e);
+                return;
+            }
+        };
+        let config = ObsConfig {
+            addr,
+            password: matches.value_of("obs_password").map(str::to_string),
+            inputs: matches
+                .values_of("obs_input")
+                .map(|v| v.map(str::to_string).collect())
+                .unwrap_or_default(),
+            scene: matches.value_of("obs_scene").map(str::to_string),
+            long_press,
+        };
+        for input in config.inputs.iter() {
+            info!("OBS input: {}", input);
+        }
+        if let Some(scene) = config.scene.as_ref() {
+            info!("OBS long press scene: {} (after {:?})", scene, long_press);
+        }
+        actions.push(Box::new(ObsAction::new(config)));
+    }
+
+    if let Some(broker) = matches.value_of("mqtt") {
+        let (host, port) = match broker.split_once(':') {
+            Some((host, port)) => match port.parse() {
+                Ok(p) => (host, p),
+                Err(e) => {
+                    error!("--mqtt: invalid port: {}", e);
+                    return;
+                }
+            },
+            None => (broker, mqtt::DEFAULT_PORT),
+        };
+        let (action, source) = mqtt::connect(MqttConfig {
+            host: host.to_string(),
+            port,
+            topic: matches.value_of("mqtt_topic").unwrap().to_string(),
+            username: matches.value_of("mqtt_user").map(str::to_string),
+            password: matches.value_of("mqtt_password").map(str::to_string),
+            discovery: matches.is_present("mqtt_discovery"),
+        });
+        info!("{}", source.name());
+        sources.push(Box::new(source));
+        actions.push(Box::new(action));
+    }
+
+    if let Some(urls) = matches.values_of("webhook") {
+        let timeout = match parse_millis(&matches, "webhook_timeout", MAX_WEBHOOK_TIMEOUT) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("--webhook_timeout: {}", e);
+                return;
+            }
+        };
+        let retries = match u32::from_str(matches.value_of("webhook_retries").unwrap()) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("--webhook_retries: {}", e);
+                return;
+            }
+        };
+        let config = WebhookConfig {
+            urls: urls.map(str::to_string).collect(),
+            body: matches.value_of("webhook_body").unwrap().to_string(),
+            timeout,
+            retries,
+        };
+        for url in config.urls.iter() {
+            info!("Webhook: {}", url);
+        }
+        actions.push(Box::new(WebhookAction::new(config)));
+    }
+
+    if let Some(addr) = matches.value_of("websocket") {
+        let addr = match addr.parse() {
+            Ok(a) => a,
+            Err(e) => {
+                error!("--websocket: {}", e);
+                return;
+            }
+        };
+        match WebSocketAction::new(addr) {
+            Ok(a) => {
+                info!("WebSocket server and overlay: http://{}/", addr);
+                actions.push(Box::new(a));
+            }
+            Err(e) => {
+                error!("Error setting up WebSocket server: {}", e.msg);
+                return;
+            }
+        }
+    }
+
+    if let Some(targets) = matches.values_of("osc") {
+        let targets: Vec<&str> = targets.collect();
+        let mut mappings = Vec::new();
+        for spec in matches.values_of("osc_message").unwrap() {
+            match OscMapping::parse(spec) {
+                Ok(m) => mappings.push(m),
+                Err(e) => {
+                    error!("--osc_message: {}", e);
+                    return;
+                }
+            }
+        }
+        match OscAction::new(&targets, mappings) {
+            Ok(a) => {
+                for t in targets {
+                    info!("OSC target: {}", t);
+                }
+                actions.push(Box::new(a));
+            }
+            Err(e) => {
+                error!("Error setting up OSC: {}", e.msg);
+                return;
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "alsa"))]
+    if let Some(specs) = matches.values_of("midi_out") {
+        let mut mappings = Vec::new();
+        for spec in specs {
+            match midi::MidiOutMapping::parse(spec) {
+                Ok(m) => mappings.push(m),
+                Err(e) => {
+                    error!("--midi_out: {}", e);
+                    return;
+                }
+            }
+        }
+        let channel = match matches.value_of("midi_out_channel").unwrap().parse() {
+            Ok(c @ 1..=16) => c,
+            _ => {
+                error!("--midi_out_channel: must be between 1 and 16");
+                return;
+            }
+        };
+        match midi::MidiSeqAction::new(mappings, channel, matches.value_of("midi_out_to")) {
+            Ok(a) => {
+                info!("MIDI output port: {}", a.name());
+                actions.push(Box::new(a));
+            }
+            Err(e) => {
+                error!("Error setting up MIDI output: {}", e.msg);
+                return;
+            }
+        }
+    }
+    #[cfg(not(all(target_os = "linux", feature = "alsa")))]
+    if matches.is_present("midi_out") {
+        error!("--midi_out is only supported on Linux, with the alsa feature");
+        return;
+    }
+
+    match matches.value_of("knob") {
+        Some("mic") => {
+            // The knob changes the physical microphone's gain, even when
+            // muting app streams or a virtual microphone.
+            let device = match audio
+                .as_ref()
+                .map_err(AudioError::clone)
+                .and_then(|a| a.get_comms_device())
+            {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("Failed to get microphone device: {:?}", e);
+                    return;
+                }
+            };
+            info!(
+                "Knob controls microphone volume, {}% per step",
+                knob_step * 100.
+            );
+            match KnobVolumeAction::new(vec![device], knob_step, knob_accel) {
+                Ok(a) => actions.push(Box::new(a)),
+                Err(e) => {
+                    error!("Error setting up knob: {:?}", e);
+                    return;
+                }
+            }
+        }
+        Some(_) => {
+            let devices = match audio
+                .as_ref()
+                .map_err(|e| e.msg.clone())
+                .and_then(|a| select_output_devices(a.as_ref(), matches.values_of("output")))
+            {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            info!(
+                "Knob controls output volume, {}% per step",
+                knob_step * 100.
+            );
+            match KnobVolumeAction::new(devices, knob_step, knob_accel) {
+                Ok(a) => actions.push(Box::new(a)),
+                Err(e) => {
+                    error!("Error setting up knob: {:?}", e);
+                    return;
+                }
+            }
+        }
+        None => {}
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let source_threads = spawn_sources(sources, tx);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || {
+        if handler_shutdown.swap(true, Ordering::SeqCst) {
+            // Pressed twice: give up on cleaning up.
+            process::exit(1);
+        }
+    })
+    .expect("Failed to set Ctrl-C handler");
+
+    let mut mc = MicController::new(
+        rx,
+        actions,
+        debounce_duration,
+        ptt_buttons,
+        Mode::from_str(matches.value_of("mode").unwrap()).unwrap(),
+        shutdown.clone(),
+    );
     info!("Ready, waiting for footswitch press...");
 
     match mc.pumpit() {
         Ok(()) => {}
-        Err(e) => error!("Error in MicController: {:?}", e),
+        Err(e) => {
+            error!("Error in MicController: {:?}", e);
+            mc.error(&e);
+        }
     }
     drop(mc);
 
-    serial_thread.join().unwrap();
+    if !shutdown.load(Ordering::SeqCst) {
+        for t in source_threads {
+            t.join().unwrap();
+        }
+    }
+}
+
+/// Parses a duration in milliseconds from a command-line argument.
+fn parse_millis(matches: &ArgMatches, name: &str, max: Duration) -> Result<Duration, String> {
+    let d = u64::from_str(
+        matches.value_of(name).unwrap(), // Default set in clap_app! macro
+    )
+    .map(Duration::from_millis)
+    .map_err(|e| format!("error parsing duration: {}", e))?;
+    if d > max {
+        return Err(format!(
+            "must be less than or equal to {} milliseconds",
+            max.as_millis()
+        ));
+    }
+    Ok(d)
+}
+
+/// Parses a level in decibels from a command-line argument.
+fn parse_db(matches: &ArgMatches, name: &str) -> Result<Option<f32>, String> {
+    match matches.value_of(name).map(f32::from_str) {
+        None => Ok(None),
+        Some(Ok(db)) if db <= 0. => Ok(Some(db)),
+        Some(Ok(_)) => Err("must be less than or equal to 0 dB".to_string()),
+        Some(Err(e)) => Err(format!("error parsing level: {}", e)),
+    }
+}
+
+/// Parses a number between `min` and `max` from a command-line argument.
+fn parse_range(matches: &ArgMatches, name: &str, min: f32, max: f32) -> Result<f32, String> {
+    let v = f32::from_str(
+        matches.value_of(name).unwrap(), // Default set in clap_app! macro
+    )
+    .map_err(|e| format!("error parsing number: {}", e))?;
+    if !(min..=max).contains(&v) {
+        return Err(format!("must be between {} and {}", min, max));
+    }
+    Ok(v)
+}
+
+/// Gets the microphone device to control: either the default communications
+/// device, the capture streams of particular applications, or a virtual
+/// microphone.
+fn get_input_device(
+    audio: &Result<Box<dyn AudioControllerTrait>, AudioError>,
+    matches: &ArgMatches,
+) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(apps) = matches.values_of("app") {
+            return Ok(Box::new(pulse::AppStreamsDevice::new(
+                apps.map(str::to_string).collect(),
+            )?));
+        }
+        if matches.is_present("virtual_source") {
+            return Ok(Box::new(pulse::VirtualSourceDevice::new(
+                matches.value_of("physical_source"),
+            )?));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        if matches.is_present("app") || matches.is_present("virtual_source") {
+            return Err(AudioError {
+                msg: "--app and --virtual_source are only supported on Linux".to_string(),
+            });
+        }
+    }
+    audio
+        .as_ref()
+        .map_err(AudioError::clone)?
+        .get_comms_device()
+}
+
+/// Gets the audio controller selected with `--audio`.
+fn get_audio_controller(matches: &ArgMatches) -> Result<Box<dyn AudioControllerTrait>, AudioError> {
+    let backend = matches.value_of("audio").unwrap(); // Default set in clap_app! macro
+    let mut config = match backend {
+        "native" => {
+            return os::native_audio_controller().ok_or_else(|| AudioError {
+                msg: concat!(
+                    "No native audio controller is available on this platform. ",
+                    "Use --audio to select another one, or --no_mute to disable ",
+                    "microphone control."
+                )
+                .to_string(),
+            })
+        }
+        "fake" => return Ok(fake::AudioController::new()),
+        "command" => CommandConfig::default(),
+        preset => CommandConfig::preset(preset).unwrap(), // Checked by possible_values
+    };
+
+    let command = |name| matches.value_of(name).map(str::to_string);
+    config.name = command("name_command").or(config.name);
+    config.mute = command("mute_command").or(config.mute);
+    config.unmute = command("unmute_command").or(config.unmute);
+    config.get_mute = command("get_mute_command").or(config.get_mute);
+    config.get_volume = command("get_volume_command").or(config.get_volume);
+    config.set_volume = command("set_volume_command").or(config.set_volume);
+    config.list_outputs = command("list_outputs_command").or(config.list_outputs);
+    config.default_output = command("default_output").or(config.default_output);
+    config.get_output_volume = command("get_output_volume_command").or(config.get_output_volume);
+    config.set_output_volume = command("set_output_volume_command").or(config.set_output_volume);
+    CommandAudioController::new(config)
+}
+
+/// Finds output devices by name or ID, or the default output device if no
+/// names were given.
+fn select_output_devices(
+    audio: &dyn AudioControllerTrait,
+    names: Option<clap::Values>,
+) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, String> {
+    let names = match names {
+        Some(n) => n,
+        None => {
+            return audio
+                .get_default_output_device()
+                .map(|d| vec![d])
+                .map_err(|e| format!("Failed to get default output device: {:?}", e))
+        }
+    };
+
+    let mut devices = audio
+        .get_output_devices()
+        .map_err(|e| format!("Failed to get output devices: {:?}", e))?;
+    let mut selected = Vec::new();
+    for name in names {
+        let pos = devices
+            .iter()
+            .position(|d| d.name().is_ok_and(|n| n == name) || d.id().is_ok_and(|i| i == name));
+        match pos {
+            Some(p) => selected.push(devices.remove(p)),
+            None => {
+                let mut msg = format!("Output device {:?} not found. Available devices:", name);
+                for d in devices.iter() {
+                    msg.push_str(&format!(
+                        "\n* {}",
+                        d.name().unwrap_or_else(|_| "unknown".to_string())
+                    ));
+                }
+                return Err(msg);
+            }
+        }
+    }
+    Ok(selected)
 }
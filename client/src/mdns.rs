@@ -0,0 +1,87 @@
+/*
+ * Finds network footswitches with multicast DNS service discovery.
+ */
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+/// DNS-SD service type advertised by network footswitches.
+pub const SERVICE_TYPE: &str = "_footswitch._udp.local.";
+/// How long to wait for footswitches to answer.
+pub const BROWSE_TIME: Duration = Duration::from_secs(3);
+
+/// A footswitch found on the network.
+pub struct Footswitch {
+    /// The footswitch's DNS-SD instance name, eg: `Studio 1 pedal`.
+    pub instance: String,
+    pub addr: SocketAddr,
+}
+
+/// Browses for footswitches for `timeout`.
+///
+/// If `instance` is given, this returns as soon as that footswitch is found.
+pub fn browse(timeout: Duration, instance: Option<&str>) -> Result<Vec<Footswitch>, String> {
+    let daemon = ServiceDaemon::new().map_err(|e| format!("Failed to start mDNS: {}", e))?;
+    let events = daemon
+        .browse(SERVICE_TYPE)
+        .map_err(|e| format!("Failed to browse for footswitches: {}", e))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<Footswitch> = Vec::new();
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let info = match events.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => info,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let name = info
+            .get_fullname()
+            .strip_suffix(SERVICE_TYPE)
+            .unwrap_or(info.get_fullname())
+            .trim_end_matches('.')
+            .to_string();
+        // Prefer IPv4, as small devices often don't do IPv6 well.
+        let ip = match info.get_addresses().iter().min_by_key(|ip| ip.is_ipv6()) {
+            Some(ip) => *ip,
+            None => continue,
+        };
+        if found.iter().any(|f| f.instance == name) {
+            continue;
+        }
+        let wanted = instance.is_some_and(|i| i.eq_ignore_ascii_case(&name));
+        found.push(Footswitch {
+            instance: name,
+            addr: SocketAddr::new(ip, info.get_port()),
+        });
+        if wanted {
+            break;
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(found)
+}
+
+/// Finds the address of a footswitch by its instance name.
+pub fn resolve(instance: &str) -> Result<SocketAddr, String> {
+    browse(BROWSE_TIME, Some(instance))?
+        .into_iter()
+        .find(|f| f.instance.eq_ignore_ascii_case(instance))
+        .map(|f| f.addr)
+        .ok_or_else(|| format!("No footswitch called {:?} found on the network", instance))
+}
+
+/// Lists footswitches on the network.
+pub fn list_footswitches() {
+    match browse(BROWSE_TIME, None) {
+        Err(e) => error!("{}", e),
+        Ok(found) if found.is_empty() => error!("No network footswitches found!"),
+        Ok(found) => {
+            for f in found {
+                error!("* {} ({})", f.instance, f.addr);
+            }
+        }
+    }
+}
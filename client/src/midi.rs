@@ -0,0 +1,1079 @@
+/*
+ * MIDI input sources, for sustain pedals and foot controllers.
+ *
+ * Messages come either from a raw MIDI byte stream on a serial port (eg: a
+ * MIDI DIN interface at 31250 baud), or from an ALSA sequencer port on Linux.
+ *
+ * On Linux, the footswitch can also send MIDI from an ALSA sequencer port,
+ * eg: to punch in and out of recording in a DAW.
+ */
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use serialport::{FlowControl, SerialPort};
+
+use crate::input::{
+    EventSender, InputError, InputEvent, InputSource, SourceOptions, DEFAULT_BUTTON,
+};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+const MISSING_SERIAL_WAIT_TIME: Duration = Duration::from_secs(10);
+/// Standard MIDI baud rate.
+const MIDI_BAUD_RATE: u32 = 31250;
+/// Sustain (damper) pedal controller.
+const CC_SUSTAIN: u8 = 64;
+/// Controller values at or above this are "on", as for the sustain pedal.
+const DEFAULT_THRESHOLD: u8 = 64;
+
+/// A MIDI channel message, reduced to the parts we map to buttons.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+    /// Note on or off. A note on with a velocity of 0 is a note off.
+    Note { channel: u8, note: u8, on: bool },
+    /// Control change.
+    Control {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// Program change, which has no matching "off" message.
+    Program { channel: u8, program: u8 },
+}
+
+/// Decodes a raw MIDI byte stream, one byte at a time.
+///
+/// This handles running status, and skips over system exclusive and
+/// real-time messages.
+#[derive(Default)]
+struct Decoder {
+    status: Option<u8>,
+    data: Vec<u8>,
+}
+
+impl Decoder {
+    fn decode(&mut self, byte: u8) -> Option<Message> {
+        match byte {
+            // Real-time messages may appear anywhere, and don't affect
+            // running status.
+            0xf8..=0xff => None,
+            // System common and exclusive messages cancel running status, and
+            // their data bytes are ignored.
+            0xf0..=0xf7 => {
+                self.status = None;
+                self.data.clear();
+                None
+            }
+            0x80..=0xef => {
+                self.status = Some(byte);
+                self.data.clear();
+                None
+            }
+            _ => {
+                let status = self.status?;
+                self.data.push(byte);
+                let len = match status & 0xf0 {
+                    0xc0 | 0xd0 => 1,
+                    _ => 2,
+                };
+                if self.data.len() < len {
+                    return None;
+                }
+                let data = std::mem::take(&mut self.data);
+                let channel = status & 0x0f;
+                match status & 0xf0 {
+                    0x80 => Some(Message::Note {
+                        channel,
+                        note: data[0],
+                        on: false,
+                    }),
+                    0x90 => Some(Message::Note {
+                        channel,
+                        note: data[0],
+                        on: data[1] > 0,
+                    }),
+                    0xb0 => Some(Message::Control {
+                        channel,
+                        controller: data[0],
+                        value: data[1],
+                    }),
+                    0xc0 => Some(Message::Program {
+                        channel,
+                        program: data[0],
+                    }),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Forgets any partially-read message.
+    fn reset(&mut self) {
+        self.status = None;
+        self.data.clear();
+    }
+}
+
+/// Maps MIDI messages to buttons.
+struct Mapping {
+    /// Only listen to this channel (0 - 15), or all channels if `None`.
+    channel: Option<u8>,
+    /// (note, button), where a note of `None` matches any note.
+    notes: Vec<(Option<u8>, u8)>,
+    /// (controller, button)
+    controls: Vec<(u8, u8)>,
+    /// (program, button)
+    programs: Vec<(u8, u8)>,
+    /// Controller values at or above this press the button.
+    threshold: u8,
+    /// Controllers which are currently past the threshold.
+    controls_on: Vec<u8>,
+    /// (channel, note) of notes which are currently held.
+    notes_on: Vec<(u8, u8)>,
+}
+
+/// Parses a mapping, in the form `NUMBER[:BUTTON]`.
+fn parse_mapping(key: &str, mapping: &str) -> Result<(u8, u8), InputError> {
+    let (number, button) = mapping.split_once(':').unwrap_or((mapping, "1"));
+    match (number.parse::<u8>(), button.parse::<u8>()) {
+        (Ok(n), Ok(b)) if n < 128 => Ok((n, b)),
+        _ => Err(InputError {
+            msg: format!(
+                "Invalid MIDI {} mapping {:?}, expected NUMBER[:BUTTON]",
+                key, mapping
+            ),
+        }),
+    }
+}
+
+impl Mapping {
+    /// Options used by `Mapping::from_options`.
+    const OPTIONS: &'static [&'static str] = &["channel", "note", "cc", "program", "threshold"];
+
+    /// Creates a mapping from `channel=1-16`, `threshold=1-127`, and repeated
+    /// `note=`, `cc=` and `program=` options.
+    ///
+    /// Without any mappings, all notes and the sustain pedal (CC 64) press
+    /// `DEFAULT_BUTTON`.
+    fn from_options(options: &SourceOptions) -> Result<Self, InputError> {
+        let channel = match options.get("channel") {
+            None => None,
+            Some(c) => Some(
+                c.parse::<u8>()
+                    .ok()
+                    .filter(|c| (1..=16).contains(c))
+                    .ok_or_else(|| InputError {
+                        msg: "MIDI channel must be between 1 and 16".to_string(),
+                    })?
+                    - 1,
+            ),
+        };
+        let threshold = match options.get("threshold") {
+            None => DEFAULT_THRESHOLD,
+            Some(t) => t
+                .parse::<u8>()
+                .ok()
+                .filter(|t| (1..128).contains(t))
+                .ok_or_else(|| InputError {
+                    msg: "MIDI threshold must be between 1 and 127".to_string(),
+                })?,
+        };
+        let parse_all = |key| {
+            options
+                .get_all(key)
+                .map(|m| parse_mapping(key, m))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut notes: Vec<_> = parse_all("note")?
+            .into_iter()
+            .map(|(n, b)| (Some(n), b))
+            .collect();
+        let mut controls = parse_all("cc")?;
+        let programs = parse_all("program")?;
+        if notes.is_empty() && controls.is_empty() && programs.is_empty() {
+            notes.push((None, DEFAULT_BUTTON));
+            controls.push((CC_SUSTAIN, DEFAULT_BUTTON));
+        }
+        Ok(Mapping {
+            channel,
+            notes,
+            controls,
+            programs,
+            threshold,
+            controls_on: Vec::new(),
+            notes_on: Vec::new(),
+        })
+    }
+
+    /// Returns the button events for a message.
+    fn events(&mut self, message: Message) -> Vec<(u8, InputEvent)> {
+        let channel = match message {
+            Message::Note { channel, .. }
+            | Message::Control { channel, .. }
+            | Message::Program { channel, .. } => channel,
+        };
+        if self.channel.is_some_and(|c| c != channel) {
+            return Vec::new();
+        }
+        match message {
+            Message::Note { note, on, .. } => {
+                let held = self.notes_on.contains(&(channel, note));
+                if on == held {
+                    return Vec::new();
+                }
+                let buttons: Vec<_> = self
+                    .notes
+                    .iter()
+                    .filter(|(n, _)| n.is_none_or(|n| n == note))
+                    .map(|&(_, b)| b)
+                    .collect();
+                self.update(buttons, on, |m| {
+                    if on {
+                        m.notes_on.push((channel, note));
+                    } else {
+                        m.notes_on.retain(|n| *n != (channel, note));
+                    }
+                })
+            }
+            Message::Control {
+                controller, value, ..
+            } => {
+                // Continuous controllers (eg: half-pedalling) send lots of
+                // values, so only send changes.
+                let on = value >= self.threshold;
+                let was_on = self.controls_on.contains(&controller);
+                if on == was_on {
+                    return Vec::new();
+                }
+                let buttons: Vec<_> = self
+                    .controls
+                    .iter()
+                    .filter(|(c, _)| *c == controller)
+                    .map(|&(_, b)| b)
+                    .collect();
+                self.update(buttons, on, |m| {
+                    if on {
+                        m.controls_on.push(controller);
+                    } else {
+                        m.controls_on.retain(|c| *c != controller);
+                    }
+                })
+            }
+            // Program changes don't have a release, so tap the button.
+            Message::Program { program, .. } => self
+                .programs
+                .iter()
+                .filter(|(p, _)| *p == program)
+                .flat_map(|&(_, b)| [(b, InputEvent::Press), (b, InputEvent::Release)])
+                .collect(),
+        }
+    }
+
+    /// Returns `true` if any held note or controller is mapped to `button`.
+    fn is_held(&self, button: u8) -> bool {
+        self.notes_on.iter().any(|&(_, note)| {
+            self.notes
+                .iter()
+                .any(|&(n, b)| b == button && n.is_none_or(|n| n == note))
+        }) || self.controls_on.iter().any(|&controller| {
+            self.controls
+                .iter()
+                .any(|&(c, b)| b == button && c == controller)
+        })
+    }
+
+    /// Applies `change` to the held notes and controllers, and returns
+    /// events for the `buttons` it pressed or released.
+    ///
+    /// Buttons stay pressed while any of their notes or controllers are
+    /// held, so that chords press them once, and release them when the last
+    /// note is released.
+    fn update(
+        &mut self,
+        mut buttons: Vec<u8>,
+        on: bool,
+        change: impl FnOnce(&mut Self),
+    ) -> Vec<(u8, InputEvent)> {
+        buttons.sort_unstable();
+        buttons.dedup();
+        buttons.retain(|&b| self.is_held(b) != on);
+        change(self);
+        buttons
+            .into_iter()
+            .filter(|&b| self.is_held(b) == on)
+            .map(|b| {
+                (
+                    b,
+                    if on {
+                        InputEvent::Press
+                    } else {
+                        InputEvent::Release
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Forgets which notes and controllers are held.
+    fn reset(&mut self) {
+        self.controls_on.clear();
+        self.notes_on.clear();
+    }
+}
+
+/// Sends the events for a message. Returns `false` if the other end of the
+/// channel has gone away.
+fn send_message(mapping: &mut Mapping, message: Message, chan: &mut EventSender) -> bool {
+    trace!("MIDI message: {:?}", message);
+    mapping
+        .events(message)
+        .into_iter()
+        .all(|(button, event)| chan.send_button(button, event))
+}
+
+fn create_serial_port(serial_device: &str, baud: u32) -> Result<Box<dyn SerialPort>, InputError> {
+    serialport::new(serial_device, baud)
+        .flow_control(FlowControl::None)
+        .timeout(READ_TIMEOUT)
+        .open()
+        .map_err(|e| InputError {
+            msg: format!("Failed to open {}: {}", serial_device, e.description),
+        })
+}
+
+/// Reads MIDI messages from a serial port.
+pub struct MidiSerialSource {
+    serial_device: String,
+    baud: u32,
+    port: Box<dyn SerialPort>,
+    mapping: Mapping,
+}
+
+impl MidiSerialSource {
+    /// Creates a MIDI source from options in the form
+    /// `PORT[,baud=RATE][,channel=1-16][,note=NOTE[:BUTTON]...][,cc=CONTROLLER[:BUTTON]...][,program=PROGRAM[:BUTTON]...][,threshold=VALUE]`.
+    pub fn new(options: &str) -> Result<Self, InputError> {
+        let (serial_device, options) = options.split_once(',').unwrap_or((options, ""));
+        let options = SourceOptions::parse(options, &[Mapping::OPTIONS, &["baud"]].concat())?;
+        let mapping = Mapping::from_options(&options)?;
+        let baud = match options.get("baud") {
+            None => MIDI_BAUD_RATE,
+            Some(b) => b
+                .parse()
+                .ok()
+                .filter(|b| *b > 0)
+                .ok_or_else(|| InputError {
+                    msg: format!("Invalid baud rate {:?}", b),
+                })?,
+        };
+        if serial_device.is_empty() {
+            return Err(InputError {
+                msg: "No serial device specified".to_string(),
+            });
+        }
+        let port = create_serial_port(serial_device, baud)?;
+        Ok(MidiSerialSource {
+            serial_device: serial_device.to_string(),
+            baud,
+            port,
+            mapping,
+        })
+    }
+}
+
+impl InputSource for MidiSerialSource {
+    fn name(&self) -> String {
+        format!("MIDI serial port: {}", self.serial_device)
+    }
+
+    /// Sends events from MIDI messages to the channel.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let MidiSerialSource {
+            serial_device,
+            baud,
+            mut port,
+            mut mapping,
+        } = *self;
+        let mut decoder = Decoder::default();
+        let mut buf = [0; 64];
+
+        loop {
+            match port.read(&mut buf) {
+                Ok(len) => {
+                    for message in buf[..len].iter().filter_map(|b| decoder.decode(*b)) {
+                        if !send_message(&mut mapping, message, &mut chan) {
+                            // Other end of the channel has probably gone away.
+                            // Shut down the thread.
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => warn!("Error reading serial device: {}", e),
+            }
+
+            // Don't leave any buttons held while the device is missing.
+            decoder.reset();
+            mapping.reset();
+            if !chan.release() {
+                return;
+            }
+
+            // Something went wrong - reset the serial port if possible.
+            port = loop {
+                match create_serial_port(&serial_device, baud) {
+                    Ok(p) => {
+                        warn!("Reconnecting device {}", &serial_device);
+                        break p;
+                    }
+                    Err(e) => {
+                        error!("{}", e.msg);
+                        thread::sleep(MISSING_SERIAL_WAIT_TIME);
+                    }
+                }
+            };
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+pub use self::seq::{MidiOutMapping, MidiSeqAction, MidiSeqSource};
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+mod seq {
+    use std::ffi::CString;
+
+    use alsa::seq::{
+        Addr, ClientIter, EvCtrl, EvNote, Event, EventType, PortCap, PortSubscribe, PortType,
+    };
+    use alsa::{Direction, Seq};
+
+    use super::{send_message, Mapping, Message};
+    use crate::actions::{Action, ActionError};
+    use crate::input::{EventSender, InputError, InputSource, SourceOptions};
+
+    /// ALSA sequencer client and port name.
+    const CLIENT_NAME: &str = "footswitch";
+    const PORT_NAME: &str = "Footswitch input";
+    const OUTPUT_PORT_NAME: &str = "Footswitch output";
+    /// MIDI Machine Control device ID which addresses every device.
+    const MMC_ALL_DEVICES: u8 = 0x7f;
+
+    fn alsa_error(e: alsa::Error) -> InputError {
+        InputError {
+            msg: format!("ALSA sequencer error: {}", e),
+        }
+    }
+
+    /// Finds a sequencer port, in the form `CLIENT[:PORT]`, where `CLIENT`
+    /// is a client number or name.
+    fn find_port(seq: &Seq, addr: &str) -> Result<Addr, InputError> {
+        let (client, port) = addr.rsplit_once(':').unwrap_or((addr, "0"));
+        let port = port.parse().map_err(|_| InputError {
+            msg: format!("Invalid ALSA sequencer port {:?}", addr),
+        })?;
+        let client = match client.parse() {
+            Ok(c) => c,
+            Err(_) => ClientIter::new(seq)
+                .find(|c| c.get_name().is_ok_and(|n| n == client))
+                .map(|c| c.get_client())
+                .ok_or_else(|| InputError {
+                    msg: format!("No ALSA sequencer client called {:?}", client),
+                })?,
+        };
+        Ok(Addr { client, port })
+    }
+
+    /// Creates a sequencer client and port.
+    fn open_port(direction: Direction, name: &str, caps: PortCap) -> alsa::Result<(Seq, Addr)> {
+        let seq = Seq::open(None, Some(direction), false)?;
+        seq.set_client_name(&CString::new(CLIENT_NAME).unwrap())?;
+        let port = Addr {
+            client: seq.client_id()?,
+            port: seq.create_simple_port(
+                &CString::new(name).unwrap(),
+                caps,
+                PortType::MIDI_GENERIC | PortType::APPLICATION,
+            )?,
+        };
+        Ok((seq, port))
+    }
+
+    /// Reads MIDI messages from an ALSA sequencer port.
+    ///
+    /// This creates a port which other programs (or `aconnect`) can connect
+    /// to, and can optionally connect to a sender itself.
+    pub struct MidiSeqSource {
+        seq: Seq,
+        port: Addr,
+        mapping: Mapping,
+    }
+
+    impl MidiSeqSource {
+        /// Creates a sequencer source from options in the form
+        /// `[from=CLIENT:PORT][,channel=1-16][,note=...][,cc=...][,program=...][,threshold=VALUE]`.
+        pub fn new(options: &str) -> Result<Self, InputError> {
+            let options = SourceOptions::parse(options, &[Mapping::OPTIONS, &["from"]].concat())?;
+            let mapping = Mapping::from_options(&options)?;
+
+            let (seq, port) = open_port(
+                Direction::Capture,
+                PORT_NAME,
+                PortCap::WRITE | PortCap::SUBS_WRITE,
+            )
+            .map_err(alsa_error)?;
+
+            if let Some(from) = options.get("from") {
+                let sender = find_port(&seq, from)?;
+                let subs = PortSubscribe::empty().map_err(alsa_error)?;
+                subs.set_sender(sender);
+                subs.set_dest(port);
+                seq.subscribe_port(&subs).map_err(|e| InputError {
+                    msg: format!("Failed to connect to ALSA sequencer port {}: {}", from, e),
+                })?;
+            }
+
+            Ok(MidiSeqSource { seq, port, mapping })
+        }
+    }
+
+    impl InputSource for MidiSeqSource {
+        fn name(&self) -> String {
+            format!(
+                "ALSA sequencer port: {}:{}",
+                self.port.client, self.port.port
+            )
+        }
+
+        /// Sends events from MIDI messages to the channel.
+        fn run(self: Box<Self>, mut chan: EventSender) {
+            let MidiSeqSource {
+                seq, mut mapping, ..
+            } = *self;
+            let mut input = seq.input();
+
+            loop {
+                let event = match input.event_input() {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error!("Error reading ALSA sequencer: {}", e);
+                        return;
+                    }
+                };
+                let message = match event.get_type() {
+                    EventType::Noteon | EventType::Noteoff => {
+                        event.get_data::<EvNote>().map(|n| Message::Note {
+                            channel: n.channel,
+                            note: n.note,
+                            on: event.get_type() == EventType::Noteon && n.velocity > 0,
+                        })
+                    }
+                    EventType::Controller => event.get_data::<EvCtrl>().map(|c| Message::Control {
+                        channel: c.channel,
+                        controller: c.param as u8,
+                        value: c.value.clamp(0, 127) as u8,
+                    }),
+                    EventType::Pgmchange => event.get_data::<EvCtrl>().map(|c| Message::Program {
+                        channel: c.channel,
+                        program: c.value.clamp(0, 127) as u8,
+                    }),
+                    _ => None,
+                };
+                if let Some(message) = message {
+                    if !send_message(&mut mapping, message, &mut chan) {
+                        // Other end of the channel has probably gone away.
+                        // Shut down the thread.
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// A MIDI message to send.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum OutMessage {
+        /// Note on, or note off if the velocity is 0.
+        Note {
+            note: u8,
+            velocity: u8,
+        },
+        Control {
+            controller: u8,
+            value: u8,
+        },
+        Program(u8),
+        /// MIDI Machine Control (transport) command.
+        Mmc(u8),
+    }
+
+    impl OutMessage {
+        /// Parses a message, in the form `note NOTE [VELOCITY]`,
+        /// `cc CONTROLLER VALUE`, `program PROGRAM` or `mmc COMMAND`.
+        fn parse(spec: &str) -> Result<Self, String> {
+            let words: Vec<&str> = spec.split_whitespace().collect();
+            let num = |i: usize| -> Result<u8, String> {
+                words
+                    .get(i)
+                    .and_then(|w| w.parse().ok())
+                    .filter(|&n: &u8| n <= 127)
+                    .ok_or_else(|| format!("Invalid MIDI message {:?}", spec))
+            };
+            Ok(match (words.first().copied(), words.len()) {
+                (Some("note"), 2) => OutMessage::Note {
+                    note: num(1)?,
+                    velocity: 127,
+                },
+                (Some("note"), 3) => OutMessage::Note {
+                    note: num(1)?,
+                    velocity: num(2)?,
+                },
+                (Some("cc"), 3) => OutMessage::Control {
+                    controller: num(1)?,
+                    value: num(2)?,
+                },
+                (Some("program"), 2) => OutMessage::Program(num(1)?),
+                (Some("mmc"), 2) => OutMessage::Mmc(match words[1] {
+                    "stop" => 0x01,
+                    "play" => 0x02,
+                    "deferred_play" => 0x03,
+                    "fast_forward" => 0x04,
+                    "rewind" => 0x05,
+                    "record" | "punch_in" => 0x06,
+                    "record_exit" | "punch_out" => 0x07,
+                    "pause" => 0x09,
+                    c => return Err(format!("Unknown MMC command {:?}", c)),
+                }),
+                _ => {
+                    return Err(format!(
+                        "Invalid MIDI message {:?}, expected note, cc, program or mmc",
+                        spec
+                    ))
+                }
+            })
+        }
+    }
+
+    /// A MIDI message to send when the footswitch (or one of its buttons) is
+    /// pressed, and optionally another when it's released.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct MidiOutMapping {
+        /// The button this is for, or `None` for the footswitch as a whole.
+        button: Option<u8>,
+        press: OutMessage,
+        release: Option<OutMessage>,
+    }
+
+    impl MidiOutMapping {
+        /// Parses a mapping, in the form `[BUTTON:]MESSAGE[/RELEASE_MESSAGE]`,
+        /// eg: `mmc punch_in/mmc punch_out`, or `2:cc 20 127/cc 20 0`.
+        ///
+        /// A note without `/RELEASE_MESSAGE` is released (with a note off)
+        /// along with the footswitch. Other messages send nothing on release.
+        pub fn parse(spec: &str) -> Result<Self, String> {
+            let (button, rest) = match spec.split_once(':') {
+                Some((b, rest)) => (
+                    Some(
+                        b.trim()
+                            .parse::<u8>()
+                            .map_err(|_| format!("Invalid button in MIDI mapping {:?}", spec))?,
+                    ),
+                    rest,
+                ),
+                None => (None, spec),
+            };
+            let (press, release) = match rest.split_once('/') {
+                Some((p, r)) => (OutMessage::parse(p)?, Some(OutMessage::parse(r)?)),
+                None => {
+                    let press = OutMessage::parse(rest)?;
+                    let release = match press {
+                        OutMessage::Note { note, .. } => {
+                            Some(OutMessage::Note { note, velocity: 0 })
+                        }
+                        _ => None,
+                    };
+                    (press, release)
+                }
+            };
+            Ok(MidiOutMapping {
+                button,
+                press,
+                release,
+            })
+        }
+    }
+
+    /// Sends MIDI messages from an ALSA sequencer port when the footswitch or
+    /// its buttons change state.
+    ///
+    /// Other programs (or `aconnect`) can connect to the port, and it can
+    /// optionally connect to a receiver itself.
+    pub struct MidiSeqAction {
+        seq: Seq,
+        port: Addr,
+        /// MIDI channel, from 0 to 15.
+        channel: u8,
+        mappings: Vec<MidiOutMapping>,
+    }
+
+    impl MidiSeqAction {
+        /// `channel` is from 1 to 16, and `to` is a port to connect to, in
+        /// the form `CLIENT[:PORT]`.
+        pub fn new(
+            mappings: Vec<MidiOutMapping>,
+            channel: u8,
+            to: Option<&str>,
+        ) -> Result<Self, ActionError> {
+            let (seq, port) = open_port(
+                Direction::Playback,
+                OUTPUT_PORT_NAME,
+                PortCap::READ | PortCap::SUBS_READ,
+            )
+            .map_err(|e| ActionError {
+                msg: format!("ALSA sequencer error: {}", e),
+            })?;
+
+            if let Some(to) = to {
+                let dest = find_port(&seq, to).map_err(|e| ActionError { msg: e.msg })?;
+                let subs = PortSubscribe::empty().map_err(|e| ActionError {
+                    msg: format!("ALSA sequencer error: {}", e),
+                })?;
+                subs.set_sender(port);
+                subs.set_dest(dest);
+                seq.subscribe_port(&subs).map_err(|e| ActionError {
+                    msg: format!("Failed to connect to ALSA sequencer port {}: {}", to, e),
+                })?;
+            }
+
+            Ok(MidiSeqAction {
+                seq,
+                port,
+                channel: channel - 1,
+                mappings,
+            })
+        }
+
+        /// Returns the port's address, as `CLIENT:PORT`.
+        pub fn name(&self) -> String {
+            format!("{}:{}", self.port.client, self.port.port)
+        }
+
+        fn send_message(&self, message: OutMessage) -> alsa::Result<()> {
+            let channel = self.channel;
+            let sysex;
+            let mut event = match message {
+                OutMessage::Note { note, velocity } => {
+                    let data = EvNote {
+                        channel,
+                        note,
+                        velocity,
+                        off_velocity: 0,
+                        duration: 0,
+                    };
+                    if velocity > 0 {
+                        Event::new(EventType::Noteon, &data)
+                    } else {
+                        Event::new(EventType::Noteoff, &data)
+                    }
+                }
+                OutMessage::Control { controller, value } => Event::new(
+                    EventType::Controller,
+                    &EvCtrl {
+                        channel,
+                        param: controller.into(),
+                        value: value.into(),
+                    },
+                ),
+                OutMessage::Program(program) => Event::new(
+                    EventType::Pgmchange,
+                    &EvCtrl {
+                        channel,
+                        param: 0,
+                        value: program.into(),
+                    },
+                ),
+                OutMessage::Mmc(command) => {
+                    sysex = [0xf0, 0x7f, MMC_ALL_DEVICES, 0x06, command, 0xf7];
+                    Event::new_ext(EventType::Sysex, &sysex[..])
+                }
+            };
+            event.set_source(self.port.port);
+            event.set_subs();
+            event.set_direct();
+            self.seq.event_output_direct(&mut event)?;
+            Ok(())
+        }
+
+        /// Sends the messages for a button (or the footswitch, if `None`).
+        fn send(&self, button: Option<u8>, pressed: bool) {
+            for m in self.mappings.iter().filter(|m| m.button == button) {
+                let message = match (pressed, m.release) {
+                    (true, _) => m.press,
+                    (false, Some(r)) => r,
+                    (false, None) => continue,
+                };
+                debug!("MIDI: {:?}", message);
+                if let Err(e) = self.send_message(message) {
+                    warn!("Error sending MIDI: {}", e);
+                }
+            }
+        }
+    }
+
+    impl Action for MidiSeqAction {
+        fn press(&mut self) -> Result<(), ActionError> {
+            self.send(None, true);
+            Ok(())
+        }
+
+        fn release(&mut self) -> Result<(), ActionError> {
+            self.send(None, false);
+            Ok(())
+        }
+
+        fn button(&mut self, button: u8, pressed: bool) -> Result<(), ActionError> {
+            self.send(Some(button), pressed);
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn out_messages() {
+            assert_eq!(
+                OutMessage::parse("note 60"),
+                Ok(OutMessage::Note {
+                    note: 60,
+                    velocity: 127
+                })
+            );
+            assert_eq!(
+                OutMessage::parse(" note 60  20 "),
+                Ok(OutMessage::Note {
+                    note: 60,
+                    velocity: 20
+                })
+            );
+            assert_eq!(
+                OutMessage::parse("cc 20 0"),
+                Ok(OutMessage::Control {
+                    controller: 20,
+                    value: 0
+                })
+            );
+            assert_eq!(
+                OutMessage::parse("program 127"),
+                Ok(OutMessage::Program(127))
+            );
+            assert_eq!(OutMessage::parse("mmc punch_in"), Ok(OutMessage::Mmc(0x06)));
+            assert_eq!(
+                OutMessage::parse("mmc punch_out"),
+                Ok(OutMessage::Mmc(0x07))
+            );
+            assert_eq!(OutMessage::parse("mmc stop"), Ok(OutMessage::Mmc(0x01)));
+
+            for bad in &[
+                "",
+                "note",
+                "note 128",
+                "note 60 20 1",
+                "cc 20",
+                "cc 20 x",
+                "program -1",
+                "mmc eject",
+                "sysex 1",
+            ] {
+                assert!(OutMessage::parse(bad).is_err(), "{:?}", bad);
+            }
+        }
+
+        #[test]
+        fn out_mappings() {
+            assert_eq!(
+                MidiOutMapping::parse("mmc punch_in/mmc punch_out"),
+                Ok(MidiOutMapping {
+                    button: None,
+                    press: OutMessage::Mmc(0x06),
+                    release: Some(OutMessage::Mmc(0x07)),
+                })
+            );
+            assert_eq!(
+                MidiOutMapping::parse("2:cc 20 127/cc 20 0"),
+                Ok(MidiOutMapping {
+                    button: Some(2),
+                    press: OutMessage::Control {
+                        controller: 20,
+                        value: 127
+                    },
+                    release: Some(OutMessage::Control {
+                        controller: 20,
+                        value: 0
+                    }),
+                })
+            );
+            // Notes are released with a note off, other messages aren't.
+            assert_eq!(
+                MidiOutMapping::parse("note 36 100").map(|m| m.release),
+                Ok(Some(OutMessage::Note {
+                    note: 36,
+                    velocity: 0
+                }))
+            );
+            assert_eq!(
+                MidiOutMapping::parse("mmc record").map(|m| m.release),
+                Ok(None)
+            );
+
+            assert!(MidiOutMapping::parse("x:mmc play").is_err());
+            assert!(MidiOutMapping::parse("mmc play/").is_err());
+            assert!(MidiOutMapping::parse("1:").is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(bytes: &[u8]) -> Vec<Message> {
+        let mut decoder = Decoder::default();
+        bytes.iter().filter_map(|&b| decoder.decode(b)).collect()
+    }
+
+    #[test]
+    fn decoding() {
+        let on = |note| Message::Note {
+            channel: 2,
+            note,
+            on: true,
+        };
+        let off = |note| Message::Note {
+            channel: 2,
+            note,
+            on: false,
+        };
+        // Running status, with a note on of velocity 0 as a note off.
+        assert_eq!(
+            decode_all(&[0x92, 60, 100, 62, 100, 60, 0, 0x82, 62, 64]),
+            vec![on(60), on(62), off(60), off(62)]
+        );
+        // Real-time messages in the middle of a message.
+        assert_eq!(
+            decode_all(&[0xb0, 0xf8, 64, 0xfe, 127, 0xc5, 3]),
+            vec![
+                Message::Control {
+                    channel: 0,
+                    controller: 64,
+                    value: 127
+                },
+                Message::Program {
+                    channel: 5,
+                    program: 3
+                }
+            ]
+        );
+        // System exclusive cancels running status, and its data is skipped.
+        assert_eq!(
+            decode_all(&[0x92, 60, 100, 0xf0, 0x7f, 60, 100, 0xf7, 60, 100]),
+            vec![on(60)]
+        );
+        // Data bytes without a status, and messages we don't map.
+        assert_eq!(decode_all(&[60, 100, 0xe0, 0, 64, 0xd0, 10]), vec![]);
+
+        let mut decoder = Decoder::default();
+        decoder.decode(0x92);
+        decoder.decode(60);
+        decoder.reset();
+        assert_eq!(decoder.decode(100), None);
+    }
+
+    fn mapping(options: &str) -> Mapping {
+        Mapping::from_options(&SourceOptions::parse(options, Mapping::OPTIONS).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn mappings() {
+        let cc = |controller, value| Message::Control {
+            channel: 0,
+            controller,
+            value,
+        };
+
+        let note = |note, on| Message::Note {
+            channel: 9,
+            note,
+            on,
+        };
+
+        // By default, any note and the sustain pedal press the button.
+        let mut m = mapping("");
+        assert_eq!(m.events(note(36, true)), vec![(1, InputEvent::Press)]);
+        assert_eq!(m.events(note(36, false)), vec![(1, InputEvent::Release)]);
+        assert_eq!(m.events(cc(64, 100)), vec![(1, InputEvent::Press)]);
+        // Only changes past the threshold are sent.
+        assert_eq!(m.events(cc(64, 90)), vec![]);
+        assert_eq!(m.events(cc(64, 63)), vec![(1, InputEvent::Release)]);
+        assert_eq!(m.events(cc(1, 127)), vec![]);
+
+        // Chords hold the button until the last note is released.
+        assert_eq!(m.events(note(60, true)), vec![(1, InputEvent::Press)]);
+        assert_eq!(m.events(note(64, true)), vec![]);
+        assert_eq!(m.events(note(64, true)), vec![]);
+        assert_eq!(m.events(note(60, false)), vec![]);
+        // As does the sustain pedal.
+        assert_eq!(m.events(cc(64, 127)), vec![]);
+        assert_eq!(m.events(note(64, false)), vec![]);
+        assert_eq!(m.events(cc(64, 0)), vec![(1, InputEvent::Release)]);
+        assert_eq!(m.events(note(64, false)), vec![]);
+
+        let mut m = mapping("note=60:1,note=62:2,note=64:1");
+        assert_eq!(m.events(note(60, true)), vec![(1, InputEvent::Press)]);
+        assert_eq!(m.events(note(62, true)), vec![(2, InputEvent::Press)]);
+        assert_eq!(m.events(note(64, true)), vec![]);
+        assert_eq!(m.events(note(62, false)), vec![(2, InputEvent::Release)]);
+        assert_eq!(m.events(note(60, false)), vec![]);
+        assert_eq!(m.events(note(64, false)), vec![(1, InputEvent::Release)]);
+
+        let mut m = mapping("channel=2,cc=4:2,program=5:3,threshold=10");
+        assert_eq!(m.events(cc(4, 127)), vec![]);
+        let cc4 = |value| Message::Control {
+            channel: 1,
+            controller: 4,
+            value,
+        };
+        assert_eq!(m.events(cc4(10)), vec![(2, InputEvent::Press)]);
+        m.reset();
+        assert_eq!(m.events(cc4(20)), vec![(2, InputEvent::Press)]);
+        assert_eq!(
+            m.events(Message::Program {
+                channel: 1,
+                program: 5
+            }),
+            vec![(3, InputEvent::Press), (3, InputEvent::Release)]
+        );
+        assert_eq!(
+            m.events(Message::Note {
+                channel: 1,
+                note: 60,
+                on: true
+            }),
+            vec![]
+        );
+
+        for bad in &[
+            "channel=0",
+            "channel=17",
+            "threshold=0",
+            "note=128",
+            "cc=x:1",
+        ] {
+            let options = SourceOptions::parse(bad, Mapping::OPTIONS).unwrap();
+            assert!(Mapping::from_options(&options).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+/*
+ * MQTT integration, which publishes the footswitch's state for home
+ * automation (eg: "on air" lights), and takes commands from a topic.
+ *
+ * Topics, under a prefix (default: `footswitch`):
+ *
+ * - `availability`: `online`, or `offline` (as the last will) when the client
+ *   goes away.
+ * - `state`: `ON` while the footswitch is held, otherwise `OFF`.
+ * - `force_mute`: `ON` while force mute is on, otherwise `OFF`.
+ * - `command`: takes the same commands as `--source stdin`, one per line.
+ *
+ * Everything we publish is retained.
+ */
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::actions::{Action, ActionError};
+use crate::input::{EventSender, InputSource};
+use crate::lines::parse_line;
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_TOPIC: &str = "footswitch";
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// Maximum number of requests waiting to be sent to the broker.
+const QUEUE_SIZE: usize = 16;
+
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Prefix for all of our topics.
+    pub topic: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Whether to publish Home Assistant discovery messages.
+    pub discovery: bool,
+}
+
+/// The latest payload for each topic we publish, which are published again
+/// whenever we (re)connect.
+type Retained = Arc<Mutex<BTreeMap<String, String>>>;
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+/// Home Assistant discovery messages, for a binary sensor showing whether the
+/// footswitch is held, and a switch for force mute.
+fn discovery_messages(topic: &str) -> Vec<(String, String)> {
+    // Home Assistant IDs can't have slashes.
+    let node = topic.replace('/', "_");
+    let device = json!({ "identifiers": [node], "name": "Footswitch" });
+    let availability = format!("{}/availability", topic);
+    vec![
+        (
+            format!("{}/binary_sensor/{}/state/config", DISCOVERY_PREFIX, node),
+            json!({
+                "name": "Footswitch",
+                "unique_id": format!("{}_state", node),
+                "state_topic": format!("{}/state", topic),
+                "availability_topic": availability,
+                "icon": "mdi:microphone",
+                "device": device,
+            })
+            .to_string(),
+        ),
+        (
+            format!("{}/switch/{}/force_mute/config", DISCOVERY_PREFIX, node),
+            json!({
+                "name": "Force mute",
+                "unique_id": format!("{}_force_mute", node),
+                "state_topic": format!("{}/force_mute", topic),
+                "command_topic": format!("{}/command", topic),
+                "payload_on": "force_mute on",
+                "payload_off": "force_mute off",
+                "state_on": "ON",
+                "state_off": "OFF",
+                "availability_topic": availability,
+                "icon": "mdi:microphone-off",
+                "device": device,
+            })
+            .to_string(),
+        ),
+    ]
+}
+
+/// Connects to an MQTT broker, returning an action which publishes the
+/// footswitch's state, and an input source which takes commands (and keeps
+/// the connection going).
+pub fn connect(config: MqttConfig) -> (MqttAction, MqttSource) {
+    let availability = format!("{}/availability", config.topic);
+    let mut options = MqttOptions::new(
+        format!("footswitch-{}", std::process::id()),
+        config.host.clone(),
+        config.port,
+    );
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        &availability,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = config.username.as_ref() {
+        options.set_credentials(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        );
+    }
+    let (client, connection) = Client::new(options, QUEUE_SIZE);
+
+    let mut retained = BTreeMap::new();
+    if config.discovery {
+        retained.extend(discovery_messages(&config.topic));
+    }
+    retained.insert(availability, "online".to_string());
+    retained.insert(format!("{}/state", config.topic), on_off(false).to_string());
+    retained.insert(
+        format!("{}/force_mute", config.topic),
+        on_off(false).to_string(),
+    );
+    let retained = Arc::new(Mutex::new(retained));
+
+    let action = MqttAction {
+        client: client.clone(),
+        topic: config.topic.clone(),
+        retained: retained.clone(),
+    };
+    let source = MqttSource {
+        client,
+        connection,
+        address: format!("{}:{}", config.host, config.port),
+        command_topic: format!("{}/command", config.topic),
+        retained,
+    };
+    (action, source)
+}
+
+/// Publishes the footswitch's state to MQTT.
+pub struct MqttAction {
+    client: Client,
+    topic: String,
+    retained: Retained,
+}
+
+impl MqttAction {
+    fn publish(&mut self, subtopic: &str, payload: &str) {
+        let topic = format!("{}/{}", self.topic, subtopic);
+        self.retained
+            .lock()
+            .unwrap()
+            .insert(topic.clone(), payload.to_string());
+        // If the queue is full, we're disconnected, and this is published
+        // when we reconnect.
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, true, payload)
+        {
+            debug!("Not publishing to MQTT yet: {}", e);
+        }
+    }
+}
+
+impl Action for MqttAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.publish("state", on_off(true));
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.publish("state", on_off(false));
+        Ok(())
+    }
+
+    fn force_mute(&mut self, muted: bool) -> Result<(), ActionError> {
+        self.publish("force_mute", on_off(muted));
+        Ok(())
+    }
+}
+
+/// Takes commands from MQTT, and runs the MQTT connection.
+pub struct MqttSource {
+    client: Client,
+    connection: rumqttc::Connection,
+    address: String,
+    command_topic: String,
+    retained: Retained,
+}
+
+/// Subscribes to the command topic, and publishes everything again, after
+/// (re)connecting.
+fn resubscribe(client: &Client, command_topic: &str, retained: &Retained) {
+    if let Err(e) = client.try_subscribe(command_topic, QoS::AtLeastOnce) {
+        warn!("Failed to subscribe to {}: {}", command_topic, e);
+    }
+    for (topic, payload) in retained.lock().unwrap().iter() {
+        if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload.as_str()) {
+            warn!("Failed to publish to {}: {}", topic, e);
+        }
+    }
+}
+
+impl InputSource for MqttSource {
+    fn name(&self) -> String {
+        format!("MQTT commands from: {}", self.command_topic)
+    }
+
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let MqttSource {
+            client,
+            mut connection,
+            address,
+            command_topic,
+            retained,
+        } = *self;
+        let mut connected = true;
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker at {}", address);
+                    connected = true;
+                    resubscribe(&client, &command_topic, &retained);
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) if p.topic == command_topic => {
+                    for line in String::from_utf8_lossy(&p.payload).lines() {
+                        match parse_line(line) {
+                            Ok(Some((button, event))) => {
+                                debug!("MQTT command: {}", line.trim());
+                                if !chan.send_button(button, event) {
+                                    return;
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("MQTT command: {}", e),
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if connected {
+                        warn!(
+                            "MQTT broker at {}: {}; will keep trying to connect",
+                            address, e
+                        );
+                        // Buttons pressed over MQTT can't be released now.
+                        if !chan.release() {
+                            return;
+                        }
+                    } else {
+                        debug!("MQTT: {}", e);
+                    }
+                    connected = false;
+                    thread::sleep(RECONNECT_INTERVAL);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{spawn_sources, InputEvent, SourceEvent};
+    use serde_json::Value;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    #[test]
+    fn discovery() {
+        let messages = discovery_messages("studio/footswitch");
+        assert_eq!(
+            messages.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>(),
+            vec![
+                "homeassistant/binary_sensor/studio_footswitch/state/config",
+                "homeassistant/switch/studio_footswitch/force_mute/config",
+            ]
+        );
+        let sensor: Value = serde_json::from_str(&messages[0].1).unwrap();
+        assert_eq!(sensor["unique_id"], "studio_footswitch_state");
+        assert_eq!(sensor["state_topic"], "studio/footswitch/state");
+        assert_eq!(
+            sensor["availability_topic"],
+            "studio/footswitch/availability"
+        );
+        let switch: Value = serde_json::from_str(&messages[1].1).unwrap();
+        assert_eq!(switch["command_topic"], "studio/footswitch/command");
+        assert_eq!(switch["state_topic"], "studio/footswitch/force_mute");
+        // Turning the switch on must be a command we understand.
+        assert_eq!(
+            parse_line(switch["payload_on"].as_str().unwrap()),
+            Ok(Some((1, InputEvent::ForceMute(true))))
+        );
+        assert_eq!(sensor["device"], switch["device"]);
+    }
+
+    /// Reads an MQTT packet, returning its fixed header byte and body.
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            stream.read_exact(&mut byte).ok()?;
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).ok()?;
+        Some((header, body))
+    }
+
+    /// A stand-in broker for one client, which acknowledges everything, and
+    /// sends each message the client publishes to the channel.
+    fn broker(listener: TcpListener, published: mpsc::Sender<(String, String, bool)>) -> TcpStream {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            while let Some((header, body)) = read_packet(&mut reader) {
+                let reply: Vec<u8> = match header >> 4 {
+                    // CONNECT -> CONNACK
+                    1 => vec![0x20, 2, 0, 0],
+                    // PUBLISH -> PUBACK
+                    3 => {
+                        let qos = (header >> 1) & 3;
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + topic_len]);
+                        let mut payload = 2 + topic_len;
+                        let mut reply = Vec::new();
+                        if qos > 0 {
+                            reply = vec![0x40, 2, body[payload], body[payload + 1]];
+                            payload += 2;
+                        }
+                        let payload = String::from_utf8_lossy(&body[payload..]);
+                        let retain = header & 1 != 0;
+                        let _ = published.send((topic.into_owned(), payload.into_owned(), retain));
+                        reply
+                    }
+                    // SUBSCRIBE -> SUBACK
+                    8 => vec![0x90, 3, body[0], body[1], 1],
+                    // PINGREQ -> PINGRESP
+                    12 => vec![0xd0, 0],
+                    _ => Vec::new(),
+                };
+                if reader.write_all(&reply).is_err() {
+                    break;
+                }
+            }
+        });
+        stream
+    }
+
+    #[test]
+    fn local_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut action, source) = connect(MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            topic: "test/footswitch".to_string(),
+            username: None,
+            password: None,
+            discovery: true,
+        });
+        let (tx, events) = mpsc::channel();
+        spawn_sources(vec![Box::new(source)], tx);
+        let (published_tx, published) = mpsc::channel();
+        let mut stream = broker(listener, published_tx);
+        let next = || {
+            published
+                .recv_timeout(Duration::from_secs(5))
+                .map(|(topic, payload, retain)| {
+                    assert!(retain, "{} isn't retained", topic);
+                    (topic, payload)
+                })
+                .unwrap()
+        };
+
+        // Everything is published after connecting.
+        let mut topics: Vec<_> = (0..5).map(|_| next()).collect();
+        topics.sort();
+        assert_eq!(
+            topics[0].0,
+            "homeassistant/binary_sensor/test_footswitch/state/config"
+        );
+        assert_eq!(
+            topics[1].0,
+            "homeassistant/switch/test_footswitch/force_mute/config"
+        );
+        assert_eq!(
+            topics[2..],
+            [
+                (
+                    "test/footswitch/availability".to_string(),
+                    "online".to_string()
+                ),
+                ("test/footswitch/force_mute".to_string(), "OFF".to_string()),
+                ("test/footswitch/state".to_string(), "OFF".to_string()),
+            ]
+        );
+
+        action.press().unwrap();
+        assert_eq!(
+            next(),
+            ("test/footswitch/state".to_string(), "ON".to_string())
+        );
+        action.force_mute(true).unwrap();
+        assert_eq!(
+            next(),
+            ("test/footswitch/force_mute".to_string(), "ON".to_string())
+        );
+
+        // Commands, with a PUBLISH to the command topic.
+        let topic = b"test/footswitch/command";
+        let payload = b"press 2\nbogus\nforce_mute on\n";
+        let mut packet = vec![
+            0x30,
+            (2 + topic.len() + payload.len()) as u8,
+            0,
+            topic.len() as u8,
+        ];
+        packet.extend_from_slice(topic);
+        packet.extend_from_slice(payload);
+        stream.write_all(&packet).unwrap();
+        let next_event = || {
+            let SourceEvent { button, event, .. } =
+                events.recv_timeout(Duration::from_secs(5)).unwrap();
+            (button, event)
+        };
+        assert_eq!(next_event(), (2, InputEvent::Press));
+        assert_eq!(next_event(), (1, InputEvent::ForceMute(true)));
+
+        // Buttons are released when the broker goes away.
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+        assert_eq!(next_event(), (2, InputEvent::Release));
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * Mumble RPC action, which starts and stops talking (or mutes) in Mumble
+ * directly, over the same local socket as `mumble rpc`.
+ */
+
+use std::env;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::actions::{Action, ActionError};
+use crate::ipc;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the footswitch changes in Mumble.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MumbleMode {
+    /// Talks while pressed, like Mumble's own push-to-talk.
+    Talk,
+    /// Unmutes while pressed, and mutes while released.
+    Mute,
+    /// Undeafens while pressed, and deafens (and mutes) while released.
+    Deaf,
+}
+
+impl MumbleMode {
+    /// The RPC commands for pressing and releasing the footswitch.
+    fn commands(self) -> (&'static str, &'static str) {
+        match self {
+            MumbleMode::Talk => ("starttalking", "stoptalking"),
+            MumbleMode::Mute => ("unmute", "mute"),
+            MumbleMode::Deaf => ("undeaf", "deaf"),
+        }
+    }
+}
+
+/// Finds Mumble's RPC socket. Newer versions of Mumble put it in
+/// `$XDG_RUNTIME_DIR`, and older ones in the home directory.
+fn socket_path() -> PathBuf {
+    if cfg!(windows) {
+        return PathBuf::from(r"\\.\pipe\MumbleSocket");
+    }
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .map(|d| d.join("MumbleSocket"))
+        .filter(|p| p.exists())
+        .or_else(|| dirs::home_dir().map(|d| d.join(".MumbleSocket")))
+        .unwrap_or_else(|| PathBuf::from("MumbleSocket"))
+}
+
+/// Sends an RPC command to Mumble, eg: `<self><mute>true</mute></self>`.
+///
+/// Mumble replies with `<reply><succeeded>true</succeeded></reply>`. Older
+/// versions don't reply, so only a failure is an error. With `wait`, this
+/// waits for the reply, and returns whether there was one.
+fn send_command(path: &Path, command: &str, wait: bool) -> Result<bool, String> {
+    let mut stream =
+        ipc::connect(path).map_err(|e| format!("Failed to connect to Mumble: {}", e))?;
+    stream
+        .write_all(format!("<self><{0}>true</{0}></self>", command).as_bytes())
+        .map_err(|e| format!("Error writing to Mumble: {}", e))?;
+    if !wait {
+        return Ok(false);
+    }
+
+    stream
+        .set_timeout(REPLY_TIMEOUT)
+        .map_err(|e| e.to_string())?;
+    let mut reply = Vec::new();
+    let mut buf = [0; 256];
+    while let Ok(len @ 1..) = stream.read(&mut buf) {
+        reply.extend_from_slice(&buf[..len]);
+        if reply.ends_with(b"</reply>") {
+            break;
+        }
+    }
+    if String::from_utf8_lossy(&reply).contains("<succeeded>false</succeeded>") {
+        return Err(format!("Mumble {} failed", command));
+    }
+    Ok(!reply.is_empty())
+}
+
+struct Worker {
+    /// Mumble's RPC socket, or `None` to find it.
+    path: Option<PathBuf>,
+    mode: MumbleMode,
+    pressed: bool,
+    /// Whether we couldn't send the last command.
+    failed: bool,
+    /// Whether to wait for replies, which older versions of Mumble don't
+    /// send.
+    wait: bool,
+}
+
+impl Worker {
+    /// Sends `command`, or returns why that failed.
+    fn send(&mut self, command: &str) -> Result<(), String> {
+        let path = self.path.clone().unwrap_or_else(socket_path);
+        if !send_command(&path, command, self.wait)? && self.wait {
+            debug!("Mumble didn't reply; not waiting for replies any more");
+            self.wait = false;
+        }
+        if self.failed {
+            info!("Connected to Mumble at {}", path.display());
+            self.failed = false;
+        }
+        Ok(())
+    }
+
+    /// Sets Mumble's state to match the footswitch.
+    fn apply(&mut self) {
+        let (press, release) = self.mode.commands();
+        let command = if self.pressed { press } else { release };
+        if let Err(e) = self.send(command) {
+            if self.failed {
+                debug!("{}", e);
+            } else {
+                warn!("{}; will keep trying", e);
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Follows the footswitch's state from `states`, retrying while Mumble
+    /// isn't there, until `states` is closed.
+    ///
+    /// `_running` is dropped when this finishes.
+    fn run(mut self, states: Receiver<bool>, _running: Sender<()>) {
+        self.apply();
+        loop {
+            let timeout = if self.failed {
+                RETRY_INTERVAL
+            } else {
+                // Nothing to do until the footswitch changes.
+                Duration::MAX
+            };
+            match states.recv_timeout(timeout) {
+                Ok(pressed) => {
+                    // Skip over states that have already been replaced.
+                    self.pressed = states.try_iter().last().unwrap_or(pressed);
+                    self.apply();
+                }
+                Err(RecvTimeoutError::Timeout) => self.apply(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        // Don't leave Mumble transmitting. Muting and deafening are left as
+        // they are, as we can't find out what they were before.
+        if self.mode == MumbleMode::Talk && self.pressed {
+            let _ = self.send("stoptalking");
+        }
+    }
+}
+
+/// Starts and stops talking (or mutes and unmutes) in Mumble when the
+/// footswitch changes state.
+///
+/// Mumble is driven from another thread, so that a slow or missing Mumble
+/// never holds up the footswitch. If Mumble isn't running, this keeps trying,
+/// and catches Mumble up with the footswitch when it comes back.
+pub struct MumbleAction {
+    /// Footswitch states for the worker to apply.
+    states: Option<Sender<bool>>,
+    /// Disconnected when the worker has finished.
+    running: Receiver<()>,
+}
+
+impl MumbleAction {
+    pub fn new(path: Option<PathBuf>, mode: MumbleMode) -> Self {
+        let worker = Worker {
+            path,
+            mode,
+            pressed: false,
+            failed: false,
+            wait: true,
+        };
+        let (states, rx) = mpsc::channel();
+        let (running_tx, running) = mpsc::channel();
+        thread::spawn(move || worker.run(rx, running_tx));
+        MumbleAction {
+            states: Some(states),
+            running,
+        }
+    }
+
+    fn send(&mut self, pressed: bool) {
+        if let Some(states) = self.states.as_ref() {
+            let _ = states.send(pressed);
+        }
+    }
+}
+
+impl Action for MumbleAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.send(true);
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.send(false);
+        Ok(())
+    }
+}
+
+impl Drop for MumbleAction {
+    fn drop(&mut self) {
+        // Give the worker a chance to stop talking.
+        self.states = None;
+        let _ = self.running.recv_timeout(REPLY_TIMEOUT);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::time::Instant;
+
+    /// A stand-in for an older version of Mumble, which doesn't reply to
+    /// commands. Each command is sent to `commands`.
+    fn serve(listener: UnixListener, commands: mpsc::Sender<String>) {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut command = Vec::new();
+            let mut buf = [0; 256];
+            while !command.ends_with(b"</self>") {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    len => command.extend_from_slice(&buf[..len]),
+                }
+            }
+            if commands.send(String::from_utf8(command).unwrap()).is_err() {
+                return;
+            }
+            // Hold the connection open, so the client has to give up waiting.
+            let _ = stream.read(&mut buf);
+        }
+    }
+
+    #[test]
+    fn without_replies() {
+        let dir = env::temp_dir().join(format!("footswitch-mumble-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("MumbleSocket");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (commands_tx, commands) = mpsc::channel();
+        thread::spawn(move || serve(listener, commands_tx));
+        let next = || commands.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let started = Instant::now();
+        let mut action = MumbleAction::new(Some(path), MumbleMode::Talk);
+        // The footswitch doesn't wait for Mumble.
+        action.press().unwrap();
+        assert!(started.elapsed() < REPLY_TIMEOUT);
+
+        assert_eq!(next(), "<self><stoptalking>true</stoptalking></self>");
+        assert_eq!(next(), "<self><starttalking>true</starttalking></self>");
+        // Once Mumble hasn't replied, commands are sent without waiting.
+        let started = Instant::now();
+        action.release().unwrap();
+        assert_eq!(next(), "<self><stoptalking>true</stoptalking></self>");
+        action.press().unwrap();
+        assert_eq!(next(), "<self><starttalking>true</starttalking></self>");
+        assert!(started.elapsed() < REPLY_TIMEOUT);
+
+        // Mumble is left not talking.
+        drop(action);
+        assert_eq!(next(), "<self><stoptalking>true</stoptalking></self>");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,678 @@
+/*
+ * Network input sources, for wireless footswitches (eg: ESP32-based).
+ *
+ * These accept the serial protocol (see ../serial/README.md) over UDP or
+ * TCP, optionally signed with a shared secret. Footswitches found with mDNS
+ * are subscribed to over UDP.
+ *
+ * Signed messages carry a timestamp, so that they can't be replayed.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::input::{EventSender, InputError, InputEvent, InputSource, SourceOptions, Threshold};
+use crate::mdns;
+use crate::serial::Decoder;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to ask a footswitch found with mDNS to send us events.
+const SUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to look up a quiet footswitch's address again.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Message which asks a footswitch to send us events.
+const SUBSCRIBE_MESSAGE: &[u8] = b"S";
+/// How often to check whether senders have gone quiet.
+const LIVENESS_CHECK: Duration = Duration::from_millis(250);
+/// Largest UDP datagram, TCP read, or signed message we'll handle.
+const MAX_MESSAGE_SIZE: usize = 1024;
+/// Signed messages with timestamps further than this from our clock are
+/// ignored.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+/// How long to wait after failing to receive a UDP packet, which doubles for
+/// each failure in a row, up to `MAX_RECV_BACKOFF`.
+const RECV_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(5);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A socket listening for footswitch events.
+enum Listener {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+}
+
+/// Something received by a `Listener`.
+enum Packet {
+    Data(SocketAddr, Vec<u8>),
+    Closed(SocketAddr),
+}
+
+impl Listener {
+    /// Receives packets on background threads, and sends them to `chan`.
+    fn spawn(self, chan: mpsc::Sender<Packet>) {
+        match self {
+            Listener::Udp(socket) => {
+                thread::spawn(move || {
+                    let mut buf = [0; MAX_MESSAGE_SIZE];
+                    let mut backoff = RECV_BACKOFF;
+                    loop {
+                        match socket.recv_from(&mut buf) {
+                            Ok((len, peer)) => {
+                                backoff = RECV_BACKOFF;
+                                if chan.send(Packet::Data(peer, buf[..len].to_vec())).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => {
+                                warn!("Error receiving UDP packet: {}", e);
+                                thread::sleep(backoff);
+                                backoff = (backoff * 2).min(MAX_RECV_BACKOFF);
+                            }
+                        }
+                    }
+                });
+            }
+            Listener::Tcp(listener) => {
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let chan = chan.clone();
+                                thread::spawn(move || read_stream(stream, chan));
+                            }
+                            Err(e) => warn!("Error accepting TCP connection: {}", e),
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Reads from a TCP connection until it closes.
+fn read_stream(mut stream: TcpStream, chan: mpsc::Sender<Packet>) {
+    let peer = match stream.peer_addr() {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let mut buf = [0; MAX_MESSAGE_SIZE];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(len) if len > 0 => {
+                if chan.send(Packet::Data(peer, buf[..len].to_vec())).is_err() {
+                    return;
+                }
+            }
+            _ => {
+                let _ = chan.send(Packet::Closed(peer));
+                return;
+            }
+        }
+    }
+}
+
+/// Current time, in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Signs a message, returning a line in the form
+/// `SIGNATURE TIMESTAMP MESSAGE\n`.
+fn sign(secret: &[u8], timestamp: u64, message: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{} ", timestamp).into_bytes();
+    payload.extend_from_slice(message);
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&payload);
+    let mut line = hex::encode(mac.finalize().into_bytes()).into_bytes();
+    line.push(b' ');
+    line.extend_from_slice(&payload);
+    line.push(b'\n');
+    line
+}
+
+/// Checks a hex-encoded HMAC-SHA256 signature of `payload`.
+fn verify(secret: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(payload);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Signatures of recently accepted messages, so that they can't be replayed
+/// from another address.
+#[derive(Default)]
+struct Seen {
+    /// Signature, and the message's timestamp.
+    signatures: HashMap<Vec<u8>, u64>,
+}
+
+impl Seen {
+    /// Checks that a signed message's timestamp is close to now, and that it
+    /// hasn't been seen before. Returns an error explaining why not.
+    fn check(&mut self, signature: &[u8], timestamp: u64) -> Result<(), &'static str> {
+        let now = now_millis();
+        let skew = MAX_CLOCK_SKEW.as_millis() as u64;
+        if timestamp.abs_diff(now) > skew {
+            return Err("a timestamp too far from our clock");
+        }
+        // Messages this old are rejected by their timestamp anyway.
+        self.signatures.retain(|_, &mut t| t + skew >= now);
+        // Signatures are hex, which could be sent in either case.
+        let signature = signature.to_ascii_lowercase();
+        if self.signatures.insert(signature, timestamp).is_some() {
+            return Err("a replayed signature");
+        }
+        Ok(())
+    }
+}
+
+/// A footswitch sending us events.
+struct Sender {
+    decoder: Decoder,
+    pressed: bool,
+    /// When we last got a valid message.
+    last_seen: Instant,
+    /// Timestamp of the last signed message, which the next must be after.
+    last_timestamp: u64,
+    /// A signed message we're part way through reading.
+    buffer: Vec<u8>,
+}
+
+impl Sender {
+    fn new(threshold: Threshold) -> Self {
+        Sender {
+            decoder: Decoder::new(threshold),
+            pressed: false,
+            last_seen: Instant::now(),
+            last_timestamp: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Handles data from the sender, and adds any axis events to `events`.
+    fn receive(
+        &mut self,
+        peer: SocketAddr,
+        data: &[u8],
+        secret: Option<&[u8]>,
+        seen: &mut Seen,
+        events: &mut Vec<InputEvent>,
+    ) {
+        let secret = match secret {
+            Some(s) => s,
+            None => {
+                self.last_seen = Instant::now();
+                self.decode(peer, data, events);
+                return;
+            }
+        };
+
+        // Signed messages are one per line, in the form
+        // `SIGNATURE TIMESTAMP MESSAGE`.
+        self.buffer.extend_from_slice(data);
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = line.strip_suffix(b"\r\n").unwrap_or(&line[..end]);
+            let (signature, payload) = match line.iter().position(|&b| b == b' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (line, &[][..]),
+            };
+            if !verify(secret, payload, signature) {
+                warn!("Ignoring message with a bad signature from {}", peer);
+                continue;
+            }
+            let (timestamp, message) = match payload.iter().position(|&b| b == b' ') {
+                Some(i) => (&payload[..i], &payload[i + 1..]),
+                None => (payload, &[][..]),
+            };
+            let timestamp = match std::str::from_utf8(timestamp)
+                .ok()
+                .and_then(|t| t.parse::<u64>().ok())
+            {
+                Some(t) => t,
+                None => {
+                    warn!("Ignoring signed message without a timestamp from {}", peer);
+                    continue;
+                }
+            };
+            if timestamp <= self.last_timestamp {
+                warn!("Ignoring replayed or out of order message from {}", peer);
+                continue;
+            }
+            if let Err(e) = seen.check(signature, timestamp) {
+                warn!("Ignoring message with {} from {}", e, peer);
+                continue;
+            }
+            self.last_timestamp = timestamp;
+            self.last_seen = Instant::now();
+            self.decode(peer, message, events);
+            // The end of the line also ends any analog message.
+            self.decode(peer, b"\n", events);
+        }
+        if self.buffer.len() > MAX_MESSAGE_SIZE {
+            warn!("Ignoring message that is too long from {}", peer);
+            self.buffer.clear();
+        }
+    }
+
+    fn decode(&mut self, peer: SocketAddr, data: &[u8], events: &mut Vec<InputEvent>) {
+        let mut decoded = Vec::with_capacity(2);
+        for &b in data {
+            if let Err(e) = self.decoder.decode(b, &mut decoded) {
+                warn!("{} from {}", e, peer);
+            }
+        }
+        for e in decoded {
+            match e {
+                InputEvent::Press => self.pressed = true,
+                InputEvent::Release => self.pressed = false,
+                InputEvent::Axis(_)
+                | InputEvent::Delta(_)
+                | InputEvent::ForceMute(_)
+                | InputEvent::Mode(_) => events.push(e),
+            }
+        }
+    }
+}
+
+/// A footswitch found with mDNS, which we ask to send us events.
+struct Subscription {
+    /// The footswitch's DNS-SD instance name.
+    instance: String,
+    addr: SocketAddr,
+    socket: UdpSocket,
+    /// The result of looking up the footswitch's address, while we're
+    /// looking it up.
+    resolving: Option<mpsc::Receiver<Result<SocketAddr, String>>>,
+}
+
+impl Subscription {
+    fn subscribe(&self, secret: Option<&[u8]>) {
+        let message = match secret {
+            Some(s) => sign(s, now_millis(), SUBSCRIBE_MESSAGE),
+            None => SUBSCRIBE_MESSAGE.to_vec(),
+        };
+        if let Err(e) = self.socket.send_to(&message, self.addr) {
+            warn!("Error subscribing to {}: {}", self.instance, e);
+        }
+    }
+
+    /// Starts looking up the footswitch's address again, in case it changed.
+    ///
+    /// This takes a few seconds, so it's done on another thread.
+    fn resolve(&mut self) {
+        if self.resolving.is_some() {
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        let instance = self.instance.clone();
+        thread::spawn(move || {
+            let _ = tx.send(mdns::resolve(&instance));
+        });
+        self.resolving = Some(rx);
+    }
+
+    /// Picks up the footswitch's new address, if we've finished looking it up.
+    fn check_resolved(&mut self) {
+        let result = match self.resolving.as_ref().map(|r| r.try_recv()) {
+            None | Some(Err(mpsc::TryRecvError::Empty)) => return,
+            Some(Ok(result)) => result,
+            Some(Err(mpsc::TryRecvError::Disconnected)) => {
+                Err(format!("Looking up {} stopped unexpectedly", self.instance))
+            }
+        };
+        self.resolving = None;
+        match result {
+            Ok(addr) if addr != self.addr => {
+                info!("Footswitch {} moved to {}", self.instance, addr);
+                self.addr = addr;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Options shared by all network sources.
+struct NetOptions {
+    secret: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    threshold: Threshold,
+}
+
+impl NetOptions {
+    fn parse(options: &str) -> Result<Self, InputError> {
+        let options = SourceOptions::parse(
+            options,
+            &["secret", "secret_file", "timeout", "press", "release"],
+        )?;
+        let secret = match (options.get("secret"), options.get("secret_file")) {
+            (Some(s), _) => Some(s.as_bytes().to_vec()),
+            (None, Some(path)) => Some(
+                fs::read_to_string(path)
+                    .map_err(|e| InputError {
+                        msg: format!("Failed to read {}: {}", path, e),
+                    })?
+                    .trim()
+                    .as_bytes()
+                    .to_vec(),
+            ),
+            (None, None) => None,
+        };
+        if secret.as_ref().is_some_and(|s| s.is_empty()) {
+            return Err(InputError {
+                msg: "Shared secret is empty".to_string(),
+            });
+        }
+
+        let timeout = match options.get("timeout") {
+            None => Some(DEFAULT_TIMEOUT),
+            Some(t) => match t.parse::<u64>() {
+                Ok(0) => None,
+                Ok(t) => Some(Duration::from_millis(t)),
+                Err(_) => {
+                    return Err(InputError {
+                        msg: format!("Invalid timeout {:?}", t),
+                    })
+                }
+            },
+        };
+        let threshold = Threshold::from_options(&options)?;
+        Ok(NetOptions {
+            secret,
+            timeout,
+            threshold,
+        })
+    }
+}
+
+/// Receives footswitch events over the network.
+///
+/// The footswitch is pressed while any sender has it pressed. A sender is
+/// released if it disconnects, or doesn't send anything for a while.
+pub struct NetSource {
+    addr: SocketAddr,
+    listener: Listener,
+    subscription: Option<Subscription>,
+    options: NetOptions,
+}
+
+impl NetSource {
+    /// Creates a network source from options in the form
+    /// `ADDRESS[,secret=SECRET|secret_file=PATH][,timeout=MSEC]`.
+    ///
+    /// `ADDRESS` may be just a port number, to listen on all addresses.
+    pub fn new(tcp: bool, options: &str) -> Result<Self, InputError> {
+        let (addr, options) = options.split_once(',').unwrap_or((options, ""));
+        let options = NetOptions::parse(options)?;
+        let addr = match addr.parse::<u16>() {
+            Ok(port) => SocketAddr::from(([0, 0, 0, 0], port)),
+            Err(_) => addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut a| a.next())
+                .ok_or_else(|| InputError {
+                    msg: format!("Invalid address {:?}", addr),
+                })?,
+        };
+
+        let error = |e| InputError {
+            msg: format!("Failed to listen on {}: {}", addr, e),
+        };
+        let listener = if tcp {
+            Listener::Tcp(TcpListener::bind(addr).map_err(error)?)
+        } else {
+            Listener::Udp(UdpSocket::bind(addr).map_err(error)?)
+        };
+        Ok(NetSource {
+            addr,
+            listener,
+            subscription: None,
+            options,
+        })
+    }
+
+    /// Creates a network source for a footswitch found with mDNS, from
+    /// options in the form `INSTANCE[,secret=SECRET|secret_file=PATH][,timeout=MSEC]`.
+    pub fn with_mdns(options: &str) -> Result<Self, InputError> {
+        let (instance, options) = options.split_once(',').unwrap_or((options, ""));
+        let options = NetOptions::parse(options)?;
+        if instance.is_empty() {
+            return Err(InputError {
+                msg: "No footswitch name specified".to_string(),
+            });
+        }
+        let addr = mdns::resolve(instance).map_err(|msg| InputError { msg })?;
+
+        let error = |e| InputError {
+            msg: format!("Failed to open UDP socket: {}", e),
+        };
+        let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).map_err(error)?;
+        let subscription = Subscription {
+            instance: instance.to_string(),
+            addr,
+            socket: socket.try_clone().map_err(error)?,
+            resolving: None,
+        };
+        Ok(NetSource {
+            addr: socket.local_addr().map_err(error)?,
+            listener: Listener::Udp(socket),
+            subscription: Some(subscription),
+            options,
+        })
+    }
+}
+
+impl InputSource for NetSource {
+    fn name(&self) -> String {
+        format!(
+            "{}{}",
+            match (&self.subscription, &self.listener) {
+                (Some(s), _) => format!("Network footswitch: {} ({})", s.instance, s.addr),
+                (None, Listener::Udp(_)) => format!("UDP listener: {}", self.addr),
+                (None, Listener::Tcp(_)) => format!("TCP listener: {}", self.addr),
+            },
+            if self.options.secret.is_some() {
+                " (signed)"
+            } else {
+                ""
+            }
+        )
+    }
+
+    /// Sends events from all senders to the channel.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let NetSource {
+            listener,
+            mut subscription,
+            options:
+                NetOptions {
+                    secret,
+                    timeout,
+                    threshold,
+                },
+            ..
+        } = *self;
+        let (tx, rx) = mpsc::channel();
+        listener.spawn(tx);
+
+        let mut senders: HashMap<SocketAddr, Sender> = HashMap::new();
+        let mut seen = Seen::default();
+        let mut pressed = false;
+        let mut events = Vec::new();
+        let mut last_subscribed: Option<Instant> = None;
+        let mut last_resolved = Instant::now();
+
+        loop {
+            if let Some(s) = &mut subscription {
+                s.check_resolved();
+                if !senders.contains_key(&s.addr) && last_resolved.elapsed() >= RESOLVE_INTERVAL {
+                    s.resolve();
+                    last_resolved = Instant::now();
+                }
+                if last_subscribed.is_none_or(|t| t.elapsed() >= SUBSCRIBE_INTERVAL) {
+                    s.subscribe(secret.as_deref());
+                    last_subscribed = Some(Instant::now());
+                }
+            }
+
+            match rx.recv_timeout(LIVENESS_CHECK) {
+                Ok(Packet::Data(peer, data)) => {
+                    let sender = senders.entry(peer).or_insert_with(|| {
+                        info!("New footswitch sender: {}", peer);
+                        Sender::new(threshold.clone())
+                    });
+                    sender.receive(peer, &data, secret.as_deref(), &mut seen, &mut events);
+                }
+                Ok(Packet::Closed(peer)) => {
+                    info!("Footswitch sender disconnected: {}", peer);
+                    senders.remove(&peer);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    error!("Network listener stopped");
+                    return;
+                }
+            }
+
+            // Fail safe: forget senders that have gone quiet.
+            if let Some(timeout) = timeout {
+                senders.retain(|peer, s| {
+                    let alive = s.last_seen.elapsed() < timeout;
+                    if !alive {
+                        warn!("Footswitch sender went quiet: {}", peer);
+                    }
+                    alive
+                });
+            }
+
+            let now_pressed = senders.values().any(|s| s.pressed);
+            if now_pressed != pressed {
+                pressed = now_pressed;
+                events.push(if pressed {
+                    InputEvent::Press
+                } else {
+                    InputEvent::Release
+                });
+            }
+            for e in events.drain(..) {
+                if !chan.send(e) {
+                    // Other end of the channel has probably gone away.
+                    // Shut down the thread.
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"hunter2";
+
+    fn sender() -> Sender {
+        let options = SourceOptions::parse("", &[]).unwrap();
+        Sender::new(Threshold::from_options(&options).unwrap())
+    }
+
+    /// Sends a line to a sender, and returns whether it was accepted.
+    fn receive(sender: &mut Sender, seen: &mut Seen, line: &[u8]) -> bool {
+        let before = sender.last_timestamp;
+        let peer = SocketAddr::from(([127, 0, 0, 1], 5555));
+        sender.receive(peer, line, Some(SECRET), seen, &mut Vec::new());
+        sender.last_timestamp != before
+    }
+
+    #[test]
+    fn signed_messages() {
+        let mut seen = Seen::default();
+        let mut s = sender();
+        let now = now_millis();
+        assert!(receive(&mut s, &mut seen, &sign(SECRET, now, b"1")));
+        assert!(s.pressed);
+        assert!(receive(&mut s, &mut seen, &sign(SECRET, now + 1, b"0")));
+        assert!(!s.pressed);
+        assert!(!receive(
+            &mut s,
+            &mut seen,
+            &sign(b"hunter3", now + 2, b"1")
+        ));
+        assert!(!s.pressed);
+    }
+
+    #[test]
+    fn replays() {
+        let mut seen = Seen::default();
+        let mut s = sender();
+        let now = now_millis();
+        let press = sign(SECRET, now, b"1");
+        assert!(receive(&mut s, &mut seen, &press));
+        assert!(receive(&mut s, &mut seen, &sign(SECRET, now + 1, b"0")));
+
+        // From the same sender, and from another address.
+        assert!(!receive(&mut s, &mut seen, &press));
+        assert!(!s.pressed);
+        assert!(!receive(&mut sender(), &mut seen, &press));
+        assert!(!receive(
+            &mut sender(),
+            &mut seen,
+            &press.to_ascii_uppercase()
+        ));
+
+        // Messages from too long ago, or without a timestamp.
+        let old = now - MAX_CLOCK_SKEW.as_millis() as u64 - 1;
+        assert!(!receive(&mut sender(), &mut seen, &sign(SECRET, old, b"1")));
+        let mut mac = HmacSha256::new_from_slice(SECRET).unwrap();
+        mac.update(b"1");
+        let unstamped = format!("{} 1\n", hex::encode(mac.finalize().into_bytes()));
+        assert!(!receive(&mut sender(), &mut seen, unstamped.as_bytes()));
+    }
+
+    #[test]
+    fn resolved_addresses() {
+        let old = SocketAddr::from(([192, 168, 1, 20], 5555));
+        let mut s = Subscription {
+            instance: "Studio 1 pedal".to_string(),
+            addr: old,
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            resolving: None,
+        };
+        let (tx, rx) = mpsc::channel();
+        s.resolving = Some(rx);
+
+        // Still looking.
+        s.check_resolved();
+        assert!(s.resolving.is_some());
+
+        let new = SocketAddr::from(([192, 168, 1, 21], 5555));
+        tx.send(Ok(new)).unwrap();
+        s.check_resolved();
+        assert_eq!(s.addr, new);
+        assert!(s.resolving.is_none());
+
+        // Failed lookups keep the old address.
+        let (tx, rx) = mpsc::channel();
+        s.resolving = Some(rx);
+        drop(tx);
+        s.check_resolved();
+        assert_eq!(s.addr, new);
+        assert!(s.resolving.is_none());
+    }
+}
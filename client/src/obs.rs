@@ -0,0 +1,577 @@
+/*
+ * OBS Studio action, which mutes OBS audio inputs and switches scenes over
+ * obs-websocket (protocol version 5, built in to OBS 28 and later).
+ */
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tungstenite::{Message, WebSocket};
+
+use crate::actions::{Action, ActionError};
+
+/// obs-websocket's default address.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:4455";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often to check that OBS is still there, so that we can catch it up
+/// with the footswitch if it restarts.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const RPC_VERSION: u64 = 1;
+
+// Message opcodes.
+const OP_HELLO: u64 = 0;
+const OP_IDENTIFY: u64 = 1;
+const OP_IDENTIFIED: u64 = 2;
+const OP_REQUEST_BATCH: u64 = 8;
+const OP_REQUEST_BATCH_RESPONSE: u64 = 9;
+
+/// Answers obs-websocket's authentication challenge.
+fn authenticate(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = BASE64.encode(Sha256::digest(format!("{}{}", password, salt)));
+    BASE64.encode(Sha256::digest(format!("{}{}", secret, challenge)))
+}
+
+pub struct ObsConfig {
+    pub addr: SocketAddr,
+    pub password: Option<String>,
+    /// Audio inputs to unmute while the footswitch is pressed.
+    pub inputs: Vec<String>,
+    /// Scene to switch to on a long press.
+    pub scene: Option<String>,
+    pub long_press: Duration,
+}
+
+struct Connection {
+    ws: WebSocket<TcpStream>,
+    next_id: u64,
+    /// Whether reading or writing has failed, rather than a request.
+    broken: bool,
+}
+
+impl Connection {
+    fn send(&mut self, op: u64, d: Value) -> Result<(), String> {
+        let msg = json!({ "op": op, "d": d }).to_string();
+        trace!("OBS <- {}", msg);
+        self.ws.send(Message::Text(msg)).map_err(|e| {
+            self.broken = true;
+            format!("Error writing to OBS: {}", e)
+        })
+    }
+
+    /// Waits for a message with opcode `op`, and returns its data.
+    fn recv(&mut self, op: u64) -> Result<Value, String> {
+        loop {
+            let msg = match self.ws.read() {
+                Ok(Message::Text(t)) => t,
+                Ok(Message::Close(frame)) => {
+                    self.broken = true;
+                    return Err(match frame {
+                        Some(f) if !f.reason.is_empty() => {
+                            format!("OBS closed the connection: {}", f.reason)
+                        }
+                        _ => "OBS closed the connection".to_string(),
+                    });
+                }
+                // Pings are answered by tungstenite.
+                Ok(_) => continue,
+                Err(e) => {
+                    self.broken = true;
+                    return Err(format!("Error reading from OBS: {}", e));
+                }
+            };
+            trace!("OBS -> {}", msg);
+            let mut msg: Value = serde_json::from_str(&msg).map_err(|e| {
+                self.broken = true;
+                format!("Invalid message from OBS: {}", e)
+            })?;
+            // Skip events, and replies to requests we gave up on.
+            if msg["op"].as_u64() == Some(op) {
+                return Ok(msg["d"].take());
+            }
+        }
+    }
+
+    /// Connects to obs-websocket, and identifies (with a password, if OBS
+    /// asks for one).
+    fn open(addr: &SocketAddr, password: Option<&str>) -> Result<Self, String> {
+        let stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)
+            .map_err(|e| format!("Failed to connect to OBS at {}: {}", addr, e))?;
+        stream
+            .set_read_timeout(Some(REPLY_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let (ws, _) = tungstenite::client(format!("ws://{}", addr), stream)
+            .map_err(|e| format!("Failed to connect to OBS at {}: {}", addr, e))?;
+        let mut conn = Connection {
+            ws,
+            next_id: 0,
+            broken: false,
+        };
+
+        let hello = conn.recv(OP_HELLO)?;
+        let mut identify = json!({ "rpcVersion": RPC_VERSION, "eventSubscriptions": 0 });
+        if let Some(auth) = hello.get("authentication") {
+            let password = password.ok_or("OBS needs a password (--obs_password)")?;
+            identify["authentication"] = json!(authenticate(
+                password,
+                auth["salt"].as_str().unwrap_or_default(),
+                auth["challenge"].as_str().unwrap_or_default(),
+            ));
+        }
+        conn.send(OP_IDENTIFY, identify)?;
+        conn.recv(OP_IDENTIFIED)?;
+        Ok(conn)
+    }
+
+    /// Sends several requests at once, and returns each one's response data,
+    /// or why it failed.
+    fn batch(
+        &mut self,
+        requests: Vec<(&str, Value)>,
+    ) -> Result<Vec<Result<Value, String>>, String> {
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        let requests: Vec<Value> = requests
+            .into_iter()
+            .map(|(t, d)| json!({ "requestType": t, "requestData": d }))
+            .collect();
+        self.send(
+            OP_REQUEST_BATCH,
+            json!({ "requestId": id, "haltOnFailure": false, "requests": requests }),
+        )?;
+        let mut reply = loop {
+            let reply = self.recv(OP_REQUEST_BATCH_RESPONSE)?;
+            if reply["requestId"] == id.as_str() {
+                break reply;
+            }
+        };
+        let results = match reply["results"].take() {
+            Value::Array(r) => r,
+            _ => return Err("No results in OBS response".to_string()),
+        };
+        Ok(results
+            .into_iter()
+            .map(|mut r| {
+                if r["requestStatus"]["result"] == true {
+                    Ok(r["responseData"].take())
+                } else {
+                    Err(format!(
+                        "OBS {} failed: {}",
+                        r["requestType"].as_str().unwrap_or_default(),
+                        r["requestStatus"]["comment"]
+                            .as_str()
+                            .unwrap_or("unknown error")
+                    ))
+                }
+            })
+            .collect())
+    }
+
+    /// Sends a single request, and returns its response data.
+    fn request(&mut self, request_type: &str, data: Value) -> Result<Value, String> {
+        self.batch(vec![(request_type, data)])?
+            .pop()
+            .unwrap_or_else(|| Err("No results in OBS response".to_string()))
+    }
+
+    /// Sets inputs' mute states.
+    fn set_mutes<'a, I>(&mut self, mutes: I) -> Result<(), String>
+    where
+        I: Iterator<Item = (&'a String, bool)>,
+    {
+        let requests = mutes
+            .map(|(input, muted)| {
+                (
+                    "SetInputMute",
+                    json!({ "inputName": input, "inputMuted": muted }),
+                )
+            })
+            .collect();
+        for r in self.batch(requests)? {
+            if let Err(e) = r {
+                warn!("{}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Something for the worker to do.
+enum Request {
+    /// Set the inputs' mute states for the footswitch being pressed or
+    /// released.
+    Pressed(bool),
+    /// Switch to the long press scene.
+    SwitchScene,
+}
+
+struct Worker {
+    config: ObsConfig,
+    conn: Option<Connection>,
+    pressed: bool,
+    /// Each input's mute state before we changed it.
+    initial: HashMap<String, bool>,
+}
+
+impl Worker {
+    fn connect(&mut self) -> Result<(), String> {
+        let conn = Connection::open(&self.config.addr, self.config.password.as_deref())?;
+        info!("Connected to OBS at {}", self.config.addr);
+        self.conn = Some(conn);
+        self.apply();
+        Ok(())
+    }
+
+    /// Warns about a failed request, and drops the connection if it broke.
+    fn request_failed(&mut self, e: String) {
+        warn!("{}", e);
+        if self.conn.as_ref().is_some_and(|c| c.broken) {
+            warn!("Lost connection to OBS");
+            self.conn = None;
+        }
+    }
+
+    /// Sets the inputs' mute states to match the footswitch.
+    fn apply(&mut self) {
+        let conn = match self.conn.as_mut() {
+            Some(c) => c,
+            None => return,
+        };
+        if self.config.inputs.is_empty() {
+            return;
+        }
+
+        // Remember the mute states of inputs we haven't seen before, so they
+        // can be restored on exit.
+        let initial = &mut self.initial;
+        let unknown: Vec<&String> = self
+            .config
+            .inputs
+            .iter()
+            .filter(|i| !initial.contains_key(*i))
+            .collect();
+        if !unknown.is_empty() {
+            let requests = unknown
+                .iter()
+                .map(|i| ("GetInputMute", json!({ "inputName": i })))
+                .collect();
+            match conn.batch(requests) {
+                Ok(results) => {
+                    for (input, r) in unknown.into_iter().zip(results) {
+                        // If this fails, setting it will fail too, and warn
+                        // about it.
+                        if let Ok(Some(muted)) = r.map(|d| d["inputMuted"].as_bool()) {
+                            initial.insert(input.clone(), muted);
+                        }
+                    }
+                }
+                Err(e) => return self.request_failed(e),
+            }
+        }
+
+        let muted = !self.pressed;
+        if let Err(e) = conn.set_mutes(self.config.inputs.iter().map(|i| (i, muted))) {
+            self.request_failed(e);
+        }
+    }
+
+    /// Switches to the long press scene.
+    fn switch_scene(&mut self) {
+        let (conn, scene) = match (self.conn.as_mut(), self.config.scene.as_ref()) {
+            (Some(c), Some(s)) => (c, s),
+            _ => return,
+        };
+        info!("Long press: switching OBS to scene {}", scene);
+        if let Err(e) = conn.request("SetCurrentProgramScene", json!({ "sceneName": scene })) {
+            self.request_failed(e);
+        }
+    }
+
+    /// Checks that OBS is still there, so that we can catch it up with the
+    /// footswitch if it restarts.
+    fn keepalive(&mut self) {
+        if let Some(conn) = self.conn.as_mut() {
+            if let Err(e) = conn.request("GetVersion", json!({})) {
+                self.request_failed(e);
+            }
+        }
+    }
+
+    /// Puts back the inputs' original mute states.
+    fn restore(&mut self) {
+        if let (Some(conn), false) = (self.conn.as_mut(), self.initial.is_empty()) {
+            let _ = conn.set_mutes(self.initial.iter().map(|(i, m)| (i, *m)));
+        }
+    }
+
+    /// Handles `requests`, reconnecting whenever OBS goes away, until
+    /// `requests` is closed.
+    ///
+    /// `_running` is dropped when this finishes.
+    fn run(mut self, requests: Receiver<Request>, _running: Sender<()>) {
+        let mut first = true;
+        loop {
+            if self.conn.is_none() {
+                match self.connect() {
+                    Err(e) if first => warn!("{}; will keep trying to connect", e),
+                    Err(e) => debug!("{}", e),
+                    Ok(()) => {}
+                }
+                first = false;
+            }
+            let timeout = if self.conn.is_some() {
+                KEEPALIVE_INTERVAL
+            } else {
+                RECONNECT_INTERVAL
+            };
+            let first = match requests.recv_timeout(timeout) {
+                Ok(r) => r,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.keepalive();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            // Only apply the latest footswitch state.
+            let mut pressed = None;
+            let mut switch_scene = false;
+            for r in std::iter::once(first).chain(requests.try_iter()) {
+                match r {
+                    Request::Pressed(p) => pressed = Some(p),
+                    Request::SwitchScene => switch_scene = true,
+                }
+            }
+            if let Some(p) = pressed {
+                self.pressed = p;
+                self.apply();
+            }
+            if switch_scene {
+                self.switch_scene();
+            }
+        }
+        self.restore();
+    }
+}
+
+/// Unmutes OBS audio inputs while the footswitch is pressed, and switches
+/// scene when it's held down.
+///
+/// OBS is driven from another thread, so that a slow or missing OBS never
+/// holds up the footswitch. If OBS goes away, this keeps trying to reconnect,
+/// and applies the footswitch's current state when it comes back.
+pub struct ObsAction {
+    /// Requests for the worker.
+    requests: Option<Sender<Request>>,
+    /// Disconnected when the worker has finished.
+    running: Receiver<()>,
+    has_scene: bool,
+    long_press: Duration,
+    /// When the footswitch was pressed, if it's still held down.
+    pressed_at: Option<Instant>,
+    /// Whether we've switched scene for this press.
+    long_pressed: bool,
+}
+
+impl ObsAction {
+    pub fn new(config: ObsConfig) -> Self {
+        let has_scene = config.scene.is_some();
+        let long_press = config.long_press;
+        let worker = Worker {
+            config,
+            conn: None,
+            pressed: false,
+            initial: HashMap::new(),
+        };
+        let (requests, rx) = mpsc::channel();
+        let (running_tx, running) = mpsc::channel();
+        thread::spawn(move || worker.run(rx, running_tx));
+        ObsAction {
+            requests: Some(requests),
+            running,
+            has_scene,
+            long_press,
+            pressed_at: None,
+            long_pressed: false,
+        }
+    }
+
+    fn send(&mut self, request: Request) {
+        if let Some(requests) = self.requests.as_ref() {
+            let _ = requests.send(request);
+        }
+    }
+}
+
+impl Action for ObsAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.pressed_at = Some(Instant::now());
+        self.long_pressed = false;
+        self.send(Request::Pressed(true));
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.pressed_at = None;
+        self.send(Request::Pressed(false));
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<Option<Duration>, ActionError> {
+        let pressed_at = match (self.pressed_at, self.has_scene, self.long_pressed) {
+            (Some(p), true, false) => p,
+            _ => return Ok(None),
+        };
+        match self.long_press.checked_sub(pressed_at.elapsed()) {
+            Some(t) if !t.is_zero() => Ok(Some(t)),
+            _ => {
+                self.long_pressed = true;
+                self.send(Request::SwitchScene);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Drop for ObsAction {
+    fn drop(&mut self) {
+        // Give the worker a chance to restore the inputs' mute states.
+        self.requests = None;
+        let _ = self.running.recv_timeout(REPLY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// A stand-in for obs-websocket, which checks that it gets `expected`
+    /// request batches (as lists of `[requestType, requestData]`), and
+    /// replies with the matching response data. Each batch is reported on
+    /// `progress`.
+    fn session(
+        listener: &TcpListener,
+        progress: &mpsc::Sender<()>,
+        expected: Vec<(Value, Vec<Value>)>,
+    ) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = tungstenite::accept(stream).unwrap();
+        let send = |ws: &mut WebSocket<TcpStream>, op: u64, d: Value| {
+            ws.send(Message::Text(json!({ "op": op, "d": d }).to_string()))
+                .unwrap();
+        };
+        let recv = |ws: &mut WebSocket<TcpStream>| -> Value {
+            loop {
+                if let Message::Text(t) = ws.read().unwrap() {
+                    return serde_json::from_str(&t).unwrap();
+                }
+            }
+        };
+
+        send(
+            &mut ws,
+            OP_HELLO,
+            json!({
+                "rpcVersion": 1,
+                "authentication": { "challenge": "Y2hhbGxlbmdl", "salt": "c2FsdA==" },
+            }),
+        );
+        let identify = recv(&mut ws);
+        assert_eq!(identify["op"], OP_IDENTIFY);
+        assert_eq!(
+            identify["d"]["authentication"],
+            "HbemcTRAK8GnBZpkRzKZdmk94xa5VYtjm6/uKbA1epI="
+        );
+        send(&mut ws, OP_IDENTIFIED, json!({ "negotiatedRpcVersion": 1 }));
+
+        for (requests, responses) in expected {
+            let batch = recv(&mut ws);
+            assert_eq!(batch["op"], OP_REQUEST_BATCH);
+            let got: Vec<Value> = batch["d"]["requests"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| json!([r["requestType"], r["requestData"]]))
+                .collect();
+            assert_eq!(Value::Array(got), requests);
+            let results: Vec<Value> = responses
+                .into_iter()
+                .map(|d| json!({ "requestStatus": { "result": true }, "responseData": d }))
+                .collect();
+            send(
+                &mut ws,
+                OP_REQUEST_BATCH_RESPONSE,
+                json!({ "requestId": batch["d"]["requestId"], "results": results }),
+            );
+            progress.send(()).unwrap();
+        }
+    }
+
+    fn set_mute(muted: bool) -> (Value, Vec<Value>) {
+        (
+            json!([["SetInputMute", { "inputName": "Mic", "inputMuted": muted }]]),
+            vec![json!(null)],
+        )
+    }
+
+    #[test]
+    fn press_and_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (progress, batches) = mpsc::channel();
+        let server = thread::spawn(move || {
+            session(
+                &listener,
+                &progress,
+                vec![
+                    (
+                        json!([["GetInputMute", { "inputName": "Mic" }]]),
+                        vec![json!({ "inputMuted": false })],
+                    ),
+                    set_mute(true),
+                    set_mute(false),
+                    (
+                        json!([["SetCurrentProgramScene", { "sceneName": "Live" }]]),
+                        vec![json!(null)],
+                    ),
+                ],
+            );
+            // OBS restarts, so the footswitch state is applied again, and the
+            // original state is restored when the action is dropped.
+            session(&listener, &progress, vec![set_mute(true), set_mute(false)]);
+        });
+
+        let mut action = ObsAction::new(ObsConfig {
+            addr,
+            password: Some("hunter2".to_string()),
+            inputs: vec!["Mic".to_string()],
+            scene: Some("Live".to_string()),
+            long_press: Duration::from_millis(10),
+        });
+        let wait = |n| {
+            for _ in 0..n {
+                batches.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+        };
+        wait(2);
+        action.press().unwrap();
+        wait(1);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(action.poll().unwrap(), None);
+        wait(1);
+        action.release().unwrap();
+        wait(1);
+        drop(action);
+        wait(1);
+        server.join().unwrap();
+    }
+}
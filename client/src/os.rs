@@ -1,10 +1,12 @@
 /*
- * Fake AudioControllerTrait implementation.
+ * Native audio controller for platforms other than macOS and Windows.
  *
- * This is used when the platform is unsupported.
+ * On Linux, this uses PulseAudio (or PipeWire-Pulse). Other platforms have
+ * none, so use `--audio` to pick a command-based or fake implementation
+ * instead.
  */
 
-use crate::audio_controller::{AudioControllerTrait, AudioError, AudioInputDeviceTrait};
+use crate::audio_controller::AudioControllerTrait;
 
 #[macro_export]
 macro_rules! EXAMPLE_PORT {
@@ -13,31 +15,10 @@ macro_rules! EXAMPLE_PORT {
     };
 }
 
-pub struct AudioController {}
-
-pub struct AudioInputDevice {
-    name: String,
-}
-
-impl AudioControllerTrait for AudioController {
-    fn new() -> Box<dyn AudioControllerTrait> {
-        println!("Using fake audio controller device!");
-        Box::new(AudioController {})
-    }
-
-    fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
-        Ok(Box::new(AudioInputDevice {
-            name: "Fake Microphone".to_string(),
-        }))
-    }
-}
-
-impl AudioInputDeviceTrait for AudioInputDevice {
-    fn name(&self) -> Result<String, AudioError> {
-        Ok(self.name.clone())
-    }
-
-    fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
-        Ok(!state)
-    }
+/// Gets the native audio controller for this platform, if there is one.
+pub fn native_audio_controller() -> Option<Box<dyn AudioControllerTrait>> {
+    #[cfg(target_os = "linux")]
+    return Some(Box::new(crate::pulse::PulseAudioController));
+    #[cfg(not(target_os = "linux"))]
+    return None;
 }
@@ -0,0 +1,590 @@
+/*
+ * Open Sound Control (OSC) over UDP, for digital mixers and DAWs.
+ *
+ * Only the parts of OSC 1.0 that mixers and DAWs use are supported: messages
+ * are sent with int, float and string arguments, and bundles are unpacked
+ * (ignoring their time tags) when received.
+ */
+
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use crate::actions::{Action, ActionError};
+use crate::input::{
+    EventSender, InputError, InputEvent, InputSource, Mode, SourceOptions, DEFAULT_BUTTON,
+};
+
+/// The largest OSC packet we'll receive.
+const MAX_PACKET_SIZE: usize = 4096;
+/// How long to wait after failing to receive a packet, which doubles for
+/// each failure in a row, up to `MAX_RECV_BACKOFF`.
+const RECV_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(5);
+/// OSC address which presses `DEFAULT_BUTTON`, if no others are mapped.
+const DEFAULT_BUTTON_ADDRESS: &str = "/footswitch";
+const DEFAULT_FORCE_MUTE_ADDRESS: &str = "/footswitch/force_mute";
+const DEFAULT_MODE_ADDRESS: &str = "/footswitch/mode";
+
+/// An OSC argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+impl Arg {
+    /// Parses an argument, which is an int or float if it looks like one,
+    /// otherwise a string.
+    fn parse(arg: &str) -> Self {
+        if let Ok(i) = arg.parse() {
+            Arg::Int(i)
+        } else if let Ok(f) = arg.parse() {
+            Arg::Float(f)
+        } else {
+            Arg::Str(arg.to_string())
+        }
+    }
+
+    /// Returns whether the argument is "on": a non-zero int, a float of at
+    /// least 0.5 (eg: from a TouchOSC button), or a string like `on`.
+    fn is_on(&self) -> Option<bool> {
+        match self {
+            Arg::Int(i) => Some(*i != 0),
+            Arg::Float(f) => Some(*f >= 0.5),
+            Arg::Str(s) => match s.to_lowercase().as_str() {
+                "on" | "true" | "1" => Some(true),
+                "off" | "false" | "0" => Some(false),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Adds an OSC string: null terminated, and padded to a multiple of 4 bytes.
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.resize(buf.len() + 4 - s.len() % 4, 0);
+}
+
+/// Encodes an OSC message.
+pub fn encode(address: &str, args: &[Arg]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_string(&mut buf, address);
+    let tags: String = std::iter::once(',')
+        .chain(args.iter().map(|a| match a {
+            Arg::Int(_) => 'i',
+            Arg::Float(_) => 'f',
+            Arg::Str(_) => 's',
+        }))
+        .collect();
+    put_string(&mut buf, &tags);
+    for a in args {
+        match a {
+            Arg::Int(i) => buf.extend_from_slice(&i.to_be_bytes()),
+            Arg::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+            Arg::Str(s) => put_string(&mut buf, s),
+        }
+    }
+    buf
+}
+
+/// Reads an OSC string, and returns it with the rest of the packet.
+fn get_string(buf: &[u8]) -> Option<(&str, &[u8])> {
+    let end = buf.iter().position(|&b| b == 0)?;
+    let s = std::str::from_utf8(&buf[..end]).ok()?;
+    Some((s, buf.get((end + 4) & !3..)?))
+}
+
+/// Reads `N` bytes from the start of `buf`.
+fn get_bytes<const N: usize>(buf: &mut &[u8]) -> Option<[u8; N]> {
+    let bytes = buf.get(..N)?.try_into().ok()?;
+    *buf = &buf[N..];
+    Some(bytes)
+}
+
+/// Decodes an OSC packet, and adds its messages to `messages`, unpacking
+/// bundles.
+pub fn decode(packet: &[u8], messages: &mut Vec<(String, Vec<Arg>)>) -> Result<(), String> {
+    let invalid = || "Invalid OSC packet".to_string();
+    if let Some(mut elements) = packet.strip_prefix(b"#bundle\0") {
+        // Skip the time tag: everything is handled as it arrives.
+        get_bytes::<8>(&mut elements).ok_or_else(invalid)?;
+        while !elements.is_empty() {
+            let len = u32::from_be_bytes(get_bytes(&mut elements).ok_or_else(invalid)?) as usize;
+            let element = elements.get(..len).ok_or_else(invalid)?;
+            decode(element, messages)?;
+            elements = &elements[len..];
+        }
+        return Ok(());
+    }
+
+    let (address, rest) = get_string(packet).ok_or_else(invalid)?;
+    let (tags, mut data) = get_string(rest).ok_or_else(invalid)?;
+    let mut args = Vec::new();
+    for tag in tags.strip_prefix(',').ok_or_else(invalid)?.chars() {
+        args.push(match tag {
+            'i' => Arg::Int(i32::from_be_bytes(
+                get_bytes(&mut data).ok_or_else(invalid)?,
+            )),
+            'f' => Arg::Float(f32::from_be_bytes(
+                get_bytes(&mut data).ok_or_else(invalid)?,
+            )),
+            // Clamped, so that large values don't wrap around to zero (off).
+            'h' => Arg::Int(
+                i64::from_be_bytes(get_bytes(&mut data).ok_or_else(invalid)?)
+                    .clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+            ),
+            'd' => Arg::Float(f64::from_be_bytes(get_bytes(&mut data).ok_or_else(invalid)?) as f32),
+            's' => {
+                let (s, rest) = get_string(data).ok_or_else(invalid)?;
+                data = rest;
+                Arg::Str(s.to_string())
+            }
+            'T' => Arg::Int(1),
+            'F' => Arg::Int(0),
+            _ => return Err(format!("Unsupported OSC argument type {:?}", tag)),
+        });
+    }
+    messages.push((address.to_string(), args));
+    Ok(())
+}
+
+/// A message to send when the footswitch (or a button) is pressed, and
+/// another for when it's released.
+#[derive(Debug)]
+pub struct OscMapping {
+    /// The button this is for, or `None` for the footswitch as a whole.
+    button: Option<u8>,
+    address: String,
+    press: Vec<Arg>,
+    release: Option<Vec<Arg>>,
+}
+
+impl OscMapping {
+    /// Parses a mapping, in the form `[BUTTON:]ADDRESS [PRESS_ARGS][/RELEASE_ARGS]`,
+    /// eg: `/ch/01/mix/on 0/1`, or `2:/marker`.
+    ///
+    /// Arguments are separated by spaces. Without `/RELEASE_ARGS`, nothing is
+    /// sent on release.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (button, rest) = match spec.split_once(':') {
+            Some((b, rest)) if !b.starts_with('/') => (
+                Some(
+                    b.parse::<u8>()
+                        .map_err(|_| format!("Invalid button in OSC mapping {:?}", spec))?,
+                ),
+                rest,
+            ),
+            _ => (None, spec),
+        };
+        let rest = rest.trim();
+        let (address, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        if !address.starts_with('/') {
+            return Err(format!(
+                "OSC address must start with /, in mapping {:?}",
+                spec
+            ));
+        }
+        let parse_args = |args: &str| args.split_whitespace().map(Arg::parse).collect();
+        let (press, release) = match args.split_once('/') {
+            Some((p, r)) => (parse_args(p), Some(parse_args(r))),
+            None => (parse_args(args), None),
+        };
+        Ok(OscMapping {
+            button,
+            address: address.to_string(),
+            press,
+            release,
+        })
+    }
+}
+
+/// Sends OSC messages to one or more targets when the footswitch or its
+/// buttons change state.
+pub struct OscAction {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    mappings: Vec<OscMapping>,
+}
+
+impl OscAction {
+    pub fn new(targets: &[&str], mappings: Vec<OscMapping>) -> Result<Self, ActionError> {
+        let mut addrs = Vec::with_capacity(targets.len());
+        for t in targets {
+            let addr = t
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut a| a.next())
+                .ok_or_else(|| ActionError {
+                    msg: format!("Invalid OSC target {:?}, expected HOST:PORT", t),
+                })?;
+            addrs.push(addr);
+        }
+        let bind = if addrs.iter().all(SocketAddr::is_ipv6) {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(bind).map_err(|e| ActionError {
+            msg: format!("Failed to open OSC socket: {}", e),
+        })?;
+        Ok(OscAction {
+            socket,
+            targets: addrs,
+            mappings,
+        })
+    }
+
+    /// Sends the messages for a button (or the footswitch, if `None`).
+    fn send(&self, button: Option<u8>, pressed: bool) {
+        for m in self.mappings.iter().filter(|m| m.button == button) {
+            let args = match (pressed, &m.release) {
+                (true, _) => &m.press,
+                (false, Some(a)) => a,
+                (false, None) => continue,
+            };
+            debug!("OSC: {} {:?}", m.address, args);
+            let packet = encode(&m.address, args);
+            for t in self.targets.iter() {
+                if let Err(e) = self.socket.send_to(&packet, t) {
+                    warn!("Error sending OSC to {}: {}", t, e);
+                }
+            }
+        }
+    }
+}
+
+impl Action for OscAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.send(None, true);
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.send(None, false);
+        Ok(())
+    }
+
+    fn button(&mut self, button: u8, pressed: bool) -> Result<(), ActionError> {
+        self.send(Some(button), pressed);
+        Ok(())
+    }
+}
+
+/// Receives OSC messages over UDP, and maps them to button presses and
+/// commands (eg: from a TouchOSC layout or a mixer).
+pub struct OscSource {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// (OSC address, button)
+    buttons: Vec<(String, u8)>,
+    force_mute: String,
+    mode: String,
+    /// Peers to accept messages from, or empty to accept any.
+    allow: Vec<IpAddr>,
+}
+
+impl OscSource {
+    /// Creates an OSC source from options in the form
+    /// `ADDRESS[,button=OSC_ADDRESS[:BUTTON]][,force_mute=OSC_ADDRESS][,mode=OSC_ADDRESS][,allow=IP]`.
+    ///
+    /// `ADDRESS` may be just a port number, to listen on localhost.
+    pub fn new(options: &str) -> Result<Self, InputError> {
+        let (addr, options) = options.split_once(',').unwrap_or((options, ""));
+        let options = SourceOptions::parse(options, &["button", "force_mute", "mode", "allow"])?;
+        let addr = match addr.parse::<u16>() {
+            Ok(port) => SocketAddr::from(([127, 0, 0, 1], port)),
+            Err(_) => addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut a| a.next())
+                .ok_or_else(|| InputError {
+                    msg: format!("Invalid address {:?}", addr),
+                })?,
+        };
+
+        let mut buttons = Vec::new();
+        for mapping in options.get_all("button") {
+            let (address, button) = match mapping.rsplit_once(':') {
+                Some((a, b)) => (
+                    a,
+                    b.parse::<u8>().map_err(|_| InputError {
+                        msg: format!(
+                            "Invalid OSC button mapping {:?}, expected OSC_ADDRESS[:BUTTON]",
+                            mapping
+                        ),
+                    })?,
+                ),
+                None => (mapping, DEFAULT_BUTTON),
+            };
+            buttons.push((address.to_string(), button));
+        }
+        if buttons.is_empty() {
+            buttons.push((DEFAULT_BUTTON_ADDRESS.to_string(), DEFAULT_BUTTON));
+        }
+
+        let allow = options
+            .get_all("allow")
+            .map(|a| {
+                a.parse::<IpAddr>().map_err(|_| InputError {
+                    msg: format!("Invalid IP address {:?} for allow", a),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if allow.is_empty() && !addr.ip().is_loopback() {
+            warn!(
+                "Accepting OSC messages on {} from anyone on the network; use allow=IP to limit this",
+                addr
+            );
+        }
+
+        let socket = UdpSocket::bind(addr).map_err(|e| InputError {
+            msg: format!("Failed to listen on {}: {}", addr, e),
+        })?;
+        Ok(OscSource {
+            socket,
+            addr,
+            buttons,
+            force_mute: options
+                .get("force_mute")
+                .unwrap_or(DEFAULT_FORCE_MUTE_ADDRESS)
+                .to_string(),
+            mode: options
+                .get("mode")
+                .unwrap_or(DEFAULT_MODE_ADDRESS)
+                .to_string(),
+            allow,
+        })
+    }
+
+    /// Maps a message to events.
+    fn events(&self, address: &str, args: &[Arg]) -> Result<Vec<(u8, InputEvent)>, String> {
+        let on = args.first().map(|a| a.is_on());
+        if let Some((_, button)) = self.buttons.iter().find(|(a, _)| a == address) {
+            return match on {
+                Some(Some(true)) => Ok(vec![(*button, InputEvent::Press)]),
+                Some(Some(false)) => Ok(vec![(*button, InputEvent::Release)]),
+                // Without an argument, tap the button.
+                None => Ok(vec![
+                    (*button, InputEvent::Press),
+                    (*button, InputEvent::Release),
+                ]),
+                Some(None) => Err(format!("Invalid value for OSC {}: {:?}", address, args)),
+            };
+        }
+        if address == self.force_mute {
+            return match on {
+                Some(Some(muted)) => Ok(vec![(DEFAULT_BUTTON, InputEvent::ForceMute(muted))]),
+                _ => Err(format!("OSC {} needs on or off: {:?}", address, args)),
+            };
+        }
+        if address == self.mode {
+            let mode = match args.first() {
+                Some(Arg::Str(s)) => s.parse()?,
+                Some(a) if a.is_on() == Some(true) => Mode::Latch,
+                Some(_) => Mode::Hold,
+                None => return Err(format!("OSC {} needs hold or latch", address)),
+            };
+            return Ok(vec![(DEFAULT_BUTTON, InputEvent::Mode(mode))]);
+        }
+        debug!("Ignoring OSC {} {:?}", address, args);
+        Ok(Vec::new())
+    }
+}
+
+impl InputSource for OscSource {
+    fn name(&self) -> String {
+        format!("OSC on: {}", self.addr)
+    }
+
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut messages = Vec::new();
+        let mut backoff = RECV_BACKOFF;
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Error reading OSC: {}", e);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECV_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = RECV_BACKOFF;
+            if !self.allow.is_empty() && !self.allow.contains(&peer.ip()) {
+                debug!("Ignoring OSC from {}, which isn't allowed", peer);
+                continue;
+            }
+            if let Err(e) = decode(&buf[..len], &mut messages) {
+                warn!("{} from {}", e, peer);
+            }
+            for (address, args) in messages.drain(..) {
+                match self.events(&address, &args) {
+                    Ok(events) => {
+                        for (button, event) in events {
+                            if !chan.send_button(button, event) {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("{} from {}", e, peer),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_one(packet: &[u8]) -> Result<(String, Vec<Arg>), String> {
+        let mut messages = Vec::new();
+        decode(packet, &mut messages)?;
+        assert_eq!(messages.len(), 1);
+        Ok(messages.remove(0))
+    }
+
+    #[test]
+    fn round_trips() {
+        for args in &[
+            vec![],
+            vec![Arg::Int(-7), Arg::Float(0.25)],
+            vec![
+                Arg::Str(String::new()),
+                Arg::Str("abc".to_string()),
+                Arg::Str("abcd".to_string()),
+                Arg::Int(i32::MAX),
+            ],
+        ] {
+            for address in &["/a", "/abc", "/ch/01/mix/on"] {
+                let packet = encode(address, args);
+                assert_eq!(packet.len() % 4, 0);
+                assert_eq!(decode_one(&packet), Ok((address.to_string(), args.clone())));
+            }
+        }
+        assert_eq!(
+            encode("/ch/01/mix/on", &[Arg::Int(1)]),
+            b"/ch/01/mix/on\0\0\0,i\0\0\0\0\0\x01".to_vec()
+        );
+    }
+
+    #[test]
+    fn decoding() {
+        let mut packet = b"/x\0\0,hhdTF\0\0".to_vec();
+        packet.extend_from_slice(&(1i64 << 32).to_be_bytes());
+        packet.extend_from_slice(&(-1i64 << 40).to_be_bytes());
+        packet.extend_from_slice(&0.5f64.to_be_bytes());
+        assert_eq!(
+            decode_one(&packet),
+            Ok((
+                "/x".to_string(),
+                vec![
+                    Arg::Int(i32::MAX),
+                    Arg::Int(i32::MIN),
+                    Arg::Float(0.5),
+                    Arg::Int(1),
+                    Arg::Int(0)
+                ]
+            ))
+        );
+
+        // Bundles, including nested ones.
+        let first = encode("/1", &[Arg::Int(1)]);
+        let second = encode("/2", &[]);
+        let bundle = |elements: &[&[u8]]| {
+            let mut b = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+            for e in elements {
+                b.extend_from_slice(&(e.len() as u32).to_be_bytes());
+                b.extend_from_slice(e);
+            }
+            b
+        };
+        let mut messages = Vec::new();
+        decode(&bundle(&[&first, &bundle(&[&second])]), &mut messages).unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                ("/1".to_string(), vec![Arg::Int(1)]),
+                ("/2".to_string(), vec![])
+            ]
+        );
+
+        // Truncated and unsupported packets.
+        assert!(decode_one(&first[..first.len() - 1]).is_err());
+        assert!(decode_one(b"/x\0\0").is_err());
+        assert!(decode_one(b"/x\0\0i\0\0\0").is_err());
+        assert!(decode_one(b"/x\0\0,b\0\0\0\0\0\0").is_err());
+        let mut long_bundle = bundle(&[&second]);
+        long_bundle[19] += 1;
+        assert!(decode_one(&long_bundle).is_err());
+    }
+
+    #[test]
+    fn mappings() {
+        let m = OscMapping::parse("/ch/01/mix/on 0/1").unwrap();
+        assert_eq!(m.button, None);
+        assert_eq!(m.address, "/ch/01/mix/on");
+        assert_eq!(m.press, vec![Arg::Int(0)]);
+        assert_eq!(m.release, Some(vec![Arg::Int(1)]));
+
+        let m = OscMapping::parse("2:/action 40157").unwrap();
+        assert_eq!(m.button, Some(2));
+        assert_eq!(m.press, vec![Arg::Int(40157)]);
+        assert_eq!(m.release, None);
+
+        let m = OscMapping::parse("/scene go 0.5 / stop").unwrap();
+        assert_eq!(m.press, vec![Arg::Str("go".to_string()), Arg::Float(0.5)]);
+        assert_eq!(m.release, Some(vec![Arg::Str("stop".to_string())]));
+
+        let m = OscMapping::parse("/marker").unwrap();
+        assert_eq!(m.press, vec![]);
+        assert_eq!(m.release, None);
+
+        assert!(OscMapping::parse("x:/marker").is_err());
+        assert!(OscMapping::parse("marker 1").is_err());
+        assert!(OscMapping::parse("").is_err());
+    }
+
+    #[test]
+    fn source_options() {
+        let source = OscSource::new("0,allow=192.168.1.20,allow=::1").unwrap();
+        assert!(source.addr.ip().is_loopback());
+        assert_eq!(
+            source.allow,
+            vec![
+                "192.168.1.20".parse::<IpAddr>().unwrap(),
+                "::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert!(OscSource::new("0,allow=studio").is_err());
+    }
+
+    #[test]
+    fn source_events() {
+        let source =
+            OscSource::new("0,button=/1/push1,button=/1/push2:2,force_mute=/1/toggle1").unwrap();
+        assert_eq!(
+            source.events("/1/push2", &[Arg::Float(1.)]),
+            Ok(vec![(2, InputEvent::Press)])
+        );
+        assert_eq!(
+            source.events("/1/push1", &[]),
+            Ok(vec![(1, InputEvent::Press), (1, InputEvent::Release)])
+        );
+        assert_eq!(
+            source.events("/1/toggle1", &[Arg::Int(0)]),
+            Ok(vec![(1, InputEvent::ForceMute(false))])
+        );
+        assert_eq!(
+            source.events("/footswitch/mode", &[Arg::Str("latch".to_string())]),
+            Ok(vec![(1, InputEvent::Mode(Mode::Latch))])
+        );
+        assert!(source.events("/1/toggle1", &[]).is_err());
+        assert_eq!(source.events("/footswitch", &[Arg::Int(1)]), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,828 @@
+/*
+ * PulseAudio (and PipeWire-Pulse) support, using the `pactl` command.
+ *
+ * This is the native audio controller on Linux.
+ */
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::audio_controller::{
+    adjust_cubic_volume, AudioControllerTrait, AudioError, AudioInputDeviceTrait,
+    AudioOutputDeviceTrait, COMMAND_RAMP_STEP,
+};
+
+/// `PA_VOLUME_NORM`: the raw volume level for 100%.
+const VOLUME_NORM: f32 = 65536.;
+
+/// The `pactl` program, which tests replace with a stand-in.
+fn pactl_program() -> PathBuf {
+    #[cfg(test)]
+    if let Some(program) = tests::PACTL.with(|p| p.borrow().clone()) {
+        return program;
+    }
+    PathBuf::from("pactl")
+}
+
+/// Runs `pactl` with the given arguments, and returns its output.
+pub fn pactl(args: &[&str]) -> Result<String, AudioError> {
+    let output = Command::new(pactl_program())
+        .args(args)
+        .env("LC_ALL", "C")
+        .output()
+        .map_err(|e| AudioError {
+            msg: format!("Error running pactl: {}", e),
+        })?;
+    if !output.status.success() {
+        return Err(AudioError {
+            msg: format!(
+                "pactl {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the volume of the first channel from `pactl get-*-volume`, eg:
+/// `Volume: front-left: 65536 / 100% / 0.00 dB, ...`.
+fn parse_volume(output: &str) -> Result<f32, AudioError> {
+    output
+        .split_whitespace()
+        .find_map(|v| v.parse::<u32>().ok())
+        .map(|v| v as f32 / VOLUME_NORM)
+        .ok_or_else(|| AudioError {
+            msg: format!("Could not parse volume from {:?}", output.trim()),
+        })
+}
+
+/// Formats a volume for `pactl set-*-volume`.
+fn format_volume(volume: f32) -> String {
+    ((volume * VOLUME_NORM) as u32).to_string()
+}
+
+/// A capture stream, as reported by `pactl list source-outputs`.
+#[derive(Debug)]
+pub struct SourceOutput {
+    pub index: u32,
+    pub mute: bool,
+    pub volume: Option<f32>,
+    pub properties: HashMap<String, String>,
+}
+
+/// Parses the output of `pactl list source-outputs`.
+pub fn parse_source_outputs(output: &str) -> Vec<SourceOutput> {
+    let mut streams = Vec::new();
+    let mut current: Option<SourceOutput> = None;
+    let mut in_properties = false;
+
+    for line in output.lines() {
+        if let Some(index) = line.strip_prefix("Source Output #") {
+            streams.extend(current.take());
+            in_properties = false;
+            current = index.trim().parse().ok().map(|index| SourceOutput {
+                index,
+                mute: false,
+                volume: None,
+                properties: HashMap::new(),
+            });
+            continue;
+        }
+
+        let stream = match current.as_mut() {
+            Some(s) => s,
+            None => continue,
+        };
+        let trimmed = line.trim();
+        if in_properties && line.starts_with("\t\t") {
+            if let Some((key, value)) = trimmed.split_once(" = ") {
+                stream
+                    .properties
+                    .insert(key.to_string(), value.trim_matches('"').to_string());
+            }
+            continue;
+        }
+        in_properties = false;
+
+        if trimmed == "Properties:" {
+            in_properties = true;
+        } else if let Some(mute) = trimmed.strip_prefix("Mute: ") {
+            stream.mute = mute == "yes";
+        } else if let Some(volume) = trimmed.strip_prefix("Volume: ") {
+            // eg: "front-left: 65536 / 100% / 0.00 dB,   front-right: ..."
+            stream.volume = volume
+                .split_whitespace()
+                .find_map(|v| v.parse::<u32>().ok())
+                .map(|v| v as f32 / VOLUME_NORM);
+        }
+    }
+    streams.extend(current);
+    streams
+}
+
+/// Lists all capture streams.
+pub fn list_source_outputs() -> Result<Vec<SourceOutput>, AudioError> {
+    pactl(&["list", "source-outputs"]).map(|o| parse_source_outputs(&o))
+}
+
+/// Matches capture streams by application name or binary.
+#[derive(Clone, Debug)]
+pub struct AppMatcher {
+    apps: Vec<String>,
+}
+
+impl AppMatcher {
+    pub fn new(apps: Vec<String>) -> Self {
+        AppMatcher {
+            apps: apps.into_iter().map(|a| a.to_lowercase()).collect(),
+        }
+    }
+
+    pub fn matches(&self, stream: &SourceOutput) -> bool {
+        ["application.name", "application.process.binary"]
+            .iter()
+            .filter_map(|k| stream.properties.get(*k))
+            .any(|v| self.apps.contains(&v.to_lowercase()))
+    }
+}
+
+/// The state we want matching streams to be in.
+#[derive(Default)]
+struct StreamState {
+    mute: Option<bool>,
+    volume: Option<f32>,
+}
+
+fn apply_state(index: u32, state: &StreamState) -> Result<(), AudioError> {
+    let index = index.to_string();
+    if let Some(mute) = state.mute {
+        pactl(&[
+            "set-source-output-mute",
+            &index,
+            if mute { "1" } else { "0" },
+        ])?;
+    }
+    if let Some(volume) = state.volume {
+        pactl(&["set-source-output-volume", &index, &format_volume(volume)])?;
+    }
+    Ok(())
+}
+
+/// Controls the capture streams of particular applications, rather than a
+/// whole input device.
+///
+/// Streams that are opened later are set to the same state.
+pub struct AppStreamsDevice {
+    matcher: AppMatcher,
+    state: Arc<Mutex<StreamState>>,
+    subscriber: Child,
+}
+
+impl AppStreamsDevice {
+    pub fn new(apps: Vec<String>) -> Result<Self, AudioError> {
+        let matcher = AppMatcher::new(apps);
+        let state = Arc::new(Mutex::new(StreamState::default()));
+
+        let mut subscriber = Command::new(pactl_program())
+            .arg("subscribe")
+            .env("LC_ALL", "C")
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| AudioError {
+                msg: format!("Error running pactl subscribe: {}", e),
+            })?;
+        let stdout = subscriber.stdout.take().unwrap();
+
+        let thread_matcher = matcher.clone();
+        let thread_state = state.clone();
+        thread::spawn(move || watch_new_streams(stdout, thread_matcher, thread_state));
+
+        Ok(AppStreamsDevice {
+            matcher,
+            state,
+            subscriber,
+        })
+    }
+
+    fn matching_streams(&self) -> Result<Vec<SourceOutput>, AudioError> {
+        Ok(list_source_outputs()?
+            .into_iter()
+            .filter(|s| self.matcher.matches(s))
+            .collect())
+    }
+}
+
+/// Watches `pactl subscribe` output for new capture streams, and sets them
+/// to the current state if they match.
+fn watch_new_streams(
+    stdout: impl std::io::Read,
+    matcher: AppMatcher,
+    state: Arc<Mutex<StreamState>>,
+) {
+    for line in BufReader::new(stdout).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        // eg: "Event 'new' on source-output #123"
+        let index = match line
+            .strip_prefix("Event 'new' on source-output #")
+            .and_then(|i| i.trim().parse::<u32>().ok())
+        {
+            Some(i) => i,
+            None => continue,
+        };
+
+        let stream = match list_source_outputs() {
+            Ok(streams) => streams.into_iter().find(|s| s.index == index),
+            Err(e) => {
+                warn!("Error listing capture streams: {}", e.msg);
+                continue;
+            }
+        };
+        if let Some(stream) = stream.filter(|s| matcher.matches(s)) {
+            debug!("New capture stream: {:?}", stream);
+            let state = state.lock().unwrap();
+            if let Err(e) = apply_state(index, &state) {
+                warn!("Error updating capture stream #{}: {}", index, e.msg);
+            }
+        }
+    }
+    debug!("pactl subscribe exited");
+}
+
+impl Drop for AppStreamsDevice {
+    fn drop(&mut self) {
+        let _ = self.subscriber.kill();
+        let _ = self.subscriber.wait();
+    }
+}
+
+impl AudioInputDeviceTrait for AppStreamsDevice {
+    fn name(&self) -> Result<String, AudioError> {
+        Ok(format!(
+            "Capture streams of {}",
+            self.matcher.apps.join(", ")
+        ))
+    }
+
+    fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
+        // Hold the lock so new streams don't miss the update.
+        let mut s = self.state.lock().unwrap();
+        s.mute = Some(state);
+        for stream in self.matching_streams()? {
+            apply_state(
+                stream.index,
+                &StreamState {
+                    mute: Some(state),
+                    volume: None,
+                },
+            )?;
+        }
+        Ok(state)
+    }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        if let Some(mute) = self.state.lock().unwrap().mute {
+            return Ok(mute);
+        }
+        Ok(self.matching_streams()?.iter().any(|s| s.mute))
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        if let Some(volume) = self.state.lock().unwrap().volume {
+            return Ok(volume);
+        }
+        Ok(self
+            .matching_streams()?
+            .iter()
+            .find_map(|s| s.volume)
+            .unwrap_or(1.))
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        let mut s = self.state.lock().unwrap();
+        s.volume = Some(volume);
+        for stream in self.matching_streams()? {
+            apply_state(
+                stream.index,
+                &StreamState {
+                    mute: None,
+                    volume: Some(volume),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(adjust_cubic_volume(volume, db))
+    }
+
+    fn ramp_step(&self) -> Duration {
+        COMMAND_RAMP_STEP
+    }
+}
+
+/// Name of the null sink that the physical microphone is looped back into.
+const GATE_SINK_NAME: &str = "footswitch_gate";
+
+/// Name of the virtual microphone source that applications record from.
+const VIRTUAL_SOURCE_NAME: &str = "footswitch_mic";
+
+/// Loads a PulseAudio module, and returns its index.
+fn load_module(args: &[&str]) -> Result<String, AudioError> {
+    let mut a = vec!["load-module"];
+    a.extend_from_slice(args);
+    pactl(&a).map(|o| o.trim().to_string())
+}
+
+fn unload_module(index: &str) -> Result<(), AudioError> {
+    pactl(&["unload-module", index]).map(|_| ())
+}
+
+/// Gets the name of the default source.
+fn get_default_source() -> Result<String, AudioError> {
+    get_info("Default Source: ")
+}
+
+/// Unloads modules left over from a previous run that didn't exit cleanly.
+fn unload_stale_modules() -> Result<(), AudioError> {
+    // eg: "25\tmodule-null-sink\tsink_name=footswitch_gate ..."
+    for line in pactl(&["list", "short", "modules"])?.lines().rev() {
+        let mut parts = line.split('\t');
+        let (index, args) = match (parts.next(), parts.nth(1)) {
+            (Some(i), Some(a)) => (i, a),
+            _ => continue,
+        };
+        if args.contains(GATE_SINK_NAME) || args.contains(VIRTUAL_SOURCE_NAME) {
+            warn!("Unloading stale module #{}: {}", index, args);
+            unload_module(index)?;
+        }
+    }
+    Ok(())
+}
+
+/// A virtual microphone source, which is fed from a physical microphone
+/// through a gate.
+///
+/// Applications record from the virtual source, which is muted instead of
+/// the physical microphone. This works with applications that ignore the
+/// mute state of the physical microphone.
+///
+/// The virtual source is controlled rather than the gate's null sink, as
+/// PipeWire ignores a sink's volume and mute state in its monitor.
+///
+/// The virtual source is removed when this is dropped.
+pub struct VirtualSourceDevice {
+    /// Indexes of the modules we loaded, in the order they were loaded.
+    modules: Vec<String>,
+}
+
+impl VirtualSourceDevice {
+    /// Creates a virtual source fed from `master`, or the default source if
+    /// `None`.
+    pub fn new(master: Option<&str>) -> Result<Self, AudioError> {
+        unload_stale_modules()?;
+        let master = match master {
+            Some(m) => m.to_string(),
+            None => get_default_source()?,
+        };
+
+        let mut device = VirtualSourceDevice {
+            modules: Vec::new(),
+        };
+        device.modules.push(load_module(&[
+            "module-null-sink",
+            &format!("sink_name={}", GATE_SINK_NAME),
+            "sink_properties=device.description=Footswitch-Gate",
+        ])?);
+        device.modules.push(load_module(&[
+            "module-loopback",
+            &format!("source={}", master),
+            &format!("sink={}", GATE_SINK_NAME),
+            "latency_msec=20",
+            "source_dont_move=true",
+            "sink_dont_move=true",
+        ])?);
+        device.modules.push(load_module(&[
+            "module-remap-source",
+            &format!("master={}.monitor", GATE_SINK_NAME),
+            &format!("source_name={}", VIRTUAL_SOURCE_NAME),
+            "source_properties=device.description=Footswitch-Microphone",
+        ])?);
+
+        info!(
+            "Created virtual microphone {} from {}",
+            VIRTUAL_SOURCE_NAME, master
+        );
+        Ok(device)
+    }
+}
+
+impl Drop for VirtualSourceDevice {
+    fn drop(&mut self) {
+        for index in self.modules.iter().rev() {
+            if let Err(e) = unload_module(index) {
+                warn!("Error unloading module #{}: {}", index, e.msg);
+            }
+        }
+    }
+}
+
+impl AudioInputDeviceTrait for VirtualSourceDevice {
+    fn name(&self) -> Result<String, AudioError> {
+        Ok(VIRTUAL_SOURCE_NAME.to_string())
+    }
+
+    fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
+        pactl(&[
+            "set-source-mute",
+            VIRTUAL_SOURCE_NAME,
+            if state { "1" } else { "0" },
+        ])?;
+        Ok(state)
+    }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        // eg: "Mute: yes"
+        Ok(pactl(&["get-source-mute", VIRTUAL_SOURCE_NAME])?.trim() == "Mute: yes")
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        parse_volume(&pactl(&["get-source-volume", VIRTUAL_SOURCE_NAME])?)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        pactl(&[
+            "set-source-volume",
+            VIRTUAL_SOURCE_NAME,
+            &format_volume(volume),
+        ])
+        .map(|_| ())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(adjust_cubic_volume(volume, db))
+    }
+
+    fn ramp_step(&self) -> Duration {
+        COMMAND_RAMP_STEP
+    }
+}
+
+/// Gets a line from `pactl info`, eg: `Default Sink: `.
+fn get_info(prefix: &str) -> Result<String, AudioError> {
+    pactl(&["info"])?
+        .lines()
+        .find_map(|l| l.strip_prefix(prefix))
+        .map(str::to_string)
+        .ok_or_else(|| AudioError {
+            msg: format!("Could not find {:?} in pactl info", prefix.trim()),
+        })
+}
+
+/// The default source (microphone).
+pub struct DefaultSourceDevice;
+
+impl AudioInputDeviceTrait for DefaultSourceDevice {
+    fn name(&self) -> Result<String, AudioError> {
+        get_default_source()
+    }
+
+    fn set_mute(&self, state: bool) -> Result<bool, AudioError> {
+        pactl(&[
+            "set-source-mute",
+            "@DEFAULT_SOURCE@",
+            if state { "1" } else { "0" },
+        ])?;
+        Ok(state)
+    }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        Ok(pactl(&["get-source-mute", "@DEFAULT_SOURCE@"])?.trim() == "Mute: yes")
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        parse_volume(&pactl(&["get-source-volume", "@DEFAULT_SOURCE@"])?)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        pactl(&[
+            "set-source-volume",
+            "@DEFAULT_SOURCE@",
+            &format_volume(volume),
+        ])
+        .map(|_| ())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(adjust_cubic_volume(volume, db))
+    }
+
+    fn ramp_step(&self) -> Duration {
+        COMMAND_RAMP_STEP
+    }
+}
+
+/// A sink (output device).
+#[derive(Debug)]
+pub struct SinkDevice {
+    /// The sink's name, or `@DEFAULT_SINK@`.
+    name: String,
+    description: Option<String>,
+}
+
+/// Parses the output of `pactl list sinks`.
+pub fn parse_sinks(output: &str) -> Vec<SinkDevice> {
+    let mut sinks = Vec::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("Name: ") {
+            sinks.push(SinkDevice {
+                name: name.to_string(),
+                description: None,
+            });
+        } else if let Some(description) = line.strip_prefix("Description: ") {
+            if let Some(s) = sinks.last_mut() {
+                s.description = Some(description.to_string());
+            }
+        }
+    }
+    sinks
+}
+
+impl AudioOutputDeviceTrait for SinkDevice {
+    fn id(&self) -> Result<String, AudioError> {
+        if self.name == "@DEFAULT_SINK@" {
+            // Save volumes against the real sink, in case the default changes.
+            return get_info("Default Sink: ");
+        }
+        Ok(self.name.clone())
+    }
+
+    fn name(&self) -> Result<String, AudioError> {
+        match &self.description {
+            Some(d) => Ok(d.clone()),
+            None => self.id(),
+        }
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        parse_volume(&pactl(&["get-sink-volume", &self.name])?)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        pactl(&["set-sink-volume", &self.name, &format_volume(volume)]).map(|_| ())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        Ok(adjust_cubic_volume(volume, db))
+    }
+
+    fn ramp_step(&self) -> Duration {
+        COMMAND_RAMP_STEP
+    }
+}
+
+/// Controls the default source and sinks with `pactl`.
+pub struct PulseAudioController;
+
+impl AudioControllerTrait for PulseAudioController {
+    fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
+        Ok(Box::new(DefaultSourceDevice))
+    }
+
+    fn get_default_output_device(&self) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError> {
+        Ok(Box::new(SinkDevice {
+            name: "@DEFAULT_SINK@".to_string(),
+            description: None,
+        }))
+    }
+
+    fn get_output_devices(&self) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, AudioError> {
+        Ok(parse_sinks(&pactl(&["list", "sinks"])?)
+            .into_iter()
+            .map(|s| Box::new(s) as Box<dyn AudioOutputDeviceTrait>)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    thread_local! {
+        /// A stand-in for `pactl`, used by this thread.
+        pub static PACTL: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    }
+
+    /// Replaces `pactl` with a script, which answers with the `case`
+    /// patterns in `replies`. Returns the path it logs commands to.
+    fn fake_pactl(name: &str, replies: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("footswitch-pactl-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("log");
+        let _ = fs::remove_file(&log);
+        let program = dir.join("pactl");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> '{}'\ncase \"$*\" in\n{}\nesac\n",
+                log.display(),
+                replies
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        PACTL.with(|p| *p.borrow_mut() = Some(program));
+        log
+    }
+
+    fn commands(log: &Path) -> Vec<String> {
+        fs::read_to_string(log)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// `pactl list source-outputs` from PipeWire, with some lines removed.
+    const SOURCE_OUTPUTS: &str = "Source Output #57
+\tDriver: PipeWire
+\tOwner Module: n/a
+\tClient: 56
+\tSource: 49
+\tSample Specification: float32le 1ch 48000Hz
+\tChannel Map: mono
+\tCorked: no
+\tMute: no
+\tVolume: mono: 65536 / 100% / 0.00 dB
+\t        balance 0.00
+\tBuffer Latency: 0 usec
+\tResample method: PipeWire
+\tProperties:
+\t\tclient.api = \"pipewire-pulse\"
+\t\tapplication.name = \"Firefox\"
+\t\tapplication.process.binary = \"firefox\"
+\t\tmedia.name = \"AudioStream = Mic\"
+\t\tnode.name = \"Firefox\"
+
+Source Output #63
+\tDriver: PipeWire
+\tClient: 61
+\tSource: 49
+\tMute: yes
+\tVolume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB
+\t        balance 0.00
+\tProperties:
+\t\tapplication.name = \"WEBRTC VoiceEngine\"
+\t\tmedia.name = \"recStream\"
+
+Source Output #70
+\tDriver: PipeWire
+\tClient: 68
+\tSource: 49
+\tMute: no
+\tVolume: mono: 49152 /  75% / -7.50 dB
+\tProperties:
+\t\tapplication.name = \"Téléconférence\"
+\t\tapplication.process.binary = \"会議\"
+";
+
+    #[test]
+    fn source_outputs() {
+        let streams = parse_source_outputs(SOURCE_OUTPUTS);
+        assert_eq!(streams.len(), 3);
+
+        assert_eq!(streams[0].index, 57);
+        assert!(!streams[0].mute);
+        assert_eq!(streams[0].volume, Some(1.));
+        assert_eq!(streams[0].properties["application.name"], "Firefox");
+        assert_eq!(streams[0].properties["media.name"], "AudioStream = Mic");
+        // The balance line isn't a property.
+        assert_eq!(streams[0].properties.len(), 5);
+
+        assert_eq!(streams[1].index, 63);
+        assert!(streams[1].mute);
+        assert_eq!(streams[1].volume, Some(0.5));
+        assert!(!streams[1]
+            .properties
+            .contains_key("application.process.binary"));
+
+        assert_eq!(streams[2].volume, Some(0.75));
+        assert_eq!(streams[2].properties["application.name"], "Téléconférence");
+        assert_eq!(streams[2].properties["application.process.binary"], "会議");
+
+        assert!(parse_source_outputs("").is_empty());
+    }
+
+    #[test]
+    fn app_matching() {
+        let streams = parse_source_outputs(SOURCE_OUTPUTS);
+        let matching = |apps: &[&str]| -> Vec<u32> {
+            let matcher = AppMatcher::new(apps.iter().map(|a| a.to_string()).collect());
+            streams
+                .iter()
+                .filter(|s| matcher.matches(s))
+                .map(|s| s.index)
+                .collect()
+        };
+
+        // By name or binary, ignoring case.
+        assert_eq!(matching(&["firefox"]), [57]);
+        assert_eq!(matching(&["webrtc voiceengine"]), [63]);
+        assert_eq!(matching(&["TÉLÉCONFÉRENCE"]), [70]);
+        assert_eq!(matching(&["会議", "FireFox"]), [57, 70]);
+        // Only whole names match.
+        assert!(matching(&["fire", "AudioStream", "recStream"]).is_empty());
+        assert!(matching(&[]).is_empty());
+    }
+
+    #[test]
+    fn virtual_source() {
+        let log = fake_pactl(
+            "virtual",
+            "'list short modules') printf '25\\tmodule-null-sink\\tsink_name=footswitch_gate\\n26\\tmodule-loopback\\tsource=mic\\n' ;;\n\
+             info) echo 'Default Source: alsa_input.mic' ;;\n\
+             'load-module module-null-sink'*) echo 31 ;;\n\
+             'load-module module-loopback'*) echo 32 ;;\n\
+             'load-module module-remap-source'*) echo 33 ;;\n\
+             'get-source-mute footswitch_mic') echo 'Mute: yes' ;;\n\
+             'get-source-volume footswitch_mic') echo 'Volume: mono: 32768 /  50% / -18.06 dB' ;;",
+        );
+        let device = VirtualSourceDevice::new(None).unwrap();
+        assert_eq!(device.name().unwrap(), "footswitch_mic");
+        assert!(device.set_mute(true).unwrap());
+        assert!(device.get_mute().unwrap());
+        assert_eq!(device.get_volume().unwrap(), 0.5);
+        device.set_volume(0.25).unwrap();
+        drop(device);
+
+        assert_eq!(
+            commands(&log),
+            [
+                "list short modules",
+                // Left over from a previous run.
+                "unload-module 25",
+                "info",
+                "load-module module-null-sink sink_name=footswitch_gate \
+                 sink_properties=device.description=Footswitch-Gate",
+                "load-module module-loopback source=alsa_input.mic sink=footswitch_gate \
+                 latency_msec=20 source_dont_move=true sink_dont_move=true",
+                "load-module module-remap-source master=footswitch_gate.monitor \
+                 source_name=footswitch_mic source_properties=device.description=Footswitch-Microphone",
+                // The virtual source is controlled, not the gate.
+                "set-source-mute footswitch_mic 1",
+                "get-source-mute footswitch_mic",
+                "get-source-volume footswitch_mic",
+                "set-source-volume footswitch_mic 16384",
+                "unload-module 33",
+                "unload-module 32",
+                "unload-module 31",
+            ]
+        );
+    }
+
+    #[test]
+    fn sinks() {
+        let sinks = parse_sinks(
+            "Sink #48\n\
+             \tState: RUNNING\n\
+             \tName: alsa_output.pci-0000_00_1f.3.analog-stereo\n\
+             \tDescription: Built-in Audio Analog Stereo\n\
+             \tDriver: PipeWire\n\
+             \n\
+             Sink #52\n\
+             \tName: footswitch-gate\n",
+        );
+        assert_eq!(sinks.len(), 2);
+        assert_eq!(
+            sinks[0].id().unwrap(),
+            "alsa_output.pci-0000_00_1f.3.analog-stereo"
+        );
+        assert_eq!(sinks[0].name().unwrap(), "Built-in Audio Analog Stereo");
+        assert_eq!(sinks[1].name().unwrap(), "footswitch-gate");
+    }
+
+    #[test]
+    fn volumes() {
+        let v = parse_volume(
+            "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB\n",
+        );
+        assert_eq!(v.unwrap(), 0.5);
+        assert!(parse_volume("Failure: No such entity").is_err());
+        assert_eq!(format_volume(0.5), "32768");
+    }
+}
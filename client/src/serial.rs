@@ -0,0 +1,207 @@
+/*
+ * Serial port input source, for serial.ino and keyboard.ino.
+ *
+ * See ../serial/README.md for the protocol.
+ */
+
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use serialport::{FlowControl, SerialPort};
+
+use crate::input::{EventSender, InputError, InputEvent, InputSource, SourceOptions, Threshold};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Maximum value of an analog message, which matches Arduino's
+/// `analogRead()`.
+const ANALOG_MAX: f32 = 1023.;
+/// Maximum length of an analog or knob message's value.
+const VALUE_MAX_LEN: usize = 8;
+const MISSING_SERIAL_WAIT_TIME: Duration = Duration::from_secs(10);
+
+fn create_serial_port(serial_device: &str) -> Result<Box<dyn SerialPort>, serialport::Error> {
+    serialport::new(serial_device, 9600)
+        .flow_control(FlowControl::Hardware)
+        .timeout(READ_TIMEOUT)
+        .open()
+        .inspect_err(|e| {
+            error!("Failed to open {}: {}", serial_device, e.description);
+        })
+}
+
+/// Decodes the serial protocol, one byte at a time.
+pub struct Decoder {
+    /// Press and release thresholds for analog messages.
+    threshold: Threshold,
+    /// The type (`A` or `D`) and value of an analog or knob message we're
+    /// part way through reading.
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl Decoder {
+    pub fn new(threshold: Threshold) -> Self {
+        Decoder {
+            threshold,
+            message: None,
+        }
+    }
+
+    /// Decodes a byte, and adds any resulting events to `events`.
+    ///
+    /// Returns an error if the byte isn't valid, and the decoder is reset.
+    pub fn decode(&mut self, byte: u8, events: &mut Vec<InputEvent>) -> Result<(), String> {
+        if byte == b'\n' {
+            if let Some((kind, value)) = self.message.take() {
+                let value = std::str::from_utf8(&value).map(str::trim);
+                if kind == b'D' {
+                    let steps = value
+                        .ok()
+                        .and_then(|v| v.parse::<i32>().ok())
+                        .ok_or("Unhandled knob serial input")?;
+                    events.push(InputEvent::Delta(steps));
+                } else {
+                    let value = value.ok().and_then(|v| v.parse::<u16>().ok());
+                    let value =
+                        (value.ok_or("Unhandled analog serial input")? as f32 / ANALOG_MAX).min(1.);
+                    events.push(InputEvent::Axis(value));
+                    events.extend(self.threshold.update(value));
+                }
+                return Ok(());
+            }
+        }
+        match (&mut self.message, byte) {
+            (Some((_, value)), b) if value.len() < VALUE_MAX_LEN => value.push(b),
+            (Some(_), _) => {
+                self.message = None;
+                return Err("Serial input value too long".to_string());
+            }
+            (None, b'0') => events.push(InputEvent::Release),
+            (None, b'1') => events.push(InputEvent::Press),
+            (None, b'A') | (None, b'D') => {
+                self.message = Some((byte, Vec::with_capacity(VALUE_MAX_LEN)))
+            }
+            // Line endings, from senders that print messages on their own
+            // line.
+            (None, b'\r') | (None, b'\n') => {}
+            (None, b) => return Err(format!("Unhandled serial input: {}", b)),
+        }
+        Ok(())
+    }
+
+    /// Forgets any partially-read message.
+    pub fn reset(&mut self) {
+        self.message = None;
+    }
+}
+
+/// Lists available serial ports.
+pub fn list_ports() {
+    let ports = serialport::available_ports().unwrap_or_else(|_| {
+        error!("Unable to probe for available serial ports!");
+        Vec::with_capacity(0)
+    });
+    if ports.is_empty() {
+        error!("No serial ports found!");
+    } else {
+        for p in ports {
+            error!("* {}", p.port_name);
+        }
+    }
+}
+
+/// Reads events from a footswitch's serial port.
+pub struct SerialSource {
+    serial_device: String,
+    port: Box<dyn SerialPort>,
+    decoder: Decoder,
+}
+
+impl SerialSource {
+    /// Creates a serial source from options in the form
+    /// `PORT[,press=VALUE][,release=VALUE]`.
+    pub fn new(options: &str) -> Result<Self, InputError> {
+        let (serial_device, options) = options.split_once(',').unwrap_or((options, ""));
+        let options = SourceOptions::parse(options, &["press", "release"])?;
+        let threshold = Threshold::from_options(&options)?;
+        if serial_device.is_empty() {
+            return Err(InputError {
+                msg: "No serial device specified".to_string(),
+            });
+        }
+        let port = create_serial_port(serial_device).map_err(|e| InputError {
+            msg: format!("Failed to open {}: {}", serial_device, e.description),
+        })?;
+        Ok(SerialSource {
+            serial_device: serial_device.to_string(),
+            port,
+            decoder: Decoder::new(threshold),
+        })
+    }
+}
+
+impl InputSource for SerialSource {
+    fn name(&self) -> String {
+        format!("Serial port: {}", self.serial_device)
+    }
+
+    /// Sends events from the serial port to the channel.
+    fn run(self: Box<Self>, mut chan: EventSender) {
+        let SerialSource {
+            serial_device,
+            mut port,
+            mut decoder,
+        } = *self;
+        let mut buf = [0; 1];
+        let mut events = Vec::with_capacity(2);
+
+        'outer: loop {
+            'inner: loop {
+                let res = port.read(&mut buf[..]);
+                match res {
+                    Ok(len) => {
+                        if len == 1 {
+                            if let Err(e) = decoder.decode(buf[0], &mut events) {
+                                warn!("{}", e);
+                                break 'inner;
+                            }
+                            for e in events.drain(..) {
+                                if !chan.send(e) {
+                                    // Other end of the channel has probably gone away.
+                                    // Shut down the thread.
+                                    break 'outer;
+                                }
+                            }
+                        } else {
+                            warn!("Unhandled serial input length ({}): {:?}", len, &buf[..len]);
+                            break 'inner;
+                        }
+                    }
+                    Err(error) => match error.kind() {
+                        io::ErrorKind::TimedOut => continue,
+                        _ => {
+                            warn!("Error reading serial device: {:?}", error);
+                            break 'inner;
+                        }
+                    },
+                }
+            }
+
+            // Something went wrong - reset the serial port if possible.
+            decoder.reset();
+            // Don't leave the footswitch held while the port is missing.
+            if !chan.release() {
+                break 'outer;
+            }
+            port = 'reset: loop {
+                match create_serial_port(&serial_device) {
+                    Ok(p) => {
+                        warn!("Reconnecting device {}", &serial_device);
+                        break 'reset p;
+                    }
+                    Err(_) => thread::sleep(MISSING_SERIAL_WAIT_TIME),
+                }
+            };
+        }
+    }
+}
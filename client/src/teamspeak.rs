@@ -0,0 +1,454 @@
+/*
+ * TeamSpeak ClientQuery action, which sets the TeamSpeak 3 client's input
+ * mute or talk state directly.
+ *
+ * ClientQuery is a line-based telnet interface, which needs the API key
+ * from TeamSpeak's ClientQuery plugin settings.
+ */
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::actions::{Action, ActionError};
+
+/// ClientQuery's default address.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:25639";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// ClientQuery disconnects idle clients, so send something this often.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the footswitch changes in TeamSpeak.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeamSpeakMode {
+    /// Unmutes the microphone while pressed (`client_input_muted`).
+    Mute,
+    /// Activates the microphone while pressed, like TeamSpeak's own
+    /// push-to-talk (`client_input_deactivated`).
+    Talk,
+}
+
+impl TeamSpeakMode {
+    fn variable(self) -> &'static str {
+        match self {
+            TeamSpeakMode::Mute => "client_input_muted",
+            TeamSpeakMode::Talk => "client_input_deactivated",
+        }
+    }
+}
+
+/// Escapes a ClientQuery value.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '/' => out.push_str("\\/"),
+            ' ' => out.push_str("\\s"),
+            '|' => out.push_str("\\p"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Unescapes a ClientQuery value.
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => out.push(' '),
+            Some('p') => out.push('|'),
+            Some('/') => out.push('/'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Gets a `key=value` parameter from a ClientQuery reply.
+fn param(item: &str, key: &str) -> Option<String> {
+    item.split(' ')
+        .find_map(|p| p.strip_prefix(key)?.strip_prefix('='))
+        .map(unescape)
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    /// Whether reading or writing has failed, rather than a command.
+    broken: bool,
+}
+
+impl Connection {
+    /// Runs a command, and returns its reply (if any).
+    fn command(&mut self, command: &str) -> Result<String, String> {
+        trace!("ClientQuery: {}", command);
+        if let Err(e) = self.stream.write_all(format!("{}\n", command).as_bytes()) {
+            self.broken = true;
+            return Err(format!("Error writing to TeamSpeak: {}", e));
+        }
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => {
+                    self.broken = true;
+                    return Err("TeamSpeak closed the connection".to_string());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.broken = true;
+                    return Err(format!("Error reading from TeamSpeak: {}", e));
+                }
+            }
+            let line = line.trim_matches(|c| c == '\r' || c == '\n');
+            if line.starts_with("notify") || line.is_empty() {
+                continue;
+            }
+            if let Some(status) = line.strip_prefix("error ") {
+                return match param(status, "id").as_deref() {
+                    Some("0") => Ok(reply),
+                    _ => Err(format!(
+                        "TeamSpeak {} failed: {}",
+                        command.split(' ').next().unwrap_or_default(),
+                        param(status, "msg").unwrap_or_default()
+                    )),
+                };
+            }
+            reply = line.to_string();
+        }
+    }
+
+    /// Connects to ClientQuery, and authenticates with the API key.
+    fn open(addr: &SocketAddr, api_key: &str) -> Result<Self, String> {
+        let stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)
+            .map_err(|e| format!("Failed to connect to TeamSpeak at {}: {}", addr, e))?;
+        stream
+            .set_read_timeout(Some(REPLY_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+
+        // The rest of the greeting is skipped while waiting for the reply to
+        // `auth`.
+        let mut greeting = String::new();
+        reader
+            .read_line(&mut greeting)
+            .map_err(|e| format!("Error reading from TeamSpeak: {}", e))?;
+        if !greeting.starts_with("TS3 Client") {
+            return Err(format!("{} isn't TeamSpeak ClientQuery", addr));
+        }
+
+        let mut conn = Connection {
+            stream,
+            reader,
+            broken: false,
+        };
+        conn.command(&format!("auth apikey={}", escape(api_key)))?;
+        Ok(conn)
+    }
+
+    /// Lists the IDs of TeamSpeak's server tabs.
+    fn server_tabs(&mut self) -> Result<Vec<String>, String> {
+        Ok(self
+            .command("serverconnectionhandlerlist")?
+            .split('|')
+            .filter_map(|i| param(i, "schandlerid"))
+            .collect())
+    }
+
+    /// Gets one of our client variables on a server tab.
+    fn get_variable(&mut self, tab: &str, variable: &str) -> Result<String, String> {
+        self.command(&format!("use schandlerid={}", tab))?;
+        let clid = param(&self.command("whoami")?, "clid").ok_or("No client ID")?;
+        let reply = self.command(&format!("clientvariable clid={} {}", clid, variable))?;
+        param(&reply, variable).ok_or_else(|| format!("No {} in reply", variable))
+    }
+
+    /// Sets one of our client variables on a server tab.
+    fn set_variable(&mut self, tab: &str, variable: &str, value: &str) -> Result<(), String> {
+        self.command(&format!("use schandlerid={}", tab))?;
+        self.command(&format!("clientupdate {}={}", variable, value))
+            .map(|_| ())
+    }
+}
+
+struct Worker {
+    addr: SocketAddr,
+    api_key: String,
+    mode: TeamSpeakMode,
+    conn: Option<Connection>,
+    pressed: bool,
+    /// Each server tab's setting before we changed it.
+    initial: HashMap<String, String>,
+}
+
+impl Worker {
+    fn connect(&mut self) -> Result<(), String> {
+        let conn = Connection::open(&self.addr, &self.api_key)?;
+        info!("Connected to TeamSpeak at {}", self.addr);
+        self.conn = Some(conn);
+        self.apply();
+        Ok(())
+    }
+
+    /// Sets TeamSpeak's state to match the footswitch.
+    fn apply(&mut self) {
+        let conn = match self.conn.as_mut() {
+            Some(c) => c,
+            None => return,
+        };
+        let variable = self.mode.variable();
+        let value = if self.pressed { "0" } else { "1" };
+        let tabs = conn.server_tabs().unwrap_or_else(|e| {
+            warn!("{}", e);
+            Vec::new()
+        });
+        for tab in tabs {
+            if conn.broken {
+                break;
+            }
+            if !self.initial.contains_key(&tab) {
+                match conn.get_variable(&tab, variable) {
+                    Ok(v) => {
+                        self.initial.insert(tab.clone(), v);
+                    }
+                    // Not connected to a server.
+                    Err(e) => {
+                        debug!("Skipping TeamSpeak server tab {}: {}", tab, e);
+                        continue;
+                    }
+                }
+            }
+            if let Err(e) = conn.set_variable(&tab, variable, value) {
+                debug!("Skipping TeamSpeak server tab {}: {}", tab, e);
+            }
+        }
+        if conn.broken {
+            warn!("Lost connection to TeamSpeak");
+            self.conn = None;
+        }
+    }
+
+    /// Keeps the connection from going idle, and checks that TeamSpeak is
+    /// still there.
+    fn keepalive(&mut self) {
+        if let Some(conn) = self.conn.as_mut() {
+            if let Err(e) = conn.command("currentschandlerid") {
+                warn!("{}", e);
+                if conn.broken {
+                    self.conn = None;
+                }
+            }
+        }
+    }
+
+    /// Puts back each server tab's original setting.
+    fn restore(&mut self) {
+        if let Some(conn) = self.conn.as_mut() {
+            for (tab, value) in self.initial.iter() {
+                let _ = conn.set_variable(tab, self.mode.variable(), value);
+            }
+        }
+    }
+
+    /// Follows the footswitch's state from `states`, reconnecting whenever
+    /// TeamSpeak goes away, until `states` is closed.
+    ///
+    /// `_running` is dropped when this finishes.
+    fn run(mut self, states: Receiver<bool>, _running: Sender<()>) {
+        let mut first = true;
+        loop {
+            if self.conn.is_none() {
+                match self.connect() {
+                    Err(e) if first => warn!("{}; will keep trying to connect", e),
+                    Err(e) => debug!("{}", e),
+                    Ok(()) => {}
+                }
+                first = false;
+            }
+            let timeout = if self.conn.is_some() {
+                KEEPALIVE_INTERVAL
+            } else {
+                RECONNECT_INTERVAL
+            };
+            match states.recv_timeout(timeout) {
+                Ok(pressed) => {
+                    // Skip over states that have already been replaced.
+                    self.pressed = states.try_iter().last().unwrap_or(pressed);
+                    self.apply();
+                }
+                Err(RecvTimeoutError::Timeout) => self.keepalive(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.restore();
+    }
+}
+
+/// Sets TeamSpeak's input mute or talk state when the footswitch changes
+/// state, on every server tab that's connected.
+///
+/// TeamSpeak is driven from another thread, so that a slow or missing
+/// TeamSpeak never holds up the footswitch. If TeamSpeak goes away, this
+/// keeps trying to reconnect, and applies the footswitch's current state when
+/// it comes back.
+pub struct TeamSpeakAction {
+    /// Footswitch states for the worker to apply.
+    states: Option<Sender<bool>>,
+    /// Disconnected when the worker has finished.
+    running: Receiver<()>,
+}
+
+impl TeamSpeakAction {
+    pub fn new(addr: SocketAddr, api_key: &str, mode: TeamSpeakMode) -> Self {
+        let worker = Worker {
+            addr,
+            api_key: api_key.to_string(),
+            mode,
+            conn: None,
+            pressed: false,
+            initial: HashMap::new(),
+        };
+        let (states, rx) = mpsc::channel();
+        let (running_tx, running) = mpsc::channel();
+        thread::spawn(move || worker.run(rx, running_tx));
+        TeamSpeakAction {
+            states: Some(states),
+            running,
+        }
+    }
+
+    fn send(&mut self, pressed: bool) {
+        if let Some(states) = self.states.as_ref() {
+            let _ = states.send(pressed);
+        }
+    }
+}
+
+impl Action for TeamSpeakAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.send(true);
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.send(false);
+        Ok(())
+    }
+}
+
+impl Drop for TeamSpeakAction {
+    fn drop(&mut self) {
+        // Give the worker a chance to restore TeamSpeak's settings.
+        self.states = None;
+        let _ = self.running.recv_timeout(REPLY_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn escaping() {
+        let value = "a b|c/d\\e\tf";
+        assert_eq!(escape(value), "a\\sb\\pc\\/d\\\\e\\tf");
+        assert_eq!(unescape(&escape(value)), value);
+        assert_eq!(
+            param("clid=5 client_nickname=foo\\sbar", "client_nickname").as_deref(),
+            Some("foo bar")
+        );
+    }
+
+    /// A stand-in for ClientQuery, with one server tab connected to a
+    /// server, and another that isn't. `clientupdate` values are sent to
+    /// `updates`.
+    fn serve(listener: TcpListener, updates: mpsc::Sender<String>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        stream
+            .write_all(b"TS3 Client\n\rWelcome to the TeamSpeak 3 ClientQuery interface.\n\r")
+            .unwrap();
+        let mut tab = String::new();
+        for line in reader.lines() {
+            let line = line.unwrap();
+            let (command, args) = line.split_once(' ').unwrap_or((&line, ""));
+            let reply = match command {
+                "auth" => {
+                    assert_eq!(args, "apikey=ABCD\\s1234\\p5678");
+                    None
+                }
+                "serverconnectionhandlerlist" => Some("schandlerid=1|schandlerid=2"),
+                "use" => {
+                    tab = param(args, "schandlerid").unwrap();
+                    None
+                }
+                "whoami" if tab == "2" => {
+                    stream
+                        .write_all(b"error id=1794 msg=not\\sconnected\n\r")
+                        .unwrap();
+                    continue;
+                }
+                "whoami" => Some("clid=5 cid=1"),
+                "clientvariable" => {
+                    assert_eq!(args, "clid=5 client_input_muted");
+                    Some("clid=5 client_input_muted=0")
+                }
+                "clientupdate" => {
+                    assert_eq!(tab, "1");
+                    updates
+                        .send(param(args, "client_input_muted").unwrap())
+                        .unwrap();
+                    None
+                }
+                _ => panic!("Unexpected command {:?}", line),
+            };
+            // Events can arrive at any time.
+            let mut out = "notifytalkstatuschange schandlerid=1 status=0 clid=7\n\r".to_string();
+            if let Some(reply) = reply {
+                out.push_str(&format!("{}\n\r", reply));
+            }
+            out.push_str("error id=0 msg=ok\n\r");
+            stream.write_all(out.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn press_and_release() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (updates_tx, updates) = mpsc::channel();
+        let server = thread::spawn(move || serve(listener, updates_tx));
+        let next = || updates.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let mut action = TeamSpeakAction::new(addr, "ABCD 1234|5678", TeamSpeakMode::Mute);
+        assert_eq!(next(), "1");
+        action.press().unwrap();
+        assert_eq!(next(), "0");
+        action.release().unwrap();
+        assert_eq!(next(), "1");
+        drop(action);
+        // The original setting is restored.
+        assert_eq!(next(), "0");
+        server.join().unwrap();
+    }
+}
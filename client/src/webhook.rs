@@ -0,0 +1,316 @@
+/*
+ * Webhook action, which POSTs JSON to URLs when the footswitch changes
+ * state (eg: for Shelly relays, chat bots or dashboards).
+ *
+ * Requests are sent from a thread for each URL, so that slow or broken
+ * endpoints never hold up the footswitch.
+ */
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::actions::{Action, ActionError};
+
+pub const DEFAULT_BODY: &str =
+    r#"{"event": "{event}", "timestamp": {timestamp}, "message": "{message}"}"#;
+/// Maximum number of requests waiting to be sent to each URL. Any more are
+/// dropped.
+const QUEUE_SIZE: usize = 32;
+/// How long to wait before the first retry, which doubles for each one after
+/// that.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    /// Request body, where `{event}`, `{timestamp}` and `{message}` are
+    /// replaced.
+    pub body: String,
+    pub timeout: Duration,
+    /// Number of times to retry a request that fails.
+    pub retries: u32,
+}
+
+/// Escapes a string to go inside a JSON string.
+fn escape(s: &str) -> String {
+    let quoted = serde_json::Value::from(s).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Fills in a body template.
+fn expand(template: &str, event: &str, message: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    template
+        .replace("{event}", event)
+        .replace("{timestamp}", &timestamp.to_string())
+        .replace("{message}", &escape(message))
+}
+
+/// Sends requests from `queue` to `url`, until the queue is closed.
+///
+/// `_running` is dropped when this finishes.
+fn send_requests(
+    url: String,
+    config: WebhookConfig,
+    queue: Receiver<String>,
+    _running: mpsc::Sender<()>,
+) {
+    let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+    for body in queue {
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 0..=config.retries {
+            if attempt > 0 {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            let res = agent
+                .post(&url)
+                .set("Content-Type", "application/json")
+                .send_string(&body);
+            match res {
+                Ok(_) => {
+                    debug!("Webhook sent to {}", url);
+                    break;
+                }
+                // Don't retry requests the endpoint didn't like.
+                Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => {
+                    warn!("Webhook to {} failed: HTTP {}", url, code);
+                    break;
+                }
+                Err(e) if attempt < config.retries => {
+                    debug!("Webhook to {} failed, retrying: {}", url, e)
+                }
+                Err(e) => warn!("Webhook to {} failed: {}", url, e),
+            }
+        }
+    }
+}
+
+/// A URL, and the queue of requests for its thread to send.
+struct Endpoint {
+    url: String,
+    queue: SyncSender<String>,
+}
+
+/// POSTs to webhooks when the footswitch is pressed or released, or when the
+/// client stops because of an error.
+pub struct WebhookAction {
+    body: String,
+    timeout: Duration,
+    endpoints: Vec<Endpoint>,
+    /// Disconnected when all of the threads have finished.
+    running: Receiver<()>,
+}
+
+impl WebhookAction {
+    pub fn new(config: WebhookConfig) -> Self {
+        let (running_tx, running) = mpsc::channel();
+        let endpoints = config
+            .urls
+            .iter()
+            .map(|url| {
+                let (queue, rx) = mpsc::sync_channel(QUEUE_SIZE);
+                let url = url.clone();
+                let config = config.clone();
+                let running = running_tx.clone();
+                thread::spawn({
+                    let url = url.clone();
+                    move || send_requests(url, config, rx, running)
+                });
+                Endpoint { url, queue }
+            })
+            .collect();
+        WebhookAction {
+            body: config.body,
+            timeout: config.timeout,
+            endpoints,
+            running,
+        }
+    }
+
+    /// Queues a request to every URL.
+    fn send(&mut self, event: &str, message: &str) {
+        let body = expand(&self.body, event, message);
+        for e in self.endpoints.iter() {
+            match e.queue.try_send(body.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Too many webhooks waiting for {}, dropping {}",
+                        e.url, event
+                    )
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+impl Action for WebhookAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.send("press", "");
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.send("release", "");
+        Ok(())
+    }
+
+    fn error(&mut self, msg: &str) {
+        self.send("error", msg);
+    }
+}
+
+impl Drop for WebhookAction {
+    fn drop(&mut self) {
+        // Give the last requests (eg: the final release, or an error) a
+        // chance to be sent, but don't wait for retries.
+        self.endpoints.clear();
+        let _ = self.running.recv_timeout(self.timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    /// A stand-in for a webhook endpoint, which sends the body of each
+    /// request it receives to the channel, then waits for a status code to
+    /// answer it with from `statuses`.
+    fn serve(statuses: Receiver<u16>) -> (String, Receiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let statuses = Arc::new(Mutex::new(statuses));
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let statuses = statuses.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut length = 0;
+                        loop {
+                            let mut line = String::new();
+                            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                                return;
+                            }
+                            let line = line.trim_end().to_lowercase();
+                            if line.is_empty() {
+                                break;
+                            }
+                            if let Some(l) = line.strip_prefix("content-length:") {
+                                length = l.trim().parse().unwrap();
+                            }
+                        }
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+                        let _ = tx.send(serde_json::from_slice(&body).unwrap());
+                        let status = statuses.lock().unwrap().recv().unwrap_or(200);
+                        let reply =
+                            format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+                        if stream.write_all(reply.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (url, rx)
+    }
+
+    fn webhook(url: String) -> WebhookAction {
+        WebhookAction::new(WebhookConfig {
+            urls: vec![url],
+            body: DEFAULT_BODY.to_string(),
+            timeout: Duration::from_secs(10),
+            retries: 3,
+        })
+    }
+
+    fn next(requests: &Receiver<serde_json::Value>) -> String {
+        let body = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        body["event"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn bodies() {
+        let body = expand(DEFAULT_BODY, "error", "a\"b\n");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["event"], "error");
+        assert_eq!(body["message"], "a\"b\n");
+        assert!(body["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn retries() {
+        let (statuses_tx, statuses) = mpsc::channel();
+        let (url, requests) = serve(statuses);
+        for status in [500, 200, 404, 429, 200] {
+            statuses_tx.send(status).unwrap();
+        }
+        let mut action = webhook(url);
+
+        // Server errors are retried.
+        action.press().unwrap();
+        assert_eq!(next(&requests), "press");
+        assert_eq!(next(&requests), "press");
+        // Other errors aren't, except for being rate limited.
+        action.release().unwrap();
+        action.error("oops");
+        assert_eq!(next(&requests), "release");
+        assert_eq!(next(&requests), "error");
+        assert_eq!(next(&requests), "error");
+        drop(action);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn full_queue() {
+        let (statuses_tx, statuses) = mpsc::channel();
+        let (url, requests) = serve(statuses);
+        let mut action = webhook(url);
+
+        // Hold up the first request, then fill up the queue behind it.
+        action.press().unwrap();
+        assert_eq!(next(&requests), "press");
+        let start = Instant::now();
+        for _ in 0..QUEUE_SIZE {
+            action.release().unwrap();
+        }
+        // Any more are dropped, rather than holding up the footswitch.
+        for _ in 0..5 {
+            action.press().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        drop(statuses_tx);
+        drop(action);
+        let sent: Vec<_> = requests.try_iter().collect();
+        assert_eq!(sent.len(), QUEUE_SIZE);
+        assert!(sent.iter().all(|body| body["event"] == "release"));
+    }
+
+    #[test]
+    fn flush_on_drop() {
+        let (statuses_tx, statuses) = mpsc::channel();
+        let (url, requests) = serve(statuses);
+        statuses_tx.send(200).unwrap();
+        let mut action = webhook(url);
+        action.error("Lost the footswitch");
+        drop(action);
+        let body = requests.try_recv().unwrap();
+        assert_eq!(body["event"], "error");
+        assert_eq!(body["message"], "Lost the footswitch");
+    }
+}
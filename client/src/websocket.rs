@@ -0,0 +1,448 @@
+/*
+ * WebSocket server, which pushes the footswitch's state to browsers (eg: a
+ * stream overlay in OBS), and serves a bundled overlay page on the same port.
+ *
+ * Each state change is sent to every client as JSON:
+ *
+ *     {"event": "press", "pressed": true, "force_muted": false,
+ *      "timestamp": 1700000000000}
+ *
+ * where `event` is `state` (when the client connects), `press`, `release` or
+ * `force_mute`, and `timestamp` is in milliseconds since the Unix epoch.
+ *
+ * Browsers may only connect from the overlay page itself (or another page
+ * on the same origin), so that other websites can't watch the footswitch.
+ */
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tungstenite::Message;
+
+use crate::actions::{Action, ActionError};
+
+const OVERLAY: &str = include_str!("overlay.html");
+/// Longest HTTP request head we'll read.
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Clients which can't take a message in this long are disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often to ping clients, to find ones that have gone away.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Clients which don't send anything (eg: a pong) for this long are
+/// disconnected.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(75);
+/// How often to read frames (eg: pongs and close requests) from clients.
+const READ_INTERVAL: Duration = Duration::from_millis(250);
+/// How long to wait for a frame when reading.
+const READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+#[derive(Default)]
+struct Shared {
+    pressed: bool,
+    force_muted: bool,
+    /// Queues of messages for each client's thread to send.
+    clients: Vec<Sender<String>>,
+}
+
+impl Shared {
+    fn message(&self, event: &str) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        json!({
+            "event": event,
+            "pressed": self.pressed,
+            "force_muted": self.force_muted,
+            "timestamp": timestamp,
+        })
+        .to_string()
+    }
+
+    /// Sends a state change to every client, and forgets clients which have
+    /// gone away.
+    fn broadcast(&mut self, event: &str) {
+        let msg = self.message(event);
+        self.clients.retain(|c| c.send(msg.clone()).is_ok());
+    }
+}
+
+/// Reads an HTTP request head without consuming it, so that it can be
+/// handed to tungstenite if it's a WebSocket handshake.
+fn peek_request(stream: &TcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    loop {
+        let len = stream.peek(&mut buf)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") || len == buf.len() {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Gets the value of a header from an HTTP request head.
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request.lines().skip(1).find_map(|l| {
+        let (k, v) = l.split_once(':')?;
+        if k.trim().eq_ignore_ascii_case(name) {
+            Some(v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns `true` if `host` (from a `Host` header) is an IP address or
+/// `localhost`, rather than a name that could be rebound to this machine.
+fn is_local_host(host: &str) -> bool {
+    if host.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.parse::<IpAddr>().is_ok() || name.eq_ignore_ascii_case("localhost")
+}
+
+/// Returns `true` if a WebSocket request comes from a page we served, or
+/// from something other than a browser (which doesn't send an origin).
+///
+/// Browsers must also have reached us by address, so that a website can't
+/// point its own name at this machine (DNS rebinding) to become our origin.
+fn is_allowed_origin(request: &str) -> bool {
+    match (header(request, "origin"), header(request, "host")) {
+        (None, _) => true,
+        (Some(origin), Some(host)) => {
+            is_local_host(host)
+                && (origin.eq_ignore_ascii_case(&format!("http://{}", host))
+                    || origin.eq_ignore_ascii_case(&format!("https://{}", host)))
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// Serves the overlay page, or a 404 for anything else.
+fn serve_page(mut stream: TcpStream, request: &str, len: usize) -> io::Result<()> {
+    // Consume the request we peeked at.
+    stream.read_exact(&mut vec![0; len])?;
+
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+    let (status, content_type, body) = match path {
+        "/" | "/overlay.html" => ("200 OK", "text/html; charset=utf-8", OVERLAY),
+        _ => ("404 Not Found", "text/plain", "Not found\n"),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Refuses a WebSocket request from another website.
+fn forbid(mut stream: TcpStream) -> io::Result<()> {
+    stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+}
+
+/// Sends state changes to a WebSocket client, until it goes away.
+///
+/// Messages are sent as soon as they're queued. In between, frames from the
+/// client are read every `READ_INTERVAL`, so that pings are answered, close
+/// requests are completed, and clients which stop answering our pings are
+/// disconnected.
+fn serve_client(stream: TcpStream, peer: SocketAddr, shared: Arc<Mutex<Shared>>) {
+    let mut ws = match tungstenite::accept(stream) {
+        Ok(ws) => ws,
+        Err(e) => {
+            debug!("WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    if let Err(e) = ws.get_ref().set_read_timeout(Some(READ_TIMEOUT)) {
+        debug!("Error setting up WebSocket client {}: {}", peer, e);
+        return;
+    }
+    debug!("WebSocket client connected: {}", peer);
+    let (tx, queue) = mpsc::channel();
+    {
+        let mut shared = shared.lock().unwrap();
+        let _ = tx.send(shared.message("state"));
+        shared.clients.push(tx);
+    }
+    let mut last_ping = Instant::now();
+    let mut last_heard = Instant::now();
+    loop {
+        let msg = match queue.recv_timeout(READ_INTERVAL) {
+            Ok(m) => Some(Message::Text(m)),
+            Err(RecvTimeoutError::Timeout) if last_ping.elapsed() >= PING_INTERVAL => {
+                last_ping = Instant::now();
+                Some(Message::Ping(Vec::new()))
+            }
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = ws.close(None);
+                let _ = ws.flush();
+                break;
+            }
+        };
+        if let Some(msg) = msg {
+            if let Err(e) = ws.send(msg) {
+                debug!("WebSocket client {} went away: {}", peer, e);
+                break;
+            }
+        }
+
+        // Read everything the client has sent, which also answers pings and
+        // close requests.
+        let error = loop {
+            match ws.read() {
+                Ok(_) => last_heard = Instant::now(),
+                Err(e) => break e,
+            }
+        };
+        match error {
+            tungstenite::Error::Io(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            tungstenite::Error::ConnectionClosed => {
+                debug!("WebSocket client {} disconnected", peer);
+                break;
+            }
+            e => {
+                debug!("WebSocket client {} went away: {}", peer, e);
+                break;
+            }
+        }
+        if last_heard.elapsed() >= CLIENT_TIMEOUT {
+            debug!("WebSocket client {} stopped answering pings", peer);
+            break;
+        }
+    }
+}
+
+/// Accepts HTTP and WebSocket connections.
+fn serve(listener: TcpListener, shared: Arc<Mutex<Shared>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Error accepting WebSocket connection: {}", e);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let peer = match stream.peer_addr() {
+                Ok(p) => p,
+                Err(_) => return,
+            };
+            if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err()
+                || stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err()
+            {
+                return;
+            }
+            let (request, len) = match peek_request(&stream) {
+                Ok(r) => (String::from_utf8_lossy(&r).into_owned(), r.len()),
+                Err(e) => {
+                    debug!("Bad request from {}: {}", peer, e);
+                    return;
+                }
+            };
+            let upgrade =
+                header(&request, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+            if upgrade && !is_allowed_origin(&request) {
+                debug!(
+                    "Refusing WebSocket client {} from {:?}",
+                    peer,
+                    header(&request, "origin")
+                );
+                let _ = forbid(stream);
+            } else if upgrade {
+                serve_client(stream, peer, shared);
+            } else if let Err(e) = serve_page(stream, &request, len) {
+                debug!("Error serving {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Pushes the footswitch's state to WebSocket clients.
+pub struct WebSocketAction {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl WebSocketAction {
+    pub fn new(addr: SocketAddr) -> Result<Self, ActionError> {
+        let listener = TcpListener::bind(addr).map_err(|e| ActionError {
+            msg: format!("Failed to listen on {}: {}", addr, e),
+        })?;
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        {
+            let shared = shared.clone();
+            thread::spawn(move || serve(listener, shared));
+        }
+        Ok(WebSocketAction { shared })
+    }
+
+    fn update(&mut self, event: &str, f: impl FnOnce(&mut Shared)) {
+        let mut shared = self.shared.lock().unwrap();
+        f(&mut shared);
+        shared.broadcast(event);
+    }
+}
+
+impl Action for WebSocketAction {
+    fn press(&mut self) -> Result<(), ActionError> {
+        self.update("press", |s| s.pressed = true);
+        Ok(())
+    }
+
+    fn release(&mut self) -> Result<(), ActionError> {
+        self.update("release", |s| s.pressed = false);
+        Ok(())
+    }
+
+    fn force_mute(&mut self, muted: bool) -> Result<(), ActionError> {
+        self.update("force_mute", |s| s.force_muted = muted);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::http::HeaderValue;
+
+    /// Starts a server on a free port.
+    fn server() -> (WebSocketAction, SocketAddr) {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap();
+        (WebSocketAction::new(addr).unwrap(), addr)
+    }
+
+    /// Connects to the server, or returns the HTTP status it refused with.
+    fn connect(
+        addr: SocketAddr,
+        origin: Option<&str>,
+    ) -> Result<tungstenite::WebSocket<TcpStream>, u16> {
+        let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+        if let Some(origin) = origin {
+            request
+                .headers_mut()
+                .insert("Origin", HeaderValue::from_str(origin).unwrap());
+        }
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT)).unwrap();
+        tungstenite::client(request, stream)
+            .map(|(ws, _)| ws)
+            .map_err(|e| match e {
+                tungstenite::HandshakeError::Failure(tungstenite::Error::Http(r)) => {
+                    r.status().as_u16()
+                }
+                e => panic!("{}", e),
+            })
+    }
+
+    fn next_event(ws: &mut tungstenite::WebSocket<TcpStream>) -> serde_json::Value {
+        match ws.read().unwrap() {
+            Message::Text(t) => serde_json::from_str(&t).unwrap(),
+            m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn origins() {
+        let request = "GET / HTTP/1.1\r\nHost: 127.0.0.1:8765\r\n";
+        assert!(is_allowed_origin(request));
+        assert!(is_allowed_origin(&format!(
+            "{}Origin: http://127.0.0.1:8765\r\n",
+            request
+        )));
+        assert!(!is_allowed_origin(&format!(
+            "{}Origin: https://example.com\r\n",
+            request
+        )));
+        assert!(!is_allowed_origin(&format!(
+            "{}Origin: http://127.0.0.1:8766\r\n",
+            request
+        )));
+        assert!(!is_allowed_origin(
+            "GET / HTTP/1.1\r\nOrigin: http://127.0.0.1:8765\r\n"
+        ));
+        // Websites can't rebind their own names to us.
+        assert!(!is_allowed_origin(
+            "GET / HTTP/1.1\r\nHost: evil.example:8765\r\nOrigin: http://evil.example:8765\r\n"
+        ));
+        assert!(is_allowed_origin(
+            "GET / HTTP/1.1\r\nHost: localhost:8765\r\nOrigin: http://localhost:8765\r\n"
+        ));
+        assert!(is_allowed_origin(
+            "GET / HTTP/1.1\r\nHost: [::1]:8765\r\nOrigin: http://[::1]:8765\r\n"
+        ));
+    }
+
+    #[test]
+    fn hosts() {
+        assert!(is_local_host("127.0.0.1:8765"));
+        assert!(is_local_host("192.168.1.2"));
+        assert!(is_local_host("[::1]:8765"));
+        assert!(is_local_host("[fe80::1]"));
+        assert!(is_local_host("LocalHost:8765"));
+        assert!(!is_local_host("evil.example:8765"));
+        assert!(!is_local_host("localhost.evil.example"));
+        assert!(!is_local_host("127.0.0.1.evil.example:8765"));
+        assert!(!is_local_host(""));
+    }
+
+    #[test]
+    fn clients() {
+        let (mut action, addr) = server();
+
+        let mut ws = connect(addr, Some(&format!("http://{}", addr))).unwrap();
+        assert_eq!(next_event(&mut ws)["event"], "state");
+        action.press().unwrap();
+        let event = next_event(&mut ws);
+        assert_eq!(event["event"], "press");
+        assert_eq!(event["pressed"], true);
+
+        // Pings are answered.
+        ws.send(Message::Ping(b"hello".to_vec())).unwrap();
+        assert_eq!(ws.read().unwrap(), Message::Pong(b"hello".to_vec()));
+
+        // Closing is completed, and the client is forgotten.
+        ws.close(None).unwrap();
+        loop {
+            match ws.read() {
+                Ok(Message::Close(_)) => {}
+                Ok(m) => panic!("Unexpected message {:?}", m),
+                Err(tungstenite::Error::ConnectionClosed) => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        thread::sleep(READ_INTERVAL * 2);
+        action.release().unwrap();
+        assert!(action.shared.lock().unwrap().clients.is_empty());
+
+        // Other websites can't connect, but other programs can.
+        assert_eq!(connect(addr, Some("https://example.com")).err(), Some(403));
+        let mut ws = connect(addr, None).unwrap();
+        assert_eq!(next_event(&mut ws)["pressed"], false);
+    }
+}
@@ -8,7 +8,10 @@ use std::ptr::null_mut;
 use std::result::Result;
 use std::slice;
 
-use crate::audio_controller::{AudioControllerTrait, AudioError, AudioInputDeviceTrait};
+use crate::audio_controller::{
+    adjust_cubic_volume, AudioControllerTrait, AudioError, AudioInputDeviceTrait,
+    AudioOutputDeviceTrait,
+};
 
 use winapi::{
     shared::{
@@ -17,12 +20,13 @@ use winapi::{
         wtypesbase::CLSCTX_INPROC_SERVER,
     },
     um::{
-        combaseapi::{CoCreateInstance, CLSCTX_ALL},
+        combaseapi::{CoCreateInstance, CoTaskMemFree, CLSCTX_ALL},
         coml2api::STGM_READ,
         endpointvolume::IAudioEndpointVolume,
         functiondiscoverykeys_devpkey::PKEY_Device_FriendlyName,
         mmdeviceapi::{
-            eCapture, eCommunications, CLSID_MMDeviceEnumerator, IMMDevice, IMMDeviceEnumerator,
+            eCapture, eCommunications, eConsole, eRender, CLSID_MMDeviceEnumerator, IMMDevice,
+            IMMDeviceCollection, IMMDeviceEnumerator, DEVICE_STATE_ACTIVE,
         },
         objbase::CoInitialize,
         propkeydef::REFPROPERTYKEY,
@@ -55,11 +59,43 @@ macro_rules! try_com {
 
 pub struct AudioController {}
 
+/// Gets the volume scalar which is `db` decibels away from `volume`.
+///
+/// Volume scalars are audio-tapered, and Windows can't convert between them
+/// and decibels without changing the device's volume. Instead, this fits
+/// `level = max + taper * log10(scalar)` to the device's current scalar and
+/// level, and falls back to a cubic taper when that can't be fitted (at full
+/// or no volume).
+unsafe fn adjust_volume_db(
+    endpoint: *mut IAudioEndpointVolume,
+    volume: f32,
+    db: f32,
+) -> Result<f32, AudioError> {
+    let (mut scalar, mut level) = (0f32, 0f32);
+    try_com!((*endpoint).GetMasterVolumeLevelScalar(&mut scalar));
+    try_com!((*endpoint).GetMasterVolumeLevel(&mut level));
+    let (mut min, mut max, mut step) = (0f32, 0f32, 0f32);
+    try_com!((*endpoint).GetVolumeRange(&mut min, &mut max, &mut step));
+    if scalar < 0.01 || scalar > 0.99 || level > max - 0.1 {
+        return Ok(adjust_cubic_volume(volume, db));
+    }
+    let taper = (level - max) / scalar.log10();
+    // Don't go below the quietest level the device supports.
+    let floor = 10f32.powf((min - max) / taper).min(volume);
+    Ok((volume * 10f32.powf(db / taper)).max(floor).min(1.))
+}
+
 pub struct AudioInputDevice {
     name: String,
     audio_endpoint_volume: *mut IAudioEndpointVolume,
 }
 
+pub struct AudioOutputDevice {
+    id: String,
+    name: String,
+    audio_endpoint_volume: *mut IAudioEndpointVolume,
+}
+
 impl AudioController {
     fn get_device_enumerator(&self) -> Result<*mut IMMDeviceEnumerator, AudioError> {
         unsafe {
@@ -90,16 +126,66 @@ impl AudioController {
             Ok(mm_device.assume_init())
         }
     }
+
+    fn get_default_render_imm_device(
+        &self,
+        device_enumerator: *mut IMMDeviceEnumerator,
+    ) -> Result<*mut IMMDevice, AudioError> {
+        unsafe {
+            let mut mm_device = mem::MaybeUninit::uninit();
+
+            try_com!((*device_enumerator).GetDefaultAudioEndpoint(
+                eRender,
+                eConsole,
+                mm_device.as_mut_ptr()
+            ));
+            Ok(mm_device.assume_init())
+        }
+    }
+
+    fn get_render_imm_devices(
+        &self,
+        device_enumerator: *mut IMMDeviceEnumerator,
+    ) -> Result<Vec<*mut IMMDevice>, AudioError> {
+        unsafe {
+            let mut collection = mem::MaybeUninit::<*mut IMMDeviceCollection>::uninit();
+            try_com!((*device_enumerator).EnumAudioEndpoints(
+                eRender,
+                DEVICE_STATE_ACTIVE,
+                collection.as_mut_ptr()
+            ));
+            let collection = collection.assume_init();
+
+            let mut count = 0;
+            try_com!((*collection).GetCount(&mut count));
+            let mut devices = Vec::with_capacity(count as usize);
+            for i in 0..count {
+                let mut mm_device = mem::MaybeUninit::uninit();
+                try_com!((*collection).Item(i, mm_device.as_mut_ptr()));
+                devices.push(mm_device.assume_init());
+            }
+            (*collection).Release();
+            Ok(devices)
+        }
+    }
 }
 
-impl AudioControllerTrait for AudioController {
-    fn new() -> Box<dyn AudioControllerTrait> {
+/// Gets the native audio controller for this platform, if there is one.
+pub fn native_audio_controller() -> Option<Box<dyn AudioControllerTrait>> {
+    Some(AudioController::new())
+}
+
+impl AudioController {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> Box<dyn AudioControllerTrait> {
         unsafe {
             CoInitialize(null_mut());
         }
         Box::new(AudioController {})
     }
+}
 
+impl AudioControllerTrait for AudioController {
     fn get_comms_device(&self) -> Result<Box<dyn AudioInputDeviceTrait>, AudioError> {
         let device_enumerator = self.get_device_enumerator()?;
 
@@ -107,6 +193,25 @@ impl AudioControllerTrait for AudioController {
             self.get_default_communications_imm_device(device_enumerator)?,
         )?))
     }
+
+    fn get_default_output_device(&self) -> Result<Box<dyn AudioOutputDeviceTrait>, AudioError> {
+        let device_enumerator = self.get_device_enumerator()?;
+
+        Ok(Box::new(AudioOutputDevice::new(
+            self.get_default_render_imm_device(device_enumerator)?,
+        )?))
+    }
+
+    fn get_output_devices(&self) -> Result<Vec<Box<dyn AudioOutputDeviceTrait>>, AudioError> {
+        let device_enumerator = self.get_device_enumerator()?;
+
+        self.get_render_imm_devices(device_enumerator)?
+            .into_iter()
+            .map(|d| {
+                AudioOutputDevice::new(d).map(|d| Box::new(d) as Box<dyn AudioOutputDeviceTrait>)
+            })
+            .collect()
+    }
 }
 
 impl AudioInputDevice {
@@ -161,6 +266,33 @@ impl AudioInputDevice {
     }
 }
 
+impl AudioOutputDevice {
+    fn new(mm_device: *mut IMMDevice) -> Result<AudioOutputDevice, AudioError> {
+        // Read properties
+        let props = AudioInputDevice::open_property_store(mm_device)?;
+
+        Ok(AudioOutputDevice {
+            id: AudioOutputDevice::get_id(mm_device)?,
+            name: AudioInputDevice::get_property_value(props, &PKEY_Device_FriendlyName)?,
+            audio_endpoint_volume: AudioInputDevice::get_endpoint_volume(mm_device)?,
+        })
+    }
+
+    fn get_id(mm_device: *mut IMMDevice) -> Result<String, AudioError> {
+        unsafe {
+            let mut id = mem::MaybeUninit::uninit();
+            try_com!((*mm_device).GetId(id.as_mut_ptr()));
+            let id = id.assume_init();
+
+            let r = OsString::from_wide(from_ptr(id))
+                .to_string_lossy()
+                .into_owned();
+            CoTaskMemFree(id as *mut _);
+            Ok(r)
+        }
+    }
+}
+
 unsafe fn from_ptr<'a>(ptr: *const u16) -> &'a [u16] {
     let len = lstrlenW(ptr);
     slice::from_raw_parts(ptr, len.try_into().unwrap())
@@ -178,4 +310,60 @@ impl AudioInputDeviceTrait for AudioInputDevice {
             ))
         }
     }
+
+    fn get_mute(&self) -> Result<bool, AudioError> {
+        let mut state: BOOL = 0;
+        unsafe {
+            try_com!((*self.audio_endpoint_volume).GetMute(&mut state));
+        }
+        Ok(state != 0)
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        let mut volume: f32 = 0.;
+        unsafe {
+            try_com!((*self.audio_endpoint_volume).GetMasterVolumeLevelScalar(&mut volume));
+        }
+        Ok(volume)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        unsafe {
+            try_com!((*self.audio_endpoint_volume).SetMasterVolumeLevelScalar(volume, null_mut()));
+        }
+        Ok(())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        unsafe { adjust_volume_db(self.audio_endpoint_volume, volume, db) }
+    }
+}
+
+impl AudioOutputDeviceTrait for AudioOutputDevice {
+    fn id(&self) -> Result<String, AudioError> {
+        Ok(self.id.clone())
+    }
+
+    fn name(&self) -> Result<String, AudioError> {
+        Ok(self.name.clone())
+    }
+
+    fn get_volume(&self) -> Result<f32, AudioError> {
+        let mut volume: f32 = 0.;
+        unsafe {
+            try_com!((*self.audio_endpoint_volume).GetMasterVolumeLevelScalar(&mut volume));
+        }
+        Ok(volume)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), AudioError> {
+        unsafe {
+            try_com!((*self.audio_endpoint_volume).SetMasterVolumeLevelScalar(volume, null_mut()));
+        }
+        Ok(())
+    }
+
+    fn adjust_volume_db(&self, volume: f32, db: f32) -> Result<f32, AudioError> {
+        unsafe { adjust_volume_db(self.audio_endpoint_volume, volume, db) }
+    }
 }
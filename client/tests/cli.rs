@@ -0,0 +1,102 @@
+/*
+ * End-to-end tests, which drive the client with commands and watch what it
+ * does through a webhook.
+ */
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// Runs an HTTP server, which sends the `event` of each webhook it receives
+/// to the channel.
+fn webhook_server() -> (String, Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let tx = tx.clone();
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let line = line.trim_end().to_lowercase();
+                        if line.is_empty() {
+                            break;
+                        }
+                        if let Some(l) = line.strip_prefix("content-length:") {
+                            length = l.trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .unwrap();
+                    let _ = tx.send(body["event"].as_str().unwrap().to_string());
+                }
+            });
+        }
+    });
+    (url, rx)
+}
+
+fn footswitch(source: &str, url: &str) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_footswitch"))
+        .args(["--source", source, "--audio", "fake", "--debounce", "0"])
+        .args(["--webhook", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+fn next(events: &Receiver<String>) -> String {
+    events.recv_timeout(Duration::from_secs(10)).unwrap()
+}
+
+#[test]
+fn stdin_commands() {
+    let (url, events) = webhook_server();
+    let mut child = footswitch("stdin", &url);
+    let mut stdin = child.stdin.take().unwrap();
+
+    stdin.write_all(b"press\n").unwrap();
+    assert_eq!(next(&events), "press");
+    // Bad commands are skipped.
+    stdin.write_all(b"bogus\nrelease\n").unwrap();
+    assert_eq!(next(&events), "release");
+
+    // The client stops at the end of stdin, releasing any held buttons.
+    stdin.write_all(b"press 2\n").unwrap();
+    assert_eq!(next(&events), "press");
+    drop(stdin);
+    assert_eq!(next(&events), "release");
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn file_commands() {
+    let path = env::temp_dir().join(format!("footswitch-test-{}.txt", std::process::id()));
+    fs::write(&path, "# Comment\npress\n\nrelease\npress 3\n").unwrap();
+    let (url, events) = webhook_server();
+    let mut child = footswitch(&format!("file:{}", path.display()), &url);
+
+    assert!(child.wait().unwrap().success());
+    fs::remove_file(&path).unwrap();
+    let events: Vec<String> = events.try_iter().collect();
+    assert_eq!(events, vec!["press", "release", "press", "release"]);
+}